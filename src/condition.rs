@@ -0,0 +1,220 @@
+//! Evaluates the expressions of `test` and `[`.
+
+use std::path::Path;
+
+/// Evaluates `args` as a `test` expression. An error describes why they are
+/// not one, which makes `test` exit with 2.
+pub fn evaluate(args: &[String]) -> Result<bool, String> {
+    if args.is_empty() {
+        return Ok(false);
+    }
+    let mut parser = Parser { args, pos: 0 };
+    let result = parser.or()?;
+    match args.get(parser.pos) {
+        Some(arg) => Err(format!("{arg}: unexpected argument")),
+        None => Ok(result),
+    }
+}
+
+struct Parser<'a> {
+    args: &'a [String],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self, offset: usize) -> Option<&'a str> {
+        self.args.get(self.pos + offset).map(String::as_str)
+    }
+
+    fn next(&mut self) -> Option<&'a str> {
+        let arg = self.args.get(self.pos)?;
+        self.pos += 1;
+        Some(arg)
+    }
+
+    fn or(&mut self) -> Result<bool, String> {
+        let mut result = self.and()?;
+        while self.peek(0) == Some("-o") {
+            self.pos += 1;
+            result |= self.and()?;
+        }
+        Ok(result)
+    }
+
+    fn and(&mut self) -> Result<bool, String> {
+        let mut result = self.not()?;
+        while self.peek(0) == Some("-a") {
+            self.pos += 1;
+            result &= self.not()?;
+        }
+        Ok(result)
+    }
+
+    fn not(&mut self) -> Result<bool, String> {
+        // A `!` with nothing after it is just a string, and one before a
+        // binary operator is compared, as in `[ ! = x ]`.
+        let operand = self.peek(1).is_some_and(is_binary) && self.peek(2).is_some();
+        if self.peek(0) == Some("!") && self.peek(1).is_some() && !operand {
+            self.pos += 1;
+            return Ok(!self.not()?);
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<bool, String> {
+        // An operand followed by a binary operator is a comparison, even if
+        // the operand looks like an operator itself, as in `[ -n = -n ]`.
+        if let Some(operator) = self.peek(1)
+            && is_binary(operator)
+            && self.peek(2).is_some()
+        {
+            let left = self.next().unwrap_or_default();
+            self.pos += 1;
+            let right = self.next().unwrap_or_default();
+            return binary(left, operator, right);
+        }
+
+        let Some(arg) = self.next() else {
+            return Err(String::from("argument expected"));
+        };
+        match arg {
+            "(" => {
+                let result = self.or()?;
+                match self.next() {
+                    Some(")") => Ok(result),
+                    _ => Err(String::from("`)' expected")),
+                }
+            }
+            operator if is_unary(operator) && self.peek(0).is_some() => {
+                let operand = self.next().unwrap_or_default();
+                Ok(unary(operator, operand))
+            }
+            string => Ok(!string.is_empty()),
+        }
+    }
+}
+
+fn is_unary(operator: &str) -> bool {
+    matches!(
+        operator,
+        "-e" | "-f" | "-d" | "-r" | "-w" | "-x" | "-s" | "-L" | "-h" | "-n" | "-z"
+    )
+}
+
+fn is_binary(operator: &str) -> bool {
+    matches!(
+        operator,
+        "=" | "==" | "!=" | "<" | ">" | "-eq" | "-ne" | "-lt" | "-le" | "-gt" | "-ge"
+    )
+}
+
+fn unary(operator: &str, operand: &str) -> bool {
+    let path = Path::new(operand);
+    match operator {
+        "-n" => !operand.is_empty(),
+        "-z" => operand.is_empty(),
+        "-e" => path.exists(),
+        "-f" => path.is_file(),
+        "-d" => path.is_dir(),
+        "-s" => path.metadata().is_ok_and(|metadata| metadata.len() > 0),
+        "-L" | "-h" => path.is_symlink(),
+        "-r" => accessible(path, Access::Read),
+        "-w" => accessible(path, Access::Write),
+        "-x" => accessible(path, Access::Execute),
+        _ => unreachable!("not a unary operator: {operator}"),
+    }
+}
+
+fn binary(left: &str, operator: &str, right: &str) -> Result<bool, String> {
+    let result = match operator {
+        "=" | "==" => left == right,
+        "!=" => left != right,
+        "<" => left < right,
+        ">" => left > right,
+        _ => {
+            let left = integer(left)?;
+            let right = integer(right)?;
+            match operator {
+                "-eq" => left == right,
+                "-ne" => left != right,
+                "-lt" => left < right,
+                "-le" => left <= right,
+                "-gt" => left > right,
+                "-ge" => left >= right,
+                _ => unreachable!("not a binary operator: {operator}"),
+            }
+        }
+    };
+    Ok(result)
+}
+
+fn integer(operand: &str) -> Result<i64, String> {
+    operand
+        .trim()
+        .parse()
+        .map_err(|_| format!("{operand}: integer expression expected"))
+}
+
+enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+/// Whether the shell could open `path` in the way `access` says.
+#[cfg(unix)]
+fn accessible(path: &Path, access: Access) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+
+    let Ok(path) = std::ffi::CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+    let mode = match access {
+        Access::Read => libc::R_OK,
+        Access::Write => libc::W_OK,
+        Access::Execute => libc::X_OK,
+    };
+    // SAFETY: `path` is a valid NUL-terminated string.
+    unsafe { libc::access(path.as_ptr(), mode) == 0 }
+}
+
+#[cfg(not(unix))]
+fn accessible(path: &Path, access: Access) -> bool {
+    match (path.metadata(), access) {
+        (Ok(metadata), Access::Write) => !metadata.permissions().readonly(),
+        (Ok(_), _) => true,
+        (Err(_), _) => false,
+    }
+}
+
+#[test]
+fn test_evaluate() {
+    let test = |args: &str| {
+        let args = args
+            .split_whitespace()
+            .map(String::from)
+            .collect::<Vec<_>>();
+        evaluate(&args)
+    };
+    assert_eq!(test(""), Ok(false));
+    assert_eq!(test("x"), Ok(true));
+    assert_eq!(test("-n"), Ok(true));
+    assert_eq!(test("-z x"), Ok(false));
+    assert_eq!(test("a = a"), Ok(true));
+    assert_eq!(test("a != a"), Ok(false));
+    assert_eq!(test("-n = -n"), Ok(true));
+    assert_eq!(test("! a = b"), Ok(true));
+    assert_eq!(test("! = !"), Ok(true));
+    assert_eq!(test("2 -lt 10"), Ok(true));
+    assert_eq!(test("2 < 10"), Ok(false));
+    assert_eq!(test("-3 -ge -3 -a ( x -o -z y )"), Ok(true));
+    assert_eq!(test("-d / -a -f /"), Ok(false));
+    assert_eq!(test("-e /no/such/file"), Ok(false));
+
+    assert_eq!(
+        test("x -eq 1"),
+        Err(String::from("x: integer expression expected"))
+    );
+    assert_eq!(test("( x"), Err(String::from("`)' expected")));
+    assert_eq!(test("a b"), Err(String::from("b: unexpected argument")));
+}
@@ -0,0 +1,672 @@
+//! Splits a command line into words the way a POSIX shell does, remembering
+//! how every part of each word was quoted.
+
+use std::borrow::Cow;
+use std::iter::Peekable;
+use std::str::Chars;
+
+/// How a piece of input was quoted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Quote {
+    None,
+    /// Taken literally: inside `'...'` or `$'...'`, or escaped with `\`.
+    Single,
+    Double,
+}
+
+/// A run of characters within a word that were all quoted the same way.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Part {
+    pub text: String,
+    pub quote: Quote,
+}
+
+/// One word of a command, with its quotes and escapes already removed.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Word {
+    pub parts: Vec<Part>,
+}
+
+impl Word {
+    /// The text of the word as the command sees it.
+    pub fn text(&self) -> String {
+        self.parts.iter().map(|part| part.text.as_str()).collect()
+    }
+
+    /// Whether none of the word was quoted, which is what makes `>` a
+    /// redirection rather than an argument.
+    pub fn is_unquoted(&self) -> bool {
+        self.parts.iter().all(|part| part.quote == Quote::None)
+    }
+
+    /// The unquoted text the word starts with, where expansions like `~` are
+    /// recognized.
+    pub fn unquoted_prefix(&self) -> &str {
+        match self.parts.first() {
+            Some(part) if part.quote == Quote::None => &part.text,
+            _ => "",
+        }
+    }
+
+    fn push(&mut self, c: char, quote: Quote) {
+        self.open(quote);
+        if let Some(part) = self.parts.last_mut() {
+            part.text.push(c);
+        }
+    }
+
+    /// Starts a part quoted with `quote` unless the last one already is, so
+    /// that `''` still makes an empty word.
+    fn open(&mut self, quote: Quote) {
+        if self.parts.last().is_none_or(|part| part.quote != quote) {
+            self.parts.push(Part {
+                text: String::new(),
+                quote,
+            });
+        }
+    }
+}
+
+impl From<Word> for String {
+    fn from(word: Word) -> Self {
+        word.text()
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum LexError {
+    #[error("unexpected EOF while looking for matching `{0}'")]
+    UnterminatedQuote(char),
+    /// The input ends before the line that closes a `<<` here-document.
+    #[error("here-document delimited by end-of-file (wanted `{0}')")]
+    UnterminatedHereDocument(String),
+}
+
+/// A `<<` whose lines have not been read yet.
+struct HereDocument {
+    /// Where in the output its text goes.
+    at: usize,
+    delimiter: String,
+    /// Whether any of the delimiter was quoted, which leaves the text as
+    /// written instead of expanding it.
+    quoted: bool,
+    /// Whether it was `<<-`, which strips the leading tabs of every line.
+    strip_tabs: bool,
+}
+
+/// Moves the lines of every `<<` here-document up into its command, as a
+/// quoted word after the `<<`. From then on the text is part of the command
+/// like any other, so the passes that split input into commands leave it
+/// alone and expansions apply exactly when the delimiter was unquoted.
+pub fn inline_here_documents(input: &str) -> Result<Cow<'_, str>, LexError> {
+    if !input.contains("<<") {
+        return Ok(Cow::Borrowed(input));
+    }
+
+    let mut output = String::with_capacity(input.len());
+    let mut pending = Vec::new();
+    let mut quote = None;
+    let mut depth = 0usize;
+    let mut i = 0;
+    while let Some(c) = input[i..].chars().next() {
+        let rest = &input[i..];
+        match (c, quote) {
+            ('\\', Some('\'')) => {}
+            ('\\', _) => {
+                let len = 1 + rest[1..].chars().next().map_or(0, char::len_utf8);
+                output.push_str(&rest[..len]);
+                i += len;
+                continue;
+            }
+            ('$', None) if rest[1..].starts_with('\'') => {
+                output.push_str("$'");
+                quote = Some('$');
+                i += 2;
+                continue;
+            }
+            ('\'' | '"' | '`', None) => quote = Some(c),
+            ('\'', Some('\'' | '$')) | ('"', Some('"')) | ('`', Some('`')) => quote = None,
+            ('(', None) => depth += 1,
+            (')', None) => depth = depth.saturating_sub(1),
+            // `<<<` is a here-string, and `$((...))` may shift with `<<`.
+            ('<', None)
+                if depth == 0
+                    && rest.starts_with("<<")
+                    && !rest.starts_with("<<<")
+                    && !output.ends_with('<') =>
+            {
+                let strip_tabs = rest[2..].starts_with('-');
+                let operator = if strip_tabs { 3 } else { 2 };
+                let word = rest[operator..].trim_start_matches([' ', '\t']);
+                let (delimiter, quoted, len) = delimiter(word);
+                if len > 0 {
+                    output.push_str("<< ");
+                    pending.push(HereDocument {
+                        at: output.len(),
+                        delimiter,
+                        quoted,
+                        strip_tabs,
+                    });
+                    i += rest.len() - word.len() + len;
+                    continue;
+                }
+            }
+            ('\n', None) if !pending.is_empty() => {
+                output.push('\n');
+                i += 1;
+                let mut texts = Vec::new();
+                for document in &pending {
+                    let (text, len) = read_here_document(&input[i..], document)?;
+                    texts.push(text);
+                    i += len;
+                }
+                // Back to front, so the earlier positions stay put.
+                for (document, text) in pending.drain(..).zip(texts).rev() {
+                    output.insert_str(document.at, &text);
+                }
+                continue;
+            }
+            _ => {}
+        }
+        output.push(c);
+        i += c.len_utf8();
+    }
+
+    match pending.into_iter().next() {
+        Some(document) => Err(LexError::UnterminatedHereDocument(document.delimiter)),
+        None => Ok(Cow::Owned(output)),
+    }
+}
+
+/// Reads the delimiter word at the start of `input`, returning it without its
+/// quotes, whether it had any, and how long it was as written.
+fn delimiter(input: &str) -> (String, bool, usize) {
+    let mut delimiter = String::new();
+    let mut quoted = false;
+    let mut quote = None;
+    let mut chars = input.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match (c, quote) {
+            (c, None) if c.is_whitespace() || ";|&<>()".contains(c) => {
+                return (delimiter, quoted, i);
+            }
+            ('\\', None) => {
+                quoted = true;
+                delimiter.extend(chars.next().map(|(_, c)| c));
+            }
+            ('\'' | '"', None) => {
+                quoted = true;
+                quote = Some(c);
+            }
+            (c, Some(open)) if c == open => quote = None,
+            (c, _) => delimiter.push(c),
+        }
+    }
+    (delimiter, quoted, input.len())
+}
+
+/// Reads the lines of `document` from the start of `input` up to its
+/// delimiter line, returning them as a quoted word and how much of `input`
+/// they took up.
+fn read_here_document(input: &str, document: &HereDocument) -> Result<(String, usize), LexError> {
+    let mut text = String::new();
+    let mut len = 0;
+    loop {
+        if len >= input.len() {
+            return Err(LexError::UnterminatedHereDocument(
+                document.delimiter.clone(),
+            ));
+        }
+        let end = input[len..].find('\n').map_or(input.len(), |n| len + n);
+        let mut line = &input[len..end];
+        len = (end + 1).min(input.len());
+        if document.strip_tabs {
+            line = line.trim_start_matches('\t');
+        }
+        if line == document.delimiter {
+            break;
+        }
+        text.push_str(line);
+        text.push('\n');
+    }
+
+    if document.quoted {
+        return Ok((format!("'{}'", text.replace('\'', "'\\''")), len));
+    }
+    // Inside double quotes a backslash also escapes `"`, which in the text
+    // of a here-document it does not.
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' if chars.peek() == Some(&'"') => {
+                chars.next();
+                quoted.push_str("\\\\\\\"");
+            }
+            '\\' => {
+                quoted.push(c);
+                quoted.extend(chars.next_if(|c| matches!(c, '$' | '`' | '\\' | '\n')));
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    Ok((quoted, len))
+}
+
+/// Removes every comment: an unquoted `#` that starts a word, and the rest
+/// of its line. A `#` further into a word, as in `a#b` or `$#`, is just a
+/// character.
+pub fn strip_comments(input: &str) -> Cow<'_, str> {
+    if !input.contains('#') {
+        return Cow::Borrowed(input);
+    }
+
+    let mut output = String::with_capacity(input.len());
+    let mut quote = None;
+    let mut word_start = true;
+    let mut chars = input.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, quote) {
+            // The newline stays, to end the command before it.
+            ('#', None) if word_start => {
+                while chars.next_if(|&c| c != '\n').is_some() {}
+                continue;
+            }
+            ('\\', Some('\'')) => {}
+            ('\\', _) => {
+                output.push(c);
+                output.extend(chars.next());
+                word_start = false;
+                continue;
+            }
+            ('$', None) if chars.peek() == Some(&'\'') => {
+                output.push(c);
+                output.extend(chars.next());
+                quote = Some('$');
+                word_start = false;
+                continue;
+            }
+            ('\'', None) => quote = Some('\''),
+            ('"', None) => quote = Some('"'),
+            ('\'', Some('\'' | '$')) | ('"', Some('"')) => quote = None,
+            _ => {}
+        }
+        word_start = quote.is_none() && (c.is_whitespace() || ";&|()".contains(c));
+        output.push(c);
+    }
+    Cow::Owned(output)
+}
+
+/// Removes every backslash-newline outside single quotes, joining the lines
+/// into one before any expansion sees them. Inside `'...'` a backslash is
+/// literal, so the newline stays.
+pub fn join_lines(input: &str) -> Cow<'_, str> {
+    if !input.contains("\\\n") {
+        return Cow::Borrowed(input);
+    }
+
+    let mut output = String::with_capacity(input.len());
+    let mut quote = None;
+    let mut chars = input.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, quote) {
+            ('\\', Some('\'')) => {}
+            ('\\', _) => {
+                match chars.next() {
+                    // In `$'...'` it is an escape like any other.
+                    Some('\n') if quote != Some('$') => {}
+                    Some(next) => {
+                        output.push(c);
+                        output.push(next);
+                    }
+                    None => output.push(c),
+                }
+                continue;
+            }
+            ('$', None) if chars.peek() == Some(&'\'') => {
+                output.push(c);
+                output.extend(chars.next());
+                quote = Some('$');
+                continue;
+            }
+            ('\'', None) => quote = Some('\''),
+            ('"', None) => quote = Some('"'),
+            ('\'', Some('\'' | '$')) | ('"', Some('"')) => quote = None,
+            _ => {}
+        }
+        output.push(c);
+    }
+    Cow::Owned(output)
+}
+
+/// Splits `line` into words on unquoted whitespace, removing quotes and
+/// escapes. An unquoted backslash keeps the next character literal, and in
+/// double quotes only `$`, `` ` ``, `"` and `\` can be escaped.
+pub fn split(line: &str) -> Result<Vec<Word>, LexError> {
+    tokenize(line, false)
+}
+
+/// Splits a command like `split`, but with every unquoted redirection
+/// operator a word of its own even when written against its neighbors, as
+/// in `2>/dev/null` or `a>&2`. Digits right before the operator are part of
+/// it, as the stream it redirects.
+pub fn split_command(line: &str) -> Result<Vec<Word>, LexError> {
+    tokenize(line, true)
+}
+
+fn tokenize(line: &str, redirections: bool) -> Result<Vec<Word>, LexError> {
+    let mut words = Vec::new();
+    let mut word: Option<Word> = None;
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => words.extend(word.take()),
+            '\\' => match chars.next() {
+                // A backslash before a newline joins the lines.
+                Some('\n') => {}
+                Some(c) => word.get_or_insert_default().push(c, Quote::Single),
+                None => word.get_or_insert_default().push('\\', Quote::None),
+            },
+            '\'' => {
+                let word = word.get_or_insert_default();
+                word.open(Quote::Single);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c, Quote::Single),
+                        None => return Err(LexError::UnterminatedQuote('\'')),
+                    }
+                }
+            }
+            '"' => double_quoted(&mut chars, word.get_or_insert_default())?,
+            '$' if chars.peek() == Some(&'\'') => {
+                chars.next();
+                ansi_c_quoted(&mut chars, word.get_or_insert_default())?;
+            }
+            // `$"..."` asks for a translation, of which there are none.
+            '$' if chars.peek() == Some(&'"') => {
+                chars.next();
+                double_quoted(&mut chars, word.get_or_insert_default())?;
+            }
+            '<' | '>' if redirections => {
+                let mut operator = match word.take() {
+                    Some(fd)
+                        if fd.is_unquoted() && fd.text().chars().all(|c| c.is_ascii_digit()) =>
+                    {
+                        fd
+                    }
+                    other => {
+                        words.extend(other);
+                        Word::default()
+                    }
+                };
+                operator.push(c, Quote::None);
+                for c in redirection_rest(c, &mut chars).chars() {
+                    operator.push(c, Quote::None);
+                }
+                words.push(operator);
+            }
+            c => word.get_or_insert_default().push(c, Quote::None),
+        }
+    }
+    words.extend(word);
+
+    Ok(words)
+}
+
+/// Takes what follows the `<` or `>` that starts a redirection operator:
+/// the rest of `<<`, `<<<`, `>>` or `>|`, or the `&` and stream of a
+/// duplication like `>&2`.
+fn redirection_rest(first: char, chars: &mut Peekable<Chars>) -> String {
+    let mut rest = String::new();
+    if first == '<' && chars.next_if_eq(&'<').is_some() {
+        rest.push('<');
+        rest.extend(chars.next_if_eq(&'<'));
+    } else if first == '>'
+        && let Some(c) = chars.next_if(|&c| c == '>' || c == '|')
+    {
+        rest.push(c);
+    } else if chars.next_if_eq(&'&').is_some() {
+        rest.push('&');
+        while let Some(digit) = chars.next_if(char::is_ascii_digit) {
+            rest.push(digit);
+        }
+    }
+    rest
+}
+
+/// Reads the rest of a `"..."` string into `word`.
+fn double_quoted(chars: &mut Peekable<Chars>, word: &mut Word) -> Result<(), LexError> {
+    word.open(Quote::Double);
+    loop {
+        match chars.next() {
+            Some('"') => return Ok(()),
+            Some('\\') => match chars.peek() {
+                Some('$' | '`' | '"' | '\\') => {
+                    let c = chars.next().unwrap_or('\\');
+                    word.push(c, Quote::Double);
+                }
+                Some('\n') => {
+                    chars.next();
+                }
+                _ => word.push('\\', Quote::Double),
+            },
+            Some(c) => word.push(c, Quote::Double),
+            None => return Err(LexError::UnterminatedQuote('"')),
+        }
+    }
+}
+
+/// Reads the rest of a `$'...'` string into `word`, decoding the C-style
+/// escapes in it.
+fn ansi_c_quoted(chars: &mut Peekable<Chars>, word: &mut Word) -> Result<(), LexError> {
+    word.open(Quote::Single);
+    loop {
+        let c = match chars.next() {
+            Some('\'') => return Ok(()),
+            Some('\\') => match chars.next() {
+                Some('a') => '\x07',
+                Some('b') => '\x08',
+                Some('e' | 'E') => '\x1b',
+                Some('f') => '\x0c',
+                Some('n') => '\n',
+                Some('r') => '\r',
+                Some('t') => '\t',
+                Some('v') => '\x0b',
+                Some('c') => match chars.next() {
+                    Some(c) => char::from(c as u8 & 0x1f),
+                    None => return Err(LexError::UnterminatedQuote('\'')),
+                },
+                Some(c @ '0'..='7') => {
+                    let digits: String =
+                        std::iter::once(c).chain(take_digits(chars, 8, 2)).collect();
+                    code_point(&digits, 8)
+                }
+                Some(c @ ('x' | 'u' | 'U')) => {
+                    let max = match c {
+                        'x' => 2,
+                        'u' => 4,
+                        _ => 8,
+                    };
+                    let digits: String = take_digits(chars, 16, max).collect();
+                    if digits.is_empty() {
+                        word.push('\\', Quote::Single);
+                        c
+                    } else {
+                        code_point(&digits, 16)
+                    }
+                }
+                Some(c @ ('\\' | '\'' | '"' | '?')) => c,
+                Some(c) => {
+                    word.push('\\', Quote::Single);
+                    c
+                }
+                None => return Err(LexError::UnterminatedQuote('\'')),
+            },
+            Some(c) => c,
+            None => return Err(LexError::UnterminatedQuote('\'')),
+        };
+        word.push(c, Quote::Single);
+    }
+}
+
+/// Takes up to `max` digits in `radix` off the front of `chars`.
+fn take_digits<'a>(
+    chars: &'a mut Peekable<Chars>,
+    radix: u32,
+    max: usize,
+) -> impl Iterator<Item = char> + 'a {
+    std::iter::from_fn(move || chars.next_if(|c| c.is_digit(radix))).take(max)
+}
+
+fn code_point(digits: &str, radix: u32) -> char {
+    u32::from_str_radix(digits, radix)
+        .ok()
+        .and_then(char::from_u32)
+        .unwrap_or(char::REPLACEMENT_CHARACTER)
+}
+
+#[test]
+fn test_split() {
+    let texts = |line: &str| {
+        split(line)
+            .unwrap()
+            .into_iter()
+            .map(String::from)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(texts("  a  b\tc "), ["a", "b", "c"]);
+    assert_eq!(texts("'a b' \"c d\" e\\ f"), ["a b", "c d", "e f"]);
+    assert_eq!(texts("'' \"\" x''"), ["", "", "x"]);
+    assert_eq!(texts(r#""a\$b\"c\d\\""#), [r#"a$b"c\d\"#]);
+    assert_eq!(texts(r"a\\b \'"), [r"a\b", "'"]);
+    assert_eq!(texts(r"$'a\tb\x41\101é\'\q'"), ["a\tbAAé'\\q"]);
+    assert_eq!(texts("$\"x y\" a$ $b"), ["x y", "a$", "$b"]);
+    assert_eq!(texts("a\\\nb"), ["ab"]);
+
+    // Only commands have redirections to split off.
+    assert_eq!(texts("a>b"), ["a>b"]);
+
+    assert!(matches!(
+        split("'abc"),
+        Err(LexError::UnterminatedQuote('\''))
+    ));
+    assert!(matches!(
+        split("\"abc"),
+        Err(LexError::UnterminatedQuote('"'))
+    ));
+}
+
+#[test]
+fn test_split_command() {
+    let texts = |line: &str| {
+        split_command(line)
+            .unwrap()
+            .into_iter()
+            .map(String::from)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(texts("ls /x 2>/dev/null"), ["ls", "/x", "2>", "/dev/null"]);
+    assert_eq!(
+        texts("echo a 2>&1 >/dev/null"),
+        ["echo", "a", "2>&1", ">", "/dev/null"]
+    );
+    assert_eq!(texts("a>>b c>|d"), ["a", ">>", "b", "c", ">|", "d"]);
+    assert_eq!(texts("cat<in<<<x"), ["cat", "<", "in", "<<<", "x"]);
+    assert_eq!(
+        texts("echo 12>x a2>y"),
+        ["echo", "12>", "x", "a2", ">", "y"]
+    );
+    assert_eq!(texts("x >&2"), ["x", ">&2"]);
+    // Quoted, an operator is just text, and so are quoted digits before one.
+    assert_eq!(
+        texts(r#"a'>'b \> ">" "2">x"#),
+        ["a>b", ">", ">", "2", ">", "x"]
+    );
+    let words = split_command("'>'").unwrap();
+    assert!(!words[0].is_unquoted());
+}
+
+#[test]
+fn test_join_lines() {
+    assert_eq!(join_lines("echo a"), "echo a");
+    assert_eq!(join_lines("echo $HO\\\nME \"a\\\nb\""), "echo $HOME \"ab\"");
+    assert_eq!(
+        join_lines("echo 'a\\\nb' c\\\\\nd"),
+        "echo 'a\\\nb' c\\\\\nd"
+    );
+    assert_eq!(join_lines("$'\\'\\\n' x\\\ny"), "$'\\'\\\n' xy");
+}
+
+#[test]
+fn test_strip_comments() {
+    assert_eq!(strip_comments("echo hi # a comment"), "echo hi ");
+    assert_eq!(strip_comments("# all of it\necho hi"), "\necho hi");
+    assert_eq!(strip_comments("echo a;# b\necho c"), "echo a;\necho c");
+    assert_eq!(strip_comments("echo a#b $# ${#x}"), "echo a#b $# ${#x}");
+    assert_eq!(
+        strip_comments("echo '# x' \"# y\" \\# z"),
+        "echo '# x' \"# y\" \\# z"
+    );
+    assert_eq!(strip_comments("echo $'\\' #' x"), "echo $'\\' #' x");
+    assert_eq!(strip_comments("echo # don't"), "echo ");
+}
+
+#[test]
+fn test_inline_here_documents() {
+    let inline = |input: &str| inline_here_documents(input).unwrap().into_owned();
+    assert_eq!(
+        inline("echo '<<a' \"<<b\" \\<<c"),
+        "echo '<<a' \"<<b\" \\<<c"
+    );
+    assert_eq!(
+        inline("cat <<EOF\nhi $x\nEOF\necho"),
+        "cat << \"hi $x\n\"\necho"
+    );
+    assert_eq!(
+        inline("cat <<'EOF' | wc\nit's $x\nEOF"),
+        "cat << 'it'\\''s $x\n' | wc\n"
+    );
+    assert_eq!(
+        inline("cat <<-E\n\t\"a\\\" \\$b\n\tE"),
+        "cat << \"\\\"a\\\\\\\" \\$b\n\"\n"
+    );
+    assert_eq!(
+        inline("a <<A; b <<\"B\"\n1\nA\n2\nB\n"),
+        "a << \"1\n\"; b << '2\n'\n"
+    );
+    assert_eq!(inline("cat <<<x '<<y'"), "cat <<<x '<<y'");
+    assert_eq!(inline("cat <<E\nE"), "cat << \"\"\n");
+
+    assert!(matches!(
+        inline_here_documents("cat <<EOF\nhi\n"),
+        Err(LexError::UnterminatedHereDocument(delimiter)) if delimiter == "EOF"
+    ));
+    assert!(inline_here_documents("cat <<EOF").is_err());
+}
+
+#[test]
+fn test_word_quoting() {
+    let words = split(r#"> '>' ~/x "~"/x a"b""#).unwrap();
+    assert!(words[0].is_unquoted());
+    assert!(!words[1].is_unquoted());
+    assert_eq!(words[2].unquoted_prefix(), "~/x");
+    assert_eq!(words[3].unquoted_prefix(), "");
+    assert_eq!(
+        words[4].parts,
+        [
+            Part {
+                text: "a".into(),
+                quote: Quote::None
+            },
+            Part {
+                text: "b".into(),
+                quote: Quote::Double
+            },
+        ]
+    );
+}
@@ -1,59 +1,134 @@
 use std::borrow::Cow;
-use std::fs::File;
-use std::io::{BufRead, BufReader, Write};
-use std::process::Stdio;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ffi::{OsStr, OsString};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::ops::ControlFlow;
+use std::process::{Child, ExitStatus, Stdio};
+use std::rc::Rc;
 
 use std::path::Path;
 use std::path::PathBuf;
 
-use std::sync::LazyLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{LazyLock, Mutex, PoisonError, RwLock, RwLockReadGuard};
 
 use anyhow::Context;
 use rustyline::Changeset;
 use rustyline::CompletionType;
 use rustyline::Config;
 
-use rustyline::completion::Candidate;
 use rustyline::completion::Completer;
 use rustyline::completion::FilenameCompleter;
 use rustyline::completion::Pair;
-use rustyline::highlight::Highlighter;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::{CmdKind, Highlighter};
 use rustyline::hint::Hinter;
-use rustyline::history::History;
+use rustyline::history::{FileHistory, History, SearchDirection};
 use rustyline::line_buffer::LineBuffer;
-use rustyline::validate::Validator;
-use rustyline::{Editor, Helper};
-use shlex::Shlex;
+use rustyline::validate::{ValidationContext, ValidationResult, Validator};
+use rustyline::{
+    Cmd, ConditionalEventHandler, Editor, Event, EventContext, EventHandler, Helper, KeyCode,
+    KeyEvent, Modifiers, RepeatCount,
+};
+
+use lexer::{LexError, Quote, Word};
+use parameter::{Expansion, Operator};
+use syntax::{Branch, Statement, SyntaxError};
 
 #[cfg(unix)]
 use std::os::unix::fs::PermissionsExt;
 #[cfg(unix)]
-use std::os::unix::process::CommandExt;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
 
-static PROGRAMS: LazyLock<Vec<String>> = LazyLock::new(|| {
-    let mut programs = Vec::new();
-    std::env::var_os("PATH").iter().for_each(|paths| {
-        for path in std::env::split_paths(&paths) {
-            if path.is_dir()
-                && let Ok(dir) = path.read_dir()
-            {
-                for entry in dir.flatten() {
-                    if let Some(program) = entry.path().file_stem()
-                        && is_executable(&entry.path())
-                    {
-                        programs.push(program.to_string_lossy().into());
-                    }
-                }
+mod condition;
+mod lexer;
+mod parameter;
+mod printf;
+mod syntax;
+
+/// Names of the programs on `PATH` to complete and highlight, scanned once
+/// and again on `rehash`.
+static PROGRAMS: LazyLock<RwLock<BTreeSet<String>>> =
+    LazyLock::new(|| RwLock::new(scan_programs(&std::env::var_os("PATH").unwrap_or_default())));
+
+fn programs() -> RwLockReadGuard<'static, BTreeSet<String>> {
+    PROGRAMS.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Where the programs run so far were found, valid for as long as `PATH`
+/// stays as it was when they were looked up.
+static RESOLVED: LazyLock<Mutex<ResolvedPrograms>> = LazyLock::new(Mutex::default);
+
+#[derive(Default)]
+struct ResolvedPrograms {
+    path: Option<OsString>,
+    programs: HashMap<String, PathBuf>,
+}
+
+/// Forgets the programs found so far and scans `PATH` again.
+fn rehash() {
+    *PROGRAMS.write().unwrap_or_else(PoisonError::into_inner) =
+        scan_programs(&std::env::var_os("PATH").unwrap_or_default());
+    RESOLVED
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .programs
+        .clear();
+}
+
+/// Lists where the programs run so far were found, or with `-r`, forgets
+/// them like `rehash`. Names given are looked up and remembered now.
+fn hash(args: impl Iterator<Item = String>) -> i32 {
+    let mut args = args.peekable();
+    if args.next_if(|arg| arg == "-r").is_some() {
+        rehash();
+    } else if args.peek().is_none() {
+        let resolved = RESOLVED.lock().unwrap_or_else(PoisonError::into_inner);
+        // What was found under another `PATH` is no longer where they are.
+        if resolved.path == std::env::var_os("PATH") {
+            let programs = resolved.programs.iter().collect::<BTreeMap<_, _>>();
+            for (name, path) in programs {
+                println!("{name}={}", path.display());
             }
-            if let Some(program) = path.as_path().file_stem()
-                && is_executable(&path)
-            {
-                programs.push(program.to_string_lossy().into());
+        }
+        return 0;
+    }
+
+    let mut status = 0;
+    for name in args {
+        // Looking a program up is what remembers it.
+        if command_type(&name).is_none() {
+            eprintln!("hash: {name}: not found");
+            status = 1;
+        }
+    }
+    status
+}
+
+/// Lists the executables in the directories of `paths`, by their full file
+/// names.
+fn scan_programs(paths: &OsStr) -> BTreeSet<String> {
+    let mut programs = BTreeSet::new();
+    for path in std::env::split_paths(paths) {
+        if path.is_dir()
+            && let Ok(dir) = path.read_dir()
+        {
+            for entry in dir.flatten() {
+                if is_executable(&entry.path()) {
+                    programs.insert(entry.file_name().to_string_lossy().into());
+                }
             }
         }
-    });
+        if let Some(program) = path.file_name()
+            && is_executable(&path)
+        {
+            programs.insert(program.to_string_lossy().into());
+        }
+    }
     programs
-});
+}
 
 enum Command {
     Exit,
@@ -62,20 +137,573 @@ enum Command {
     Cd,
     Type,
     History,
+    Export,
+    Alias,
+    Unalias,
+    Jobs,
+    Fg,
+    Bg,
+    Rehash,
+    Source,
+    Set,
+    Read,
+    Unset,
+    Break,
+    Continue,
+    Return,
+    /// `exec`, which replaces the shell with a program.
+    Exec,
+    Umask,
+    Local,
+    Shift,
+    /// `fc`, which lists or edits and reruns history entries.
+    Fc,
+    True,
+    False,
+    /// `:`, which does nothing with its arguments.
+    Null,
+    /// `test`, or `[` when it ends in `]`.
+    Test,
+    Printf,
+    Kill,
+    Wait,
+    /// `command`, which runs a builtin or program by the name that follows.
+    Bypass,
+    Hash,
+    Pushd,
+    Popd,
+    Dirs,
     Program(PathBuf),
 }
 
+/// Why the shell stopped running statements in order.
+enum Jump {
+    /// `exit`, or a failure under `set -e`.
+    Exit(i32),
+    /// `break`, out of this many loops.
+    Break(usize),
+    /// `continue`, with the loop this many levels out.
+    Continue(usize),
+    /// `return`, out of the function or sourced file being run.
+    Return,
+    /// Ctrl-C, which abandons everything up to the prompt.
+    Interrupt,
+}
+
+/// Session-wide state that outlives a single command.
+#[derive(Default)]
+struct ShellState {
+    /// Shell variables that have not been exported to the environment.
+    vars: HashMap<String, String>,
+    /// The `local` variables of the functions being run, innermost last.
+    /// `None` is one declared without a value, or unset since, which still
+    /// hides the variable outside.
+    scopes: Vec<HashMap<String, Option<String>>>,
+    /// Exit status of the most recently executed command.
+    last_status: i32,
+    /// The shell or script name followed by the positional parameters.
+    args: Vec<String>,
+    options: ShellOptions,
+    /// How many loops the statement being run is inside of.
+    loop_depth: usize,
+    /// How many functions and sourced files the statement being run is
+    /// inside of, which is where `return` can be used.
+    return_depth: usize,
+    aliases: BTreeMap<String, String>,
+    /// The bodies of the functions defined so far, shared with the calls
+    /// running them so a function can redefine itself.
+    functions: HashMap<String, Rc<[Statement]>>,
+    /// The directories `pushd` left, most recent first.
+    dir_stack: Vec<PathBuf>,
+    /// Background and stopped jobs, least recently used first.
+    jobs: Vec<Job>,
+    /// Whether commands are read from the terminal, each line going into
+    /// the history before it runs.
+    interactive: bool,
+    /// Whether the shell owns the terminal and gives every program its own
+    /// process group, so Ctrl-Z only suspends the program.
+    job_control: bool,
+}
+
+/// Behavior toggled with `set`.
+#[derive(Default)]
+struct ShellOptions {
+    /// `-e`: exit as soon as a command fails.
+    errexit: bool,
+    /// `-C`: refuse to let `>` overwrite an existing file, leaving that to
+    /// `>|`.
+    noclobber: bool,
+    /// `-u`: treat expanding an unset variable as an error.
+    nounset: bool,
+    /// `-o pipefail`: give a pipeline the status of its last stage that
+    /// failed, rather than of its last stage.
+    pipefail: bool,
+    /// `-x`: print each command before running it.
+    xtrace: bool,
+}
+
+/// The options `set` knows, by flag, if they have one, and by the name
+/// `set -o` uses.
+const SHELL_OPTIONS: [(Option<char>, &str); 5] = [
+    (Some('e'), "errexit"),
+    (Some('C'), "noclobber"),
+    (Some('u'), "nounset"),
+    (None, "pipefail"),
+    (Some('x'), "xtrace"),
+];
+
+impl ShellOptions {
+    fn option_mut(&mut self, name: &str) -> Option<&mut bool> {
+        match name {
+            "errexit" => Some(&mut self.errexit),
+            "noclobber" => Some(&mut self.noclobber),
+            "nounset" => Some(&mut self.nounset),
+            "pipefail" => Some(&mut self.pipefail),
+            "xtrace" => Some(&mut self.xtrace),
+            _ => None,
+        }
+    }
+
+    fn option(&self, name: &str) -> bool {
+        match name {
+            "errexit" => self.errexit,
+            "noclobber" => self.noclobber,
+            "nounset" => self.nounset,
+            "pipefail" => self.pipefail,
+            "xtrace" => self.xtrace,
+            _ => false,
+        }
+    }
+
+    /// The name of the option `flag` turns on.
+    fn flag_name(flag: char) -> Option<&'static str> {
+        SHELL_OPTIONS
+            .iter()
+            .find(|(option, _)| *option == Some(flag))
+            .map(|(_, name)| *name)
+    }
+}
+
+/// What the shell should do with a program's process group.
+#[derive(Clone, Copy)]
+enum ProcessGroup {
+    /// Stay in the shell's own group.
+    Shell,
+    /// Get a new group that takes over the terminal.
+    Foreground,
+    /// Get a new group that leaves the terminal alone.
+    Background,
+    /// Join the group an earlier stage of the same pipeline started.
+    Join(u32),
+}
+
+/// A command left running with a trailing `&`, or a command or pipeline
+/// suspended with Ctrl-Z.
+struct Job {
+    id: usize,
+    command: String,
+    /// The PID of the first program that was still running when the job was
+    /// made, which `wait` goes by.
+    pid: u32,
+    /// The first program's PID, which names the job's process group when it
+    /// has one. That program may have exited before the rest stopped.
+    pgid: u32,
+    /// The programs that have not exited yet, in pipeline order.
+    children: Vec<Child>,
+    /// How the last program exited, once it has: the job's status.
+    status: Option<ExitStatus>,
+    stopped: bool,
+}
+
+impl ShellState {
+    /// Looks `name` up as a special parameter, then as a shell variable, then
+    /// in the environment.
+    fn var(&self, name: &str) -> Option<String> {
+        let positional = self.args.get(1..).unwrap_or_default();
+        match name {
+            "?" => return Some(self.last_status.to_string()),
+            "#" => return Some(positional.len().to_string()),
+            "@" | "*" => return Some(positional.join(" ")),
+            _ => {}
+        }
+        if let Ok(index) = name.parse::<usize>() {
+            return self.args.get(index).cloned();
+        }
+        if let Some(value) = self.scopes.iter().rev().find_map(|scope| scope.get(name)) {
+            return value.clone();
+        }
+        self.vars
+            .get(name)
+            .cloned()
+            .or_else(|| std::env::var_os(name).map(|v| v.to_string_lossy().into_owned()))
+    }
+
+    /// Assigns the innermost local variable called `name`, or otherwise a
+    /// shell variable, or changes the environment when `name` is exported
+    /// already.
+    fn set_var(&mut self, name: &str, value: String) {
+        if let Some(local) = self.local_mut(name) {
+            *local = Some(value);
+        } else if std::env::var_os(name).is_some() {
+            set_env_var(name, value);
+        } else {
+            self.vars.insert(name.to_string(), value);
+        }
+    }
+
+    /// Unsets the innermost local variable called `name`, or otherwise the
+    /// shell or environment variable.
+    fn unset_var(&mut self, name: &str) {
+        if let Some(local) = self.local_mut(name) {
+            *local = None;
+        } else {
+            self.vars.remove(name);
+            remove_env_var(name);
+        }
+    }
+
+    fn local_mut(&mut self, name: &str) -> Option<&mut Option<String>> {
+        self.scopes
+            .iter_mut()
+            .rev()
+            .find_map(|scope| scope.get_mut(name))
+    }
+
+    /// Wraps `children`, the programs of a command or pipeline, as a job
+    /// numbered after the existing ones.
+    fn new_job(&self, command: &str, pgid: u32, children: Vec<Child>) -> Job {
+        Job {
+            id: self.jobs.iter().map(|job| job.id).max().unwrap_or(0) + 1,
+            command: command.to_string(),
+            pid: children.first().map_or(0, Child::id),
+            pgid,
+            children,
+            status: None,
+            stopped: false,
+        }
+    }
+
+    /// Where a program spawned now should go.
+    fn process_group(&self, background: bool) -> ProcessGroup {
+        match (self.job_control, background) {
+            (false, _) => ProcessGroup::Shell,
+            (true, false) => ProcessGroup::Foreground,
+            (true, true) => ProcessGroup::Background,
+        }
+    }
+}
+
+type ShellEditor = Editor<ShellHelper, FileHistory>;
+
 struct ShellHelper {
     completer: FilenameCompleter,
+    /// Subcommands and flags offered after the name of a known command.
+    specs: HashMap<String, Vec<String>>,
+    /// What a reverse-i-search in progress is looking for, read off its
+    /// prompt so the match in the line can be shown in bold.
+    search: RefCell<Option<String>>,
+    /// The session, for the aliases and functions it has defined. It is
+    /// only borrowed while a line is being read.
+    state: Rc<RefCell<ShellState>>,
+}
+
+/// The completion specs the shell starts with.
+const DEFAULT_SPECS: &[(&str, &[&str])] = &[
+    (
+        "git",
+        &[
+            "add",
+            "bisect",
+            "blame",
+            "branch",
+            "checkout",
+            "cherry-pick",
+            "clone",
+            "commit",
+            "config",
+            "diff",
+            "fetch",
+            "init",
+            "log",
+            "merge",
+            "pull",
+            "push",
+            "rebase",
+            "remote",
+            "reset",
+            "restore",
+            "revert",
+            "show",
+            "stash",
+            "status",
+            "switch",
+            "tag",
+            "--help",
+            "--version",
+        ],
+    ),
+    (
+        "cargo",
+        &[
+            "add",
+            "bench",
+            "build",
+            "check",
+            "clean",
+            "clippy",
+            "doc",
+            "fmt",
+            "init",
+            "install",
+            "new",
+            "publish",
+            "remove",
+            "run",
+            "test",
+            "update",
+            "--help",
+            "--release",
+            "--version",
+        ],
+    ),
+];
+
+impl ShellHelper {
+    fn new(state: Rc<RefCell<ShellState>>) -> Self {
+        let specs = DEFAULT_SPECS
+            .iter()
+            .map(|(com, words)| {
+                let words = words.iter().map(|word| word.to_string()).collect();
+                (com.to_string(), words)
+            })
+            .collect();
+        Self {
+            completer: FilenameCompleter::new(),
+            specs,
+            search: RefCell::new(None),
+            state,
+        }
+    }
+
+    /// The commands starting with `word`: builtins, aliases and functions
+    /// first, then the programs none of them hides, each in order regardless
+    /// of case.
+    fn commands(&self, word: &str) -> Vec<String> {
+        let state = self.state.borrow();
+        let mut own = BUILTINS
+            .iter()
+            .map(|c| c.to_string())
+            .chain(state.aliases.keys().cloned())
+            .chain(state.functions.keys().cloned())
+            .filter(|c| c.starts_with(word))
+            .collect::<Vec<_>>();
+        own.sort_by(|a, b| a.to_lowercase().cmp(&b.to_lowercase()).then(a.cmp(b)));
+        own.dedup();
+        let mut programs = programs()
+            .iter()
+            .filter(|c| c.starts_with(word) && !own.contains(c))
+            .cloned()
+            .collect::<Vec<_>>();
+        programs.sort_by_key(|c| c.to_lowercase());
+        own.extend(programs);
+        own
+    }
+
+    /// What to put in place of the word being completed once `elected` is
+    /// chosen for it, after `before`. A complete command or file name ends
+    /// with a space, closing the quote it was typed in, if any, and a
+    /// directory with a `/` to complete what is in it next.
+    fn finish_completion(&self, before: &str, elected: &str) -> String {
+        if self.commands(elected).len() == 1 {
+            return format!("{elected} ");
+        }
+        let quote = open_quote(before);
+        match completed_path(elected, quote) {
+            Some(true) => format!("{elected}/"),
+            Some(false) => match quote {
+                Some(quote) => format!("{elected}{quote} "),
+                None => format!("{elected} "),
+            },
+            None => elected.to_string(),
+        }
+    }
+
+    /// Candidates from the spec of the command being typed, if it has one:
+    /// subcommands and flags right after the name, flags only later on.
+    fn complete_spec(&self, line: &str, start: usize, word: &str) -> Vec<Pair> {
+        let mut words = line[..start].split_whitespace();
+        let Some(spec) = words.next().and_then(|com| self.specs.get(com)) else {
+            return Vec::new();
+        };
+        let subcommand = words.next().is_none();
+        if !subcommand && !word.starts_with('-') {
+            return Vec::new();
+        }
+        spec.iter()
+            .filter(|candidate| candidate.starts_with(word))
+            .map(|candidate| Pair {
+                display: candidate.clone(),
+                replacement: format!("{candidate} "),
+            })
+            .collect()
+    }
 }
 
+/// Suggests the rest of the most recent history entry that starts with the
+/// line typed so far, once the cursor is at its end.
 impl Hinter for ShellHelper {
     type Hint = String;
+
+    fn hint(&self, line: &str, pos: usize, ctx: &rustyline::Context<'_>) -> Option<String> {
+        if line.is_empty() || pos < line.len() {
+            return None;
+        }
+        // Searching from a history entry being browsed starts at that entry.
+        let start = if ctx.history_index() == ctx.history().len() {
+            ctx.history_index().checked_sub(1)?
+        } else {
+            ctx.history_index()
+        };
+        let found = ctx
+            .history()
+            .starts_with(line, start, SearchDirection::Reverse)
+            .ok()??;
+        let rest = &found.entry[pos..];
+        (!rest.is_empty()).then(|| rest.to_string())
+    }
+}
+
+/// Takes the suggested rest of the line on End, as on Right, when the cursor
+/// is already at the end of it.
+struct AcceptHint;
+
+impl ConditionalEventHandler for AcceptHint {
+    fn handle(
+        &self,
+        evt: &Event,
+        n: RepeatCount,
+        positive: bool,
+        ctx: &EventContext,
+    ) -> Option<Cmd> {
+        let _ = (evt, n, positive);
+        (ctx.has_hint() && ctx.pos() == ctx.line().len()).then_some(Cmd::CompleteHint)
+    }
+}
+
+/// Enter on input that is not complete yet starts a new line of it instead
+/// of running it.
+impl Validator for ShellHelper {
+    fn validate(&self, ctx: &mut ValidationContext) -> rustyline::Result<ValidationResult> {
+        if is_incomplete(ctx.input()) {
+            Ok(ValidationResult::Incomplete)
+        } else {
+            Ok(ValidationResult::Valid(None))
+        }
+    }
+}
+
+/// The text a reverse-i-search `prompt` is looking for, if it is one.
+fn search_query(prompt: &str) -> Option<&str> {
+    let query = prompt
+        .strip_prefix("(reverse-i-search)`")
+        .or_else(|| prompt.strip_prefix("(failed reverse-i-search)`"))?;
+    query.strip_suffix("': ")
 }
 
-impl Validator for ShellHelper {}
+/// Wraps the occurrence of `query` in `line` in bold, preferring the one at
+/// `pos`, where the search leaves the cursor.
+fn highlight_match<'l>(line: &'l str, pos: usize, query: &str) -> Cow<'l, str> {
+    let start = if line.get(pos..).is_some_and(|rest| rest.starts_with(query)) {
+        Some(pos)
+    } else {
+        line.rfind(query)
+    };
+    match start {
+        Some(start) if !query.is_empty() => {
+            let end = start + query.len();
+            Cow::Owned(format!(
+                "{}\x1b[1m{}\x1b[0m{}",
+                &line[..start],
+                &line[start..end],
+                &line[end..]
+            ))
+        }
+        _ => Cow::Borrowed(line),
+    }
+}
+
+/// Colors the command name at the start of `line` green if it names a
+/// builtin, a reserved word, one of the aliases or functions in `state` or a
+/// program, and red if it does not. Only the
+/// programs scanned from `PATH` are checked, so nothing is searched for as
+/// the line is typed. Names that are quoted, expanded or assign a variable
+/// are left as they are.
+fn highlight_command<'l>(line: &'l str, state: &ShellState) -> Cow<'l, str> {
+    let start = line.len() - line.trim_start().len();
+    let end = line[start..]
+        .find(|c: char| c.is_whitespace() || ";|&<>()".contains(c))
+        .map_or(line.len(), |end| start + end);
+    let com = &line[start..end];
+    if com.is_empty() || com.contains(['\'', '"', '\\', '$', '`', '=']) {
+        return Cow::Borrowed(line);
+    }
+
+    let known = if com.contains('/') {
+        is_executable(Path::new(com))
+    } else {
+        syntax::KEYWORDS.contains(&com)
+            || builtin_type(com).is_some()
+            || state.aliases.contains_key(com)
+            || state.functions.contains_key(com)
+            || programs().contains(com)
+    };
+    let color = if known { "32" } else { "31" };
+    Cow::Owned(format!(
+        "{}\x1b[{color}m{com}\x1b[0m{}",
+        &line[..start],
+        &line[end..]
+    ))
+}
 
 impl Highlighter for ShellHelper {
+    /// Shows what a reverse-i-search matched in bold, and otherwise whether
+    /// the command being typed exists, unless `NO_COLOR` is set.
+    fn highlight<'l>(&self, line: &'l str, pos: usize) -> Cow<'l, str> {
+        if let Some(query) = &*self.search.borrow() {
+            return highlight_match(line, pos, query);
+        }
+        if std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty()) {
+            highlight_command(line, &self.state.borrow())
+        } else {
+            Cow::Borrowed(line)
+        }
+    }
+
+    /// Shows the suggested rest of the line dimmed.
+    fn highlight_hint<'h>(&self, hint: &'h str) -> Cow<'h, str> {
+        Cow::Owned(format!("\x1b[2m{hint}\x1b[0m"))
+    }
+
+    /// Notes whether a reverse-i-search is going on, as the prompt is
+    /// highlighted before the line.
+    fn highlight_prompt<'b, 's: 'b, 'p: 'b>(
+        &'s self,
+        prompt: &'p str,
+        default: bool,
+    ) -> Cow<'b, str> {
+        let query = if default { None } else { search_query(prompt) };
+        *self.search.borrow_mut() = query.map(String::from);
+        Cow::Borrowed(prompt)
+    }
+
+    /// The whole line is redrawn as it changes, as the color of the command
+    /// can change with any character typed, and during a search, so the bold
+    /// part moves with the match.
+    fn highlight_char(&self, line: &str, pos: usize, kind: CmdKind) -> bool {
+        let _ = (line, pos);
+        kind != CmdKind::MoveCursor || self.search.borrow().is_some()
+    }
+
     fn highlight_candidate<'c>(
         &self,
         candidate: &'c str, // FIXME should be Completer::Candidate
@@ -90,7 +718,6 @@ impl Helper for ShellHelper {}
 
 impl Completer for ShellHelper {
     type Candidate = Pair;
-    // TODO: let the implementers choose/find word boundaries ??? => Lexer
 
     /// Takes the currently edited `line` with the cursor `pos`ition and
     /// returns the start position and the completion candidates for the
@@ -98,21 +725,24 @@ impl Completer for ShellHelper {
     ///
     /// `("ls /usr/loc", 11)` => `Ok((3, vec!["/usr/local/"]))`
     fn complete(
-        &self, // FIXME should be `&mut self`
+        &self,
         line: &str,
         pos: usize,
         ctx: &rustyline::Context<'_>,
     ) -> rustyline::Result<(usize, Vec<Self::Candidate>)> {
-        let mut commands = vec![
-            String::from("echo"),
-            String::from("exit"),
-            String::from("history"),
-        ];
-        commands.extend_from_slice(PROGRAMS.as_slice());
-
-        let mut com = commands
+        let (start, command_position) = completion_word(&line[..pos]);
+        let word = &line[start..pos];
+        if !command_position {
+            let candidates = self.complete_spec(line, start, word);
+            if !candidates.is_empty() {
+                return Ok((start, candidates));
+            }
+            return self.completer.complete(line, pos, ctx);
+        }
+
+        let com = self
+            .commands(word)
             .into_iter()
-            .filter(|c| c.starts_with(&line[..pos]))
             .map(|c| Pair {
                 display: c.clone(),
                 replacement: c,
@@ -121,431 +751,4996 @@ impl Completer for ShellHelper {
         if com.is_empty() {
             self.completer.complete(line, pos, ctx)
         } else {
-            com.sort_unstable_by(|c1, c2| c1.display().cmp(c2.display()));
-            Ok((0, com))
+            Ok((start, com))
         }
     }
 
     fn update(&self, line: &mut LineBuffer, start: usize, elected: &str, cl: &mut Changeset) {
         let end = line.pos();
+        let completion = self.finish_completion(&line[..start], elected);
+        line.replace(start..end, &completion, cl);
+    }
+}
 
-        let mut commands = vec![String::from("echo"), String::from("exit")];
-        commands.extend_from_slice(PROGRAMS.as_slice());
-
-        let len = commands.iter().filter(|c| c.starts_with(elected)).count();
+/// The quote left open at the end of `line`, if any.
+fn open_quote(line: &str) -> Option<char> {
+    let mut quote = None;
+    let mut escaped = false;
+    for c in line.chars() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match (c, quote) {
+            ('\\', Some('\'')) => {}
+            ('\\', _) => escaped = true,
+            ('\'' | '"', None) => quote = Some(c),
+            (c, Some(open)) if c == open => quote = None,
+            _ => {}
+        }
+    }
+    quote
+}
 
-        if len == 1 || elected == "echo" || elected == "exit" {
-            line.replace(start..end, &format!("{elected} "), cl);
-        } else {
-            line.replace(start..end, elected, cl);
+/// Whether the path `word` is complete, that is, it names an entry and no
+/// longer one starts with it: `Some(true)` for a directory and `Some(false)`
+/// for anything else. A word that already ends in `/` has nothing to add.
+/// Backslashes escape the next character, except inside the single `quote`
+/// the word may be typed in.
+fn completed_path(word: &str, quote: Option<char>) -> Option<bool> {
+    if word.is_empty() || word.ends_with('/') {
+        return None;
+    }
+    let mut unescaped = String::with_capacity(word.len());
+    let mut chars = word.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if quote != Some('\'') => unescaped.extend(chars.next()),
+            c => unescaped.push(c),
         }
     }
+    let path = expand_tilde(&unescaped);
+    let name = path.file_name()?;
+    let dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+
+    let mut matches = std::fs::read_dir(dir)
+        .ok()?
+        .filter_map(Result::ok)
+        .filter(|entry| {
+            let entry = entry.file_name();
+            entry
+                .as_encoded_bytes()
+                .starts_with(name.as_encoded_bytes())
+        });
+    let entry = matches.next()?;
+    if matches.next().is_some() || entry.file_name() != name {
+        return None;
+    }
+    Some(path.is_dir())
+}
+
+/// Commands given on the command line to run instead of reading them from
+/// the terminal.
+enum Script {
+    /// The string after `-c`.
+    Command(String),
+    /// A file to run like `source` would.
+    File(String),
 }
 
 fn main() -> anyhow::Result<()> {
+    let mut state = ShellState::default();
+    let mut args = std::env::args();
+    state.args.extend(args.next());
+
+    let mut load_rc = true;
+    let mut script = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--version" => {
+                println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+                return Ok(());
+            }
+            "--help" => {
+                print!("{}", usage(&state.args[0]));
+                return Ok(());
+            }
+            "--no-rc" => load_rc = false,
+            "-c" => {
+                let Some(command) = args.next() else {
+                    eprintln!("-c: option requires an argument");
+                    std::process::exit(2);
+                };
+                // As in `sh -c 'echo $0 $1' name arg`.
+                if let Some(name) = args.next() {
+                    state.args[0] = name;
+                }
+                script = Some(Script::Command(command));
+                break;
+            }
+            option if option.starts_with('-') && option != "-" => {
+                eprintln!("{option}: invalid option");
+                std::process::exit(2);
+            }
+            _ => {
+                state.args = vec![arg.clone()];
+                script = Some(Script::File(arg));
+                break;
+            }
+        }
+    }
+    state.args.extend(args);
+
     let config = Config::builder()
         .history_ignore_space(true)
-        .auto_add_history(true)
         .completion_type(CompletionType::List)
         .build();
 
-    let mut rl = Editor::with_config(config).context("create rustyline instance")?;
+    let mut rl: ShellEditor = Editor::with_config(config).context("create rustyline instance")?;
+
+    if let Some(script) = script {
+        let code = match run_script(&mut rl, &mut state, script) {
+            ControlFlow::Break(Jump::Exit(code)) => code,
+            _ => state.last_status,
+        };
+        std::process::exit(code);
+    }
+
+    if let Some(path) = history_file()
+        && path.exists()
+        && let Err(e) = rl.load_history(&path)
+    {
+        eprintln!("history: {}: {e}", path.display());
+    }
+
+    let shared = Rc::new(RefCell::new(state));
+    rl.set_helper(Some(ShellHelper::new(Rc::clone(&shared))));
+    rl.bind_sequence(
+        KeyEvent(KeyCode::End, Modifiers::NONE),
+        EventHandler::Conditional(Box::new(AcceptHint)),
+    );
 
-    let history = std::env::var("HISTFILE");
+    #[cfg(unix)]
+    {
+        shared.borrow_mut().job_control = init_job_control();
+        catch_interrupts();
+    }
 
-    if let Ok(history) = &history {
-        rl.load_history(&PathBuf::from(history))
-            .context("load history from env arg")?;
+    let mut startup = ControlFlow::Continue(());
+    if load_rc
+        && let Some(path) = rc_file()
+        && path.exists()
+    {
+        let path = path.to_string_lossy().into_owned();
+        let mut state = shared.borrow_mut();
+        startup = source(&mut rl, &mut state, "source", std::iter::once(path))?;
     }
 
-    let h = ShellHelper {
-        completer: FilenameCompleter::new(),
+    let code = match startup {
+        ControlFlow::Break(Jump::Exit(code)) => code,
+        _ => repl(&mut rl, &shared)?,
     };
-    rl.set_helper(Some(h));
 
-    loop {
-        let readline = rl.readline("$ ").context("read user input")?;
+    if let Some(path) = history_file() {
+        // Nothing is written when there is no history to save.
+        match rl.save_history(&path) {
+            Ok(()) if path.exists() => remove_tag(path).context("remove tag")?,
+            Ok(()) => {}
+            Err(e) => eprintln!("history: {}: {e}", path.display()),
+        }
+    }
 
-        if readline.contains('|') {
-            let commands: Vec<&str> = readline.split('|').map(|s| s.trim()).collect();
+    std::process::exit(code)
+}
 
-            if let Err(e) = execute_pipeline(&commands) {
-                eprintln!("Pipeline error: {}", e);
+/// Runs the commands given on the command line, without a prompt, history or
+/// job control. A script that cannot be read exits with 127.
+fn run_script(rl: &mut ShellEditor, state: &mut ShellState, script: Script) -> ControlFlow<Jump> {
+    match script {
+        Script::Command(command) => execute_script(rl, state, &command),
+        Script::File(path) => match std::fs::read_to_string(&path) {
+            Ok(script) => execute_script(rl, state, &script),
+            Err(e) => {
+                eprintln!("{path}: {}", io_error_message(&e));
+                ControlFlow::Break(Jump::Exit(127))
             }
+        },
+    }
+}
+
+/// Runs `script` a statement at a time, each one as soon as all of its lines
+/// are read. A failing line is reported and the next one runs anyway.
+fn execute_script(rl: &mut ShellEditor, state: &mut ShellState, script: &str) -> ControlFlow<Jump> {
+    let mut source = String::new();
+    for line in script.lines() {
+        source.push_str(line);
+        if is_incomplete(&source) {
+            source.push('\n');
             continue;
         }
+        match parse_source(&source) {
+            Err(e) => {
+                eprintln!("{e}");
+                state.last_status = 2;
+            }
+            Ok(statements) => execute_statements(rl, state, &statements)?,
+        }
+        source.clear();
+    }
+    if !source.is_empty() {
+        eprintln!("{}", SyntaxError::Incomplete);
+        state.last_status = 2;
+    }
+    ControlFlow::Continue(())
+}
 
-        let mut input = Shlex::new(readline.trim());
-        let com = input.next().context("parsing command")?;
-        let mut args = input;
-
-        let command = command_type(&com);
+/// Reads and runs lines until the input ends or `exit` is run, returning the
+/// status the shell should exit with.
+///
+/// The state is only borrowed between reads, as the editor's helper looks at
+/// it while a line is typed.
+fn repl(rl: &mut ShellEditor, shared: &RefCell<ShellState>) -> anyhow::Result<i32> {
+    shared.borrow_mut().interactive = true;
+    loop {
+        let prompt = {
+            let mut state = shared.borrow_mut();
+            report_jobs(&mut state, false);
+            render_prompt(&state)
+        };
+        let mut source = match rl.readline(&prompt) {
+            Ok(readline) => readline,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => return Ok(shared.borrow().last_status),
+            Err(e) => return Err(e).context("read user input"),
+        };
 
-        match command {
-            Some(Command::Echo) => {
-                let mut args = Parser::new(args);
-                let arg = args.collect::<Vec<_>>().join(" ");
-                if let Some(mut stdin) = args.stdout {
-                    writeln!(&mut stdin, "{arg}").context("write to file")?;
-                } else {
-                    println!("{arg}");
-                }
-            }
-            Some(Command::Cd) => {
-                let mut path = PathBuf::from(&args.next().context("parsing path")?);
-                if path.starts_with("~") {
-                    let home_dir = std::env::home_dir().context("get home dir")?;
-                    path = home_dir.join(path.strip_prefix("~").unwrap())
-                }
-                if path.is_absolute() {
-                    if std::env::set_current_dir(&path).is_err() {
-                        println!("cd: {}: No such file or directory", path.display())
+        // The editor only lets unfinished input through when it cannot
+        // validate it, as when stdin is not a terminal.
+        let statements = loop {
+            if is_incomplete(&source) {
+                match rl.readline("> ") {
+                    Ok(line) => {
+                        source.push('\n');
+                        source.push_str(&line);
+                        continue;
                     }
-                } else {
-                    let current_dir = std::env::current_dir().context("get current dir")?;
-                    let new_dir = current_dir.join(path);
-                    if std::env::set_current_dir(&new_dir).is_err() {
-                        println!("cd: {}: No such file or directory", new_dir.display())
+                    Err(ReadlineError::Interrupted) => break None,
+                    Err(ReadlineError::Eof) => {
+                        eprintln!("{}", SyntaxError::Incomplete);
+                        shared.borrow_mut().last_status = 2;
+                        break None;
                     }
+                    Err(e) => return Err(e).context("read user input"),
                 }
             }
-            Some(Command::Pwd) => println!(
-                "{}",
-                std::env::current_dir()
-                    .context("get current dir")?
-                    .display()
-            ),
-            Some(Command::History) => {
-                let history_info = HistoryInfo::new(args)?;
-                if let Some(read) = history_info.read {
-                    rl.load_history(&read).context("Read history from file")?;
-                } else if let Some(write) = history_info.write {
-                    rl.save_history(&write).context("Write history to file")?;
-                    remove_tag(write).context("Remove #V2 tag from history file")?;
-                } else if let Some(append) = history_info.append {
-                    rl.append_history(&append)
-                        .context("Append history to file")?;
-                    remove_tag(append).context("Remove #V2 tag from history file")?;
-                } else if let Some(num) = history_info.num {
-                    let history = rl
-                        .history()
-                        .iter()
-                        .rev()
-                        .enumerate()
-                        .take(num)
-                        .collect::<Vec<_>>();
-                    for (i, entry) in history.iter().rev() {
-                        println!("  {}  {}", rl.history().len() - i, entry);
-                    }
-                } else {
-                    rl.history()
-                        .iter()
-                        .enumerate()
-                        .for_each(|(i, entry)| println!("    {}  {entry}", i + 1));
+            // History goes in as expanded, so `!!` never refers to itself.
+            let history = rl.history().iter().map(String::as_str).collect::<Vec<_>>();
+            let expanded = match expand_history(&source, &history) {
+                Ok(expanded) => expanded.into_owned(),
+                Err(e) => {
+                    eprintln!("{e}");
+                    shared.borrow_mut().last_status = 1;
+                    break None;
                 }
+            };
+            if expanded != source {
+                println!("{expanded}");
             }
-            Some(Command::Program(ref path)) => run_command(path, &com, Parser::new(args))?,
-            Some(Command::Exit) => break,
-            Some(Command::Type) => {
-                let name = &args.next().context("parsing arg")?;
-                let command = command_type(name);
-                match command {
-                    Some(Command::Program(ref path)) => println!("{name} is {}", path.display()),
-                    Some(_) => println!("{name} is a shell builtin"),
-                    None => println!("{name}: not found"),
+            rl.add_history_entry(expanded.as_str())
+                .context("add history entry")?;
+            match parse_source(&expanded) {
+                Err(e) => {
+                    eprintln!("{e}");
+                    shared.borrow_mut().last_status = 2;
+                    break None;
                 }
+                Ok(statements) => break Some(statements),
             }
-            None => println!("{com}: command not found"),
+        };
+
+        INTERRUPTED.store(false, Ordering::Relaxed);
+        if let Some(statements) = statements
+            && let ControlFlow::Break(Jump::Exit(code)) =
+                execute_statements(rl, &mut shared.borrow_mut(), &statements)
+        {
+            return Ok(code);
         }
     }
+}
 
-    if let Ok(history) = &history {
-        let path = PathBuf::from(history);
-        rl.append_history(&path)
-            .context("write history from env arg")?;
-        remove_tag(path).context("remove tag")?;
-    }
+/// What `--help` prints for the shell run as `name`.
+fn usage(name: &str) -> String {
+    format!("Usage: {name} [--no-rc] [-c command [name [arg...]] | script [arg...]]\n\n{HELP}")
+}
 
-    Ok(())
+/// The rest of what `--help` prints, after the usage line.
+const HELP: &str = "\
+Without a command or script, reads commands from the terminal.
+
+Options:
+  -c command  run command, with $0 set to name and $1... to the args
+  --no-rc     do not run $SHELLRC, or ~/.shellrc, before the first prompt
+  --help      print this help and exit
+  --version   print the version and exit
+";
+
+/// The file run before the first prompt: `$SHELLRC`, falling back to
+/// `~/.shellrc`.
+fn rc_file() -> Option<PathBuf> {
+    std::env::var_os("SHELLRC")
+        .map(PathBuf::from)
+        .or_else(|| std::env::home_dir().map(|home| home.join(".shellrc")))
 }
 
-fn command_type(com: &str) -> Option<Command> {
-    match com {
-        "exit" => Some(Command::Exit),
-        "echo" => Some(Command::Echo),
-        "cd" => Some(Command::Cd),
-        "pwd" => Some(Command::Pwd),
-        "history" => Some(Command::History),
-        "type" => Some(Command::Type),
-        _ => std::env::var_os("PATH").and_then(|paths| {
-            for path in std::env::split_paths(&paths) {
-                if path.is_dir() {
-                    for entry in path.read_dir().ok()?.flatten() {
-                        if entry.path().file_stem() == Some(com.as_ref())
-                            && is_executable(&entry.path())
-                        {
-                            return Some(Command::Program(entry.path()));
+/// Replaces the history references in `line`: `!!` with the last command,
+/// `!n` with command `n`, `!-n` with the one `n` back and `!text` with the
+/// last one that started with `text`. A `!` that is quoted, escaped or
+/// followed by a space stays as it is.
+fn expand_history<'a>(line: &'a str, history: &[&str]) -> Result<Cow<'a, str>, String> {
+    if !line.contains('!') {
+        return Ok(Cow::Borrowed(line));
+    }
+
+    let mut output = String::with_capacity(line.len());
+    let mut quote = None;
+    let mut chars = line.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match (c, quote) {
+            ('\\', Some('\'')) => {}
+            ('\\', _) => {
+                output.push(c);
+                output.extend(chars.next().map(|(_, c)| c));
+                continue;
+            }
+            ('\'', None) => quote = Some('\''),
+            ('"', None) => quote = Some('"'),
+            ('\'' | '"', Some(open)) if c == open => quote = None,
+            ('!', None | Some('"')) if !output.ends_with('$') => {
+                let rest = &line[i + 1..];
+                let len = rest
+                    .find(|c: char| c.is_whitespace() || ";|&<>()\"'".contains(c))
+                    .unwrap_or(rest.len());
+                let event = if rest.starts_with('!') {
+                    "!"
+                } else {
+                    &rest[..len]
+                };
+                if !event.is_empty() && !event.starts_with('=') {
+                    let entry = if event == "!" {
+                        history.last()
+                    } else if let Ok(number) = event.parse::<isize>() {
+                        match number {
+                            1.. => history.get(number as usize - 1),
+                            ..0 => history
+                                .len()
+                                .checked_sub(number.unsigned_abs())
+                                .and_then(|index| history.get(index)),
+                            0 => None,
                         }
+                    } else {
+                        history.iter().rev().find(|entry| entry.starts_with(event))
+                    };
+                    let Some(entry) = entry else {
+                        return Err(format!("!{event}: event not found"));
+                    };
+                    output.push_str(entry);
+                    for _ in 0..event.chars().count() {
+                        chars.next();
                     }
-                }
-                if is_executable(&path) && path.file_name()? == com {
-                    return Some(Command::Program(path));
+                    continue;
                 }
             }
-            None
-        }),
+            _ => {}
+        }
+        output.push(c);
     }
+    Ok(Cow::Owned(output))
 }
 
-#[cfg(unix)]
-fn is_executable(path: &PathBuf) -> bool {
-    if let Ok(metadata) = path.metadata() {
-        let permissions = metadata.permissions();
-        permissions.mode() & 0o111 != 0
-    } else {
-        false
+/// The file history persists to across sessions: `$HISTFILE`, falling back
+/// to `~/.shell_history`.
+fn history_file() -> Option<PathBuf> {
+    std::env::var_os("HISTFILE")
+        .map(PathBuf::from)
+        .or_else(|| std::env::home_dir().map(|home| home.join(".shell_history")))
+}
+
+/// Finds where the word at the end of `line` starts, and whether it is in
+/// command position: the first word on the line or after an unquoted `;`,
+/// `|` or `&`.
+fn completion_word(line: &str) -> (usize, bool) {
+    let mut start = 0;
+    let mut command_position = true;
+    let mut in_word = false;
+    let mut previous = None;
+    for (offset, c, live) in tag_unquoted(line) {
+        // The `&` in `2>&1` and the `|` in `>|` are part of a redirection.
+        let operator = live
+            && (c == ';'
+                || (c == '|' && previous != Some('>'))
+                || (c == '&' && !matches!(previous, Some('>' | '<'))));
+        if operator || (live && c.is_whitespace()) {
+            command_position = operator || (command_position && !in_word);
+            in_word = false;
+            start = offset + c.len_utf8();
+        } else {
+            in_word = true;
+        }
+        previous = Some(c);
     }
+    (start, command_position)
 }
 
-#[cfg(not(unix))]
-fn is_executable(path: &Path) -> bool {
-    path.is_file()
+/// Parses complete input into statements. Here-documents are inlined,
+/// comments stripped and continued lines joined first, as those passes work
+/// on the raw text.
+fn parse_source(source: &str) -> anyhow::Result<Vec<Statement>> {
+    let source = lexer::inline_here_documents(source)?;
+    let source = lexer::strip_comments(&source);
+    Ok(syntax::parse(&lexer::join_lines(&source))?)
 }
 
-fn execute_pipeline(commands: &[&str]) -> anyhow::Result<()> {
-    if commands.len() < 2 {
-        anyhow::bail!("Pipeline must have at least 2 commands");
+/// Whether `input` stops partway through a command: inside quotes, after a
+/// trailing `\`, `|`, `&&` or `||`, or before the end of a block.
+fn is_incomplete(input: &str) -> bool {
+    // Whatever the lines of a here-document hold, they cannot leave it open.
+    let Ok(input) = lexer::inline_here_documents(input) else {
+        return true;
+    };
+    // Nor can a quote in a comment.
+    let input = lexer::strip_comments(&input);
+    let input = input.as_ref();
+    if matches!(lexer::split(input), Err(LexError::UnterminatedQuote(_))) {
+        return true;
     }
+    let backslashes = input.len() - input.trim_end_matches('\\').len();
+    if backslashes % 2 == 1 {
+        return true;
+    }
+    match tag_unquoted(input.trim_end()).as_slice() {
+        [.., (_, '|', true)] | [.., (_, '&', true), (_, '&', true)] => return true,
+        _ => {}
+    }
+    matches!(syntax::parse(input), Err(SyntaxError::Incomplete))
+}
 
-    let mut children = Vec::new();
-    let mut previous_output: Option<PipeOutput> = None;
-
-    for (i, cmd) in commands.iter().enumerate() {
-        let mut input = Shlex::new(cmd);
-        let com = input.next().context("parsing command")?;
-        let args = input;
-
-        let command = command_type(&com);
-        let is_last = i == commands.len() - 1;
+/// The prompt to show before reading the next line: `PS1` when it is set,
+/// `$ ` otherwise.
+fn render_prompt(state: &ShellState) -> String {
+    match state.var("PS1") {
+        Some(ps1) => {
+            let color = std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty());
+            expand_prompt(&ps1, state.last_status, color)
+        }
+        None => String::from("$ "),
+    }
+}
 
-        match command {
-            Some(Command::Echo) | Some(Command::Type) | Some(Command::Pwd) => {
-                if is_last {
-                    execute_builtin_in_pipeline(&com, args, false)?;
-                } else {
-                    let output = execute_builtin_in_pipeline(&com, args, true)?;
-                    previous_output = Some(output);
+/// Expands the backslash escapes in a `PS1` value: `\w` for the working
+/// directory, `\u` for the user name, `\h` for the host name, `\g` for the
+/// current git branch and `\$` for `#` when running as root and `$`
+/// otherwise. With `color`, `\$` is green after a command succeeded and red
+/// after one failed.
+fn expand_prompt(ps1: &str, last_status: i32, color: bool) -> String {
+    let mut prompt = String::with_capacity(ps1.len());
+    let mut chars = ps1.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            prompt.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('w') => {
+                if let Ok(dir) = logical_dir() {
+                    prompt.push_str(&tilde_abbreviate(&dir));
                 }
             }
-            Some(Command::Program(path)) => {
-                let mut process = std::process::Command::new(&path);
-                #[cfg(unix)]
-                process.arg0(&com);
-                process.args(args);
-
-                match previous_output.take() {
-                    Some(PipeOutput::ChildStdout(stdout)) => {
-                        process.stdin(stdout);
-                    }
-                    Some(PipeOutput::Buffer(content)) => {
-                        process.stdin(Stdio::piped());
-                        let mut child = process
-                            .stdout(if is_last {
-                                Stdio::inherit()
-                            } else {
-                                Stdio::piped()
-                            })
-                            .spawn()
-                            .context(format!("spawn process {}", i))?;
-
-                        if let Some(mut stdin) = child.stdin.take() {
-                            stdin.write_all(content.as_bytes())?;
-                        }
-
-                        if !is_last {
-                            previous_output = child.stdout.take().map(PipeOutput::ChildStdout);
-                        }
-
-                        children.push(child);
-                        continue;
-                    }
-                    None => {}
-                }
-
-                if !is_last {
-                    process.stdout(Stdio::piped());
-                }
-
-                let mut child = process.spawn().context(format!("spawn process {}", i))?;
-
-                if !is_last {
-                    previous_output = child.stdout.take().map(PipeOutput::ChildStdout);
+            Some('u') => prompt.push_str(&user_name().unwrap_or_default()),
+            Some('h') => prompt.push_str(&host_name().unwrap_or_default()),
+            Some('g') => {
+                if let Ok(dir) = std::env::current_dir()
+                    && let Some(branch) = git_branch(&dir)
+                {
+                    prompt.push_str(&branch);
                 }
-
-                children.push(child);
             }
-            Some(Command::Cd) | Some(Command::History) | Some(Command::Exit) => {
-                anyhow::bail!("{} cannot be used in pipelines", com);
+            Some('$') => {
+                let c = if is_root() { '#' } else { '$' };
+                match (color, last_status) {
+                    (false, _) => prompt.push(c),
+                    (true, 0) => prompt.push_str(&format!("\x1b[32m{c}\x1b[0m")),
+                    (true, _) => prompt.push_str(&format!("\x1b[31m{c}\x1b[0m")),
+                }
             }
-            None => {
-                anyhow::bail!("{}: command not found", com);
+            Some('\\') => prompt.push('\\'),
+            Some(c) => {
+                prompt.push('\\');
+                prompt.push(c);
             }
+            None => prompt.push('\\'),
         }
     }
-
-    for child in children.iter_mut().rev() {
-        child.wait().context("wait for process")?;
-    }
-
-    Ok(())
+    prompt
 }
 
-enum PipeOutput {
-    ChildStdout(std::process::ChildStdout),
-    Buffer(String),
+/// The branch checked out in the git repository containing `dir`, or the
+/// abbreviated commit when `HEAD` is detached. Only `.git/HEAD` files on the
+/// way up to the root are looked at.
+fn git_branch(dir: &Path) -> Option<String> {
+    let git = dir
+        .ancestors()
+        .map(|dir| dir.join(".git"))
+        .find(|git| git.exists())?;
+    // A worktree or submodule has a `.git` file pointing at the real one.
+    let git = match std::fs::read_to_string(&git) {
+        Ok(link) => git.parent()?.join(link.strip_prefix("gitdir:")?.trim()),
+        Err(_) => git,
+    };
+
+    let head = std::fs::read_to_string(git.join("HEAD")).ok()?;
+    let head = head.trim();
+    match head.strip_prefix("ref:") {
+        Some(reference) => {
+            let reference = reference.trim();
+            Some(
+                reference
+                    .strip_prefix("refs/heads/")
+                    .unwrap_or(reference)
+                    .to_string(),
+            )
+        }
+        None => Some(head.chars().take(7).collect()),
+    }
+}
+
+/// Displays `dir` with the home directory shortened to `~`.
+fn tilde_abbreviate(dir: &Path) -> String {
+    if let Some(home) = std::env::home_dir()
+        && let Ok(rest) = dir.strip_prefix(&home)
+    {
+        if rest.as_os_str().is_empty() {
+            return String::from("~");
+        }
+        return format!("~/{}", rest.display());
+    }
+    dir.display().to_string()
+}
+
+/// The user name, as the environment reports it or else the password
+/// database does.
+fn user_name() -> Option<String> {
+    std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .ok()
+        .or_else(login_name)
+}
+
+#[cfg(unix)]
+fn login_name() -> Option<String> {
+    use std::ffi::CStr;
+
+    // SAFETY: the returned entry is copied out before any other passwd lookup
+    // can overwrite it.
+    unsafe {
+        let passwd = libc::getpwuid(libc::geteuid());
+        if passwd.is_null() || (*passwd).pw_name.is_null() {
+            return None;
+        }
+        Some(
+            CStr::from_ptr((*passwd).pw_name)
+                .to_string_lossy()
+                .into_owned(),
+        )
+    }
+}
+
+#[cfg(not(unix))]
+fn login_name() -> Option<String> {
+    None
+}
+
+/// The host name up to the first `.`.
+#[cfg(unix)]
+fn host_name() -> Option<String> {
+    let mut buf = [0u8; 256];
+    // SAFETY: `buf` is valid for writes of its whole length.
+    if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } != 0 {
+        return None;
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    let name = String::from_utf8_lossy(&buf[..len]);
+    Some(name.split('.').next().unwrap_or_default().to_string())
+}
+
+#[cfg(not(unix))]
+fn host_name() -> Option<String> {
+    std::env::var("COMPUTERNAME").ok()
+}
+
+#[cfg(unix)]
+fn is_root() -> bool {
+    // SAFETY: `geteuid` has no memory safety requirements.
+    unsafe { libc::geteuid() == 0 }
+}
+
+#[cfg(not(unix))]
+fn is_root() -> bool {
+    false
+}
+
+/// Runs `statements` in order. A failing list is reported and the next one
+/// runs anyway, unless `set -e` is on; `Break` asks the shell to stop with
+/// the given exit status.
+fn execute_statements(
+    rl: &mut ShellEditor,
+    state: &mut ShellState,
+    statements: &[Statement],
+) -> ControlFlow<Jump> {
+    for statement in statements {
+        if INTERRUPTED.load(Ordering::Relaxed) {
+            state.last_status = INTERRUPTED_STATUS;
+            return ControlFlow::Break(Jump::Interrupt);
+        }
+        match statement {
+            Statement::List(list) => match execute_list(rl, state, list) {
+                Ok(flow) => flow?,
+                Err(e) => {
+                    eprintln!("{e:#}");
+                    state.last_status = 1;
+                    if state.options.errexit {
+                        return ControlFlow::Break(Jump::Exit(1));
+                    }
+                }
+            },
+            Statement::If {
+                branches,
+                otherwise,
+            } => execute_if(rl, state, branches, otherwise)?,
+            Statement::For { name, words, body } => {
+                execute_for(rl, state, name, words.as_deref(), body)?
+            }
+            Statement::While {
+                until,
+                condition,
+                body,
+            } => execute_while(rl, state, *until, condition, body)?,
+            Statement::Group(body) => execute_statements(rl, state, body)?,
+            Statement::Function { name, body } => {
+                state.functions.insert(name.clone(), body.as_slice().into());
+                state.last_status = 0;
+            }
+        }
+    }
+    ControlFlow::Continue(())
+}
+
+/// Runs the body of the first branch whose condition succeeds, or
+/// `otherwise`. With no branch taken and no `else`, the status is 0.
+fn execute_if(
+    rl: &mut ShellEditor,
+    state: &mut ShellState,
+    branches: &[Branch],
+    otherwise: &[Statement],
+) -> ControlFlow<Jump> {
+    for branch in branches {
+        execute_condition(rl, state, &branch.condition)?;
+        if state.last_status == 0 {
+            return execute_statements(rl, state, &branch.body);
+        }
+    }
+    state.last_status = 0;
+    execute_statements(rl, state, otherwise)
+}
+
+/// Runs the condition of an `if` or a loop, which `set -e` must not exit on
+/// the failure of.
+fn execute_condition(
+    rl: &mut ShellEditor,
+    state: &mut ShellState,
+    condition: &[Statement],
+) -> ControlFlow<Jump> {
+    let errexit = std::mem::replace(&mut state.options.errexit, false);
+    let flow = execute_statements(rl, state, condition);
+    state.options.errexit = errexit;
+    flow
+}
+
+/// Runs `body` once for each of the expanded `words`, or the positional
+/// parameters, with the variable `name` set to it.
+fn execute_for(
+    rl: &mut ShellEditor,
+    state: &mut ShellState,
+    name: &str,
+    words: Option<&str>,
+    body: &[Statement],
+) -> ControlFlow<Jump> {
+    if !is_valid_name(name) {
+        eprintln!("for: `{name}': not a valid identifier");
+        state.last_status = 1;
+        return ControlFlow::Continue(());
+    }
+    let values = match words.map(|words| expand_words(state, words)) {
+        Some(Ok(values)) => values,
+        Some(Err(e)) => {
+            eprintln!("{e:#}");
+            state.last_status = 1;
+            return ControlFlow::Continue(());
+        }
+        None => state.args.get(1..).unwrap_or_default().to_vec(),
+    };
+
+    state.last_status = 0;
+    state.loop_depth += 1;
+    let mut flow = ControlFlow::Continue(());
+    for value in values {
+        state.set_var(name, value);
+        if let Some(end) = loop_end(execute_statements(rl, state, body)) {
+            flow = end;
+            break;
+        }
+    }
+    state.loop_depth -= 1;
+    flow
+}
+
+/// Runs `body` for as long as `condition` succeeds, or fails with `until`.
+fn execute_while(
+    rl: &mut ShellEditor,
+    state: &mut ShellState,
+    until: bool,
+    condition: &[Statement],
+    body: &[Statement],
+) -> ControlFlow<Jump> {
+    let mut status = 0;
+    state.loop_depth += 1;
+    let flow = loop {
+        if let Some(end) = loop_end(execute_condition(rl, state, condition)) {
+            break end;
+        }
+        if (state.last_status == 0) == until {
+            break ControlFlow::Continue(());
+        }
+        if let Some(end) = loop_end(execute_statements(rl, state, body)) {
+            break end;
+        }
+        status = state.last_status;
+    };
+    state.loop_depth -= 1;
+    // The loop's status is that of the last pass through the body.
+    if flow.is_continue() {
+        state.last_status = status;
+    }
+    flow
+}
+
+/// Whether a loop ends after running part of it left `flow`. `Some` has the
+/// flow for whatever contains the loop, which a `break` or `continue` aimed
+/// further out than this loop is passed on to.
+fn loop_end(flow: ControlFlow<Jump>) -> Option<ControlFlow<Jump>> {
+    match flow {
+        ControlFlow::Continue(()) | ControlFlow::Break(Jump::Continue(1)) => None,
+        ControlFlow::Break(Jump::Break(1)) => Some(ControlFlow::Continue(())),
+        ControlFlow::Break(Jump::Break(count)) => Some(ControlFlow::Break(Jump::Break(count - 1))),
+        ControlFlow::Break(Jump::Continue(count)) => {
+            Some(ControlFlow::Break(Jump::Continue(count - 1)))
+        }
+        flow => Some(flow),
+    }
+}
+
+/// Where `break` or `continue` goes: out of as many loops as its argument
+/// says, 1 by default and all of them at most.
+fn loop_jump(
+    state: &mut ShellState,
+    builtin: &str,
+    mut args: impl Iterator<Item = String>,
+) -> ControlFlow<Jump> {
+    let count = match args.next() {
+        None => 1,
+        Some(arg) => match arg.parse::<usize>() {
+            Ok(0) => {
+                eprintln!("{builtin}: {arg}: loop count out of range");
+                state.last_status = 1;
+                return ControlFlow::Continue(());
+            }
+            Ok(count) => count,
+            Err(_) => {
+                eprintln!("{builtin}: {arg}: numeric argument required");
+                state.last_status = 1;
+                return ControlFlow::Continue(());
+            }
+        },
+    };
+    state.last_status = 0;
+    if state.loop_depth == 0 {
+        eprintln!("{builtin}: only meaningful in a `for', `while', or `until' loop");
+        return ControlFlow::Continue(());
+    }
+
+    let count = count.min(state.loop_depth);
+    ControlFlow::Break(if builtin == "break" {
+        Jump::Break(count)
+    } else {
+        Jump::Continue(count)
+    })
+}
+
+/// Expands `words` the way a command's arguments are, without treating any
+/// of them as redirections.
+fn expand_words(state: &ShellState, words: &str) -> anyhow::Result<Vec<String>> {
+    let words = expand_globs(&expand_vars(&expand_braces(words), state)?);
+    Ok(lexer::split(&words)?.into_iter().map(argument).collect())
+}
+
+/// Runs a list of commands separated by `;` or `&`. Errors are local to the
+/// list and leave the shell running.
+fn execute_list(
+    rl: &mut ShellEditor,
+    state: &mut ShellState,
+    readline: &str,
+) -> anyhow::Result<ControlFlow<Jump>> {
+    let readline = expand_aliases(state, readline, &mut Vec::new());
+    for (list, _) in split_unquoted(&readline, &[";"]) {
+        if list.trim().is_empty() {
+            continue;
+        }
+        for (list, background) in split_background(list) {
+            if list.trim().is_empty() {
+                eprintln!("syntax error near unexpected token `&'");
+                state.last_status = 2;
+                break;
+            }
+            if let ControlFlow::Break(jump) = execute_and_or(rl, state, list, background)? {
+                return Ok(ControlFlow::Break(jump));
+            }
+        }
+    }
+
+    Ok(ControlFlow::Continue(()))
+}
+
+/// Splits `list` after every unquoted `&` that sends what comes before it to
+/// the background, saying of each piece whether it ended in one.
+fn split_background(list: &str) -> Vec<(&str, bool)> {
+    let tagged = tag_unquoted(list);
+    let mut pieces = Vec::new();
+    let mut start = 0;
+    for (index, &(offset, c, live)) in tagged.iter().enumerate() {
+        let before = index.checked_sub(1).map(|index| tagged[index].1);
+        let after = tagged.get(index + 1).map(|&(_, c, _)| c);
+        // `&&` and the `&` in `2>&1` are not a request to go to the background.
+        if live
+            && c == '&'
+            && !matches!(before, Some('&' | '>' | '<'))
+            && !matches!(after, Some('&' | '>'))
+        {
+            pieces.push((&list[start..offset], true));
+            start = offset + 1;
+        }
+    }
+    let rest = &list[start..];
+    if pieces.is_empty() || !rest.trim().is_empty() {
+        pieces.push((rest.trim_end(), false));
+    }
+    pieces
+}
+
+/// Runs a chain of commands joined by `&&` and `||`.
+fn execute_and_or(
+    rl: &mut ShellEditor,
+    state: &mut ShellState,
+    readline: &str,
+    background: bool,
+) -> anyhow::Result<ControlFlow<Jump>> {
+    let clauses = split_unquoted(readline, &["&&", "||"]);
+    if background && clauses.len() > 1 {
+        anyhow::bail!("background and-or lists are not supported");
+    }
+    if clauses.len() > 1
+        && let Some((_, op)) = clauses.iter().find(|(clause, _)| clause.trim().is_empty())
+    {
+        eprintln!(
+            "syntax error near unexpected token `{}'",
+            op.unwrap_or("newline")
+        );
+        state.last_status = 2;
+        return Ok(ControlFlow::Continue(()));
+    }
+
+    // `a && b || c` groups as `(a && b) || c`: a skipped clause leaves the
+    // status untouched, so the next operator sees the last one that ran.
+    let mut op = None;
+    for (clause, next_op) in clauses {
+        let run = match op {
+            Some("&&") => state.last_status == 0,
+            Some("||") => state.last_status != 0,
+            _ => true,
+        };
+        if run {
+            match execute_command(rl, state, clause, background) {
+                Ok(ControlFlow::Continue(())) => {}
+                Ok(ControlFlow::Break(jump)) => return Ok(ControlFlow::Break(jump)),
+                Err(e) => {
+                    eprintln!("{e:#}");
+                    state.last_status = 1;
+                }
+            }
+            // `set -e` lets the commands before the last `&&` or `||` fail.
+            if next_op.is_none() && state.options.errexit && state.last_status != 0 {
+                return Ok(ControlFlow::Break(Jump::Exit(state.last_status)));
+            }
+        }
+        op = next_op;
+    }
+
+    Ok(ControlFlow::Continue(()))
+}
+
+/// Splits `line` into the commands of a pipeline at each unquoted `|` that is
+/// not part of `||` or `>|`, leaving alone any inside `$(...)` or backquotes.
+fn split_pipeline(line: &str) -> Vec<&str> {
+    let tagged = tag_unquoted(line);
+    let live = |index: usize| {
+        tagged
+            .get(index)
+            .filter(|&&(_, _, live)| live)
+            .map(|&(_, c, _)| c)
+    };
+    let mut commands = Vec::new();
+    let mut start = 0;
+    let mut depth = 0usize;
+    let mut backquoted = false;
+
+    for (index, &(offset, c, _)) in tagged.iter().enumerate() {
+        if live(index).is_none() {
+            continue;
+        }
+        match c {
+            '`' => backquoted = !backquoted,
+            '(' if !backquoted => depth += 1,
+            ')' if !backquoted => depth = depth.saturating_sub(1),
+            '|' if depth == 0
+                && !backquoted
+                && live(index + 1) != Some('|')
+                && !matches!(index.checked_sub(1).and_then(live), Some('|' | '>')) =>
+            {
+                commands.push(&line[start..offset]);
+                start = offset + 1;
+            }
+            _ => {}
+        }
+    }
+    commands.push(&line[start..]);
+
+    commands
+}
+
+/// Replaces any alias found in command position, i.e. at the start of the line
+/// or after an unquoted `;`, `&`, or `|`. A name is never expanded again
+/// inside its own expansion, which is what makes `alias ls='ls -F'` work.
+fn expand_aliases(state: &ShellState, line: &str, expanding: &mut Vec<String>) -> String {
+    let tagged = tag_unquoted(line);
+    let is_operator = |i: usize| {
+        let (_, c, live) = tagged[i];
+        // The `&` in `2>&1` and the `|` in `>|` are part of a redirection.
+        live && (c == ';'
+            || (c == '|' && (i == 0 || tagged[i - 1].1 != '>'))
+            || (c == '&' && (i == 0 || !matches!(tagged[i - 1].1, '>' | '<'))))
+    };
+
+    let mut output = String::with_capacity(line.len());
+    let mut command_position = true;
+    let mut i = 0;
+    while i < tagged.len() {
+        let (offset, c, live) = tagged[i];
+        if live && c.is_whitespace() {
+            output.push(c);
+            i += 1;
+            continue;
+        }
+        if is_operator(i) {
+            output.push(c);
+            command_position = true;
+            i += 1;
+            continue;
+        }
+
+        let mut end = i;
+        while end < tagged.len()
+            && !is_operator(end)
+            && !(tagged[end].2 && tagged[end].1.is_whitespace())
+        {
+            end += 1;
+        }
+        let unquoted = tagged[i..end].iter().all(|&(_, _, live)| live);
+        let word = &line[offset..tagged.get(end).map_or(line.len(), |&(offset, _, _)| offset)];
+        i = end;
+
+        match state.aliases.get(word) {
+            Some(value)
+                if command_position && unquoted && !expanding.iter().any(|name| name == word) =>
+            {
+                expanding.push(word.to_string());
+                output.push_str(&expand_aliases(state, value, expanding));
+                expanding.pop();
+                // An alias ending in a blank makes the next word a candidate too.
+                command_position = value.ends_with(char::is_whitespace);
+            }
+            _ => {
+                output.push_str(word);
+                command_position = false;
+            }
+        }
+    }
+
+    output
+}
+
+/// Splits `line` on any of `separators` that appear outside quotes, pairing
+/// each piece with the separator that ended it.
+fn split_unquoted<'a>(
+    line: &'a str,
+    separators: &[&'static str],
+) -> Vec<(&'a str, Option<&'static str>)> {
+    let mut pieces = Vec::new();
+    let mut quote = Quote::None;
+    let mut start = 0;
+    let mut chars = line.char_indices();
+
+    while let Some((i, c)) = chars.next() {
+        match (c, quote) {
+            ('\\', Quote::None | Quote::Double) => {
+                chars.next();
+            }
+            ('\'', Quote::None) => quote = Quote::Single,
+            ('\'', Quote::Single) => quote = Quote::None,
+            ('"', Quote::None) => quote = Quote::Double,
+            ('"', Quote::Double) => quote = Quote::None,
+            (_, Quote::None) => {
+                if let Some(sep) = separators.iter().find(|sep| line[i..].starts_with(**sep)) {
+                    pieces.push((&line[start..i], Some(*sep)));
+                    start = i + sep.len();
+                    for _ in 1..sep.chars().count() {
+                        chars.next();
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    pieces.push((&line[start..], None));
+
+    pieces
+}
+
+/// Runs a single command or pipeline, leaving a program running as a job
+/// when `background` is set.
+fn execute_command(
+    rl: &mut ShellEditor,
+    state: &mut ShellState,
+    readline: &str,
+    background: bool,
+) -> anyhow::Result<ControlFlow<Jump>> {
+    if let Some(rest) = strip_time(readline) {
+        return time(rl, state, rest, background);
+    }
+
+    let commands = split_pipeline(readline);
+    if commands.len() > 1 {
+        if background {
+            anyhow::bail!("background pipelines are not supported");
+        }
+        let commands: Vec<&str> = commands.into_iter().map(str::trim).collect();
+        if commands.iter().any(|command| command.is_empty()) {
+            eprintln!("syntax error near unexpected token `|'");
+            state.last_status = 2;
+            return Ok(ControlFlow::Continue(()));
+        }
+
+        state.last_status = match execute_pipeline(Some(rl), state, &commands, false) {
+            Ok((_, status, _, stopped)) if stopped.is_empty() => status,
+            Ok((_, _, pgid, stopped)) => {
+                let job = state.new_job(readline.trim(), pgid, stopped);
+                keep_stopped(state, job)
+            }
+            Err(e) => {
+                eprintln!("Pipeline error: {}", e);
+                1
+            }
+        };
+        return Ok(ControlFlow::Continue(()));
+    }
+
+    let line = expand_globs(&expand_vars_mut(&expand_braces(readline.trim()), state)?);
+    let mut words = lexer::split_command(&line)?;
+    trace_command(state, &words);
+    let env = take_assignments(&mut words);
+    let mut args = words.into_iter().peekable();
+    // Assignments on their own set shell variables, and a line that was
+    // blank or expanded to nothing just does nothing.
+    let Some(com) = args.next().map(argument) else {
+        for (name, value) in env {
+            state.set_var(&name, value);
+        }
+        state.last_status = 0;
+        return Ok(ControlFlow::Continue(()));
+    };
+    // `command name` skips any function called `name`.
+    let bypassed = com == "command";
+    let com = skip_command_prefix(com, &mut args);
+    if !(bypassed && com != "command")
+        && let Some(body) = state.functions.get(&com).cloned()
+    {
+        return Ok(call_function(rl, state, &body, args.map(argument)));
+    }
+
+    let command = command_type(&com);
+
+    let status = match command {
+        Some(Command::Echo) => {
+            let args = Parser::new(args, &state.options)?;
+            let output = echo(&args.args);
+            write_output(&com, &output, args.stdout, args.stderr)
+        }
+        Some(Command::Cd) => cd(args.map(argument))?,
+        Some(Command::Pwd) => {
+            let args = Parser::new(args, &state.options)?;
+            let (output, status) = pwd(&args.args)?;
+            match write_output(&com, output.as_bytes(), args.stdout, args.stderr) {
+                0 => status,
+                failed => failed,
+            }
+        }
+        Some(Command::History) => {
+            print!("{}", history(rl, HistoryInfo::new(args.map(argument))?)?);
+            0
+        }
+        Some(Command::Export) => export(state, args.map(argument)),
+        Some(Command::Alias) => alias(state, args.map(argument)),
+        Some(Command::Unalias) => unalias(state, args.map(argument)),
+        Some(Command::Jobs) => {
+            report_jobs(state, true);
+            0
+        }
+        Some(Command::Fg) => fg(state, args.map(argument))?,
+        Some(Command::Bg) => bg(state, args.map(argument)),
+        Some(Command::Kill) => kill(state, args.map(argument)),
+        Some(Command::Wait) => wait(state, args.map(argument))?,
+        Some(Command::Bypass) => command_builtin(state, args.map(argument)),
+        Some(Command::Hash) => hash(args.map(argument)),
+        Some(Command::Pushd) => pushd(state, args.map(argument))?,
+        Some(Command::Popd) => popd(state)?,
+        Some(Command::Dirs) => dirs(state, args.map(argument))?,
+        Some(Command::Rehash) => {
+            rehash();
+            0
+        }
+        Some(Command::Program(ref path)) => {
+            let group = state.process_group(background);
+            let child =
+                match spawn_command(path, &com, Parser::new(args, &state.options)?, &env, group) {
+                    Ok(child) => child,
+                    // Say why the program would not start, as when its `#!`
+                    // interpreter is missing.
+                    Err(e) => match e.downcast_ref::<std::io::Error>() {
+                        Some(io) => {
+                            eprintln!("{com}: {}", io_error_message(io));
+                            state.last_status = not_runnable_status(io);
+                            return Ok(ControlFlow::Continue(()));
+                        }
+                        None => return Err(e),
+                    },
+                };
+            let job = state.new_job(readline.trim(), child.id(), vec![child]);
+            if background {
+                println!("[{}] {}", job.id, job.pid);
+                state.jobs.push(job);
+                0
+            } else {
+                wait_foreground(state, job)?
+            }
+        }
+        Some(Command::Exit) => {
+            let code = match args.next().map(argument) {
+                Some(arg) => arg.parse().unwrap_or_else(|_| {
+                    eprintln!("exit: {arg}: numeric argument required");
+                    255
+                }),
+                None => state.last_status,
+            };
+            return Ok(ControlFlow::Break(Jump::Exit(code)));
+        }
+        Some(Command::Type) => {
+            let (output, status) = type_builtin(state, args.map(argument));
+            print!("{output}");
+            status
+        }
+        Some(Command::Set) => set(state, args.map(argument)),
+        Some(Command::Read) => read(state, Parser::new(args, &state.options)?)?,
+        Some(Command::Unset) => unset(state, args.map(argument)),
+        Some(Command::Local) => local(state, args.map(argument)),
+        Some(Command::Shift) => shift(state, args.map(argument)),
+        Some(Command::Fc) => return fc(rl, state, args.map(argument)),
+        // Redirections still happen, which makes `: > file` empty the file.
+        Some(Command::True | Command::Null) => {
+            Parser::new(args, &state.options)?;
+            0
+        }
+        Some(Command::False) => {
+            Parser::new(args, &state.options)?;
+            1
+        }
+        Some(Command::Test) => test(&com, Parser::new(args, &state.options)?.args),
+        Some(Command::Printf) => {
+            let args = Parser::new(args, &state.options)?;
+            let (output, status) = printf_builtin(&args.args);
+            match write_output(&com, &output, args.stdout, args.stderr) {
+                0 => status,
+                failed => failed,
+            }
+        }
+        Some(Command::Break | Command::Continue) => {
+            return Ok(loop_jump(state, &com, args.map(argument)));
+        }
+        Some(Command::Return) => return Ok(return_jump(state, args.map(argument))),
+        Some(Command::Exec) => return exec(state, Parser::new(args, &state.options)?, &env),
+        Some(Command::Umask) => {
+            let args = Parser::new(args, &state.options)?;
+            let (output, status) = umask(&args.args);
+            match write_output(&com, output.as_bytes(), args.stdout, args.stderr) {
+                0 => status,
+                failed => failed,
+            }
+        }
+        // The sourced lines set the status themselves.
+        Some(Command::Source) => return source(rl, state, &com, args.map(argument)),
+        None => {
+            let paths = std::env::var_os("PATH").unwrap_or_default();
+            match unrunnable(&paths, &com) {
+                Some(reason) => {
+                    eprintln!("{com}: {reason}");
+                    NOT_EXECUTABLE_STATUS
+                }
+                // A path is not searched for, so it is just missing.
+                None if com.contains('/') => {
+                    eprintln!("{com}: No such file or directory");
+                    COMMAND_NOT_FOUND_STATUS
+                }
+                None => {
+                    println!("{com}: command not found");
+                    COMMAND_NOT_FOUND_STATUS
+                }
+            }
+        }
+    };
+    state.last_status = status;
+
+    Ok(ControlFlow::Continue(()))
+}
+
+/// Runs the body of a function with `args` as the positional parameters,
+/// putting the caller's back once it is done.
+fn call_function(
+    rl: &mut ShellEditor,
+    state: &mut ShellState,
+    body: &[Statement],
+    args: impl Iterator<Item = String>,
+) -> ControlFlow<Jump> {
+    // `$0` is still the shell's name.
+    let mut positional = state.args[..1].to_vec();
+    positional.extend(args);
+    let caller = std::mem::replace(&mut state.args, positional);
+    state.scopes.push(HashMap::new());
+    state.last_status = 0;
+    let flow = returned(state, |state| execute_statements(rl, state, body));
+    state.scopes.pop();
+    state.args = caller;
+    flow
+}
+
+/// Runs `run` as the body of a function or sourced file, which a `return`
+/// inside it stops.
+fn returned(
+    state: &mut ShellState,
+    run: impl FnOnce(&mut ShellState) -> ControlFlow<Jump>,
+) -> ControlFlow<Jump> {
+    state.return_depth += 1;
+    let flow = run(state);
+    state.return_depth -= 1;
+    match flow {
+        ControlFlow::Break(Jump::Return) => ControlFlow::Continue(()),
+        flow => flow,
+    }
+}
+
+/// Where `return` goes: out of the function or sourced file, with the status
+/// its argument gives or that of the last command.
+fn return_jump(
+    state: &mut ShellState,
+    mut args: impl Iterator<Item = String>,
+) -> ControlFlow<Jump> {
+    if state.return_depth == 0 {
+        eprintln!("return: can only `return' from a function or sourced script");
+        state.last_status = 1;
+        return ControlFlow::Continue(());
+    }
+    if let Some(arg) = args.next() {
+        state.last_status = match arg.parse::<i64>() {
+            // Statuses wrap around the way they do for programs.
+            Ok(status) => i32::from(status as u8),
+            Err(_) => {
+                eprintln!("return: {arg}: numeric argument required");
+                2
+            }
+        };
+    }
+    ControlFlow::Break(Jump::Return)
+}
+
+/// The rest of `line` after a leading `time` keyword, if it has one.
+fn strip_time(line: &str) -> Option<&str> {
+    let rest = line.trim_start().strip_prefix("time")?;
+    (rest.is_empty() || rest.starts_with(char::is_whitespace)).then_some(rest)
+}
+
+/// How `time` reports, as in bash: `%R`, `%U` and `%S` are the real, user
+/// and system time in seconds, with an optional number of decimals between
+/// the `%` and the letter, and an `l` for minutes and seconds.
+const DEFAULT_TIMEFORMAT: &str = "\nreal\t%3lR\nuser\t%3lU\nsys\t%3lS";
+
+/// The format of `time -p`.
+const POSIX_TIMEFORMAT: &str = "real %2R\nuser %2U\nsys %2S";
+
+/// Runs the pipeline in `rest` and then reports how long it took on
+/// standard error, in the format `TIMEFORMAT` gives, if it is set.
+fn time(
+    rl: &mut ShellEditor,
+    state: &mut ShellState,
+    rest: &str,
+    background: bool,
+) -> anyhow::Result<ControlFlow<Jump>> {
+    let (rest, posix) = match rest.trim_start().strip_prefix("-p") {
+        Some(after) if after.is_empty() || after.starts_with(char::is_whitespace) => (after, true),
+        _ => (rest, false),
+    };
+    let start = std::time::Instant::now();
+    let (user, sys) = cpu_times();
+    let result = execute_command(rl, state, rest, background);
+
+    let real = start.elapsed();
+    let (user_after, sys_after) = cpu_times();
+    let format = if posix {
+        POSIX_TIMEFORMAT.to_string()
+    } else {
+        state
+            .var("TIMEFORMAT")
+            .unwrap_or_else(|| DEFAULT_TIMEFORMAT.to_string())
+    };
+    let times = TimeReport {
+        real,
+        user: user_after.saturating_sub(user),
+        sys: sys_after.saturating_sub(sys),
+    };
+    eprintln!("{}", times.format(&format));
+    result
+}
+
+/// What `time` measured of a command.
+struct TimeReport {
+    real: std::time::Duration,
+    user: std::time::Duration,
+    sys: std::time::Duration,
+}
+
+impl TimeReport {
+    /// Expands the `%` sequences of a `TIMEFORMAT` value.
+    fn format(&self, format: &str) -> String {
+        let mut output = String::with_capacity(format.len());
+        let mut chars = format.chars().peekable();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                output.push(c);
+                continue;
+            }
+            if chars.next_if_eq(&'%').is_some() {
+                output.push('%');
+                continue;
+            }
+            // Bash shows at most 6 decimals, and 3 when none are asked for.
+            let precision = chars
+                .next_if(char::is_ascii_digit)
+                .and_then(|digit| digit.to_digit(10))
+                .map_or(3, |digit| digit.min(6) as usize);
+            let long = chars.next_if_eq(&'l').is_some();
+            let duration = match chars.next() {
+                Some('R') => self.real,
+                Some('U') => self.user,
+                Some('S') => self.sys,
+                other => {
+                    output.push('%');
+                    output.extend(other);
+                    continue;
+                }
+            };
+            let seconds = duration.as_secs_f64();
+            if long {
+                let minutes = (seconds / 60.0).floor();
+                let seconds = seconds - minutes * 60.0;
+                output.push_str(&format!("{minutes}m{seconds:.precision$}s"));
+            } else {
+                output.push_str(&format!("{seconds:.precision$}"));
+            }
+        }
+        output
+    }
+}
+
+/// The user and system time the shell and the children it waited for have
+/// used so far.
+#[cfg(unix)]
+fn cpu_times() -> (std::time::Duration, std::time::Duration) {
+    let usage = |who| {
+        // SAFETY: `rusage` is plain data, which `getrusage` fills in.
+        unsafe {
+            let mut usage = std::mem::zeroed::<libc::rusage>();
+            libc::getrusage(who, &mut usage);
+            usage
+        }
+    };
+    let duration = |time: libc::timeval| {
+        std::time::Duration::new(time.tv_sec as u64, time.tv_usec as u32 * 1000)
+    };
+    let (shell, children) = (usage(libc::RUSAGE_SELF), usage(libc::RUSAGE_CHILDREN));
+    (
+        duration(shell.ru_utime) + duration(children.ru_utime),
+        duration(shell.ru_stime) + duration(children.ru_stime),
+    )
+}
+
+#[cfg(not(unix))]
+fn cpu_times() -> (std::time::Duration, std::time::Duration) {
+    Default::default()
+}
+
+/// Runs the commands in the file named by the first of `args` in the current
+/// shell.
+fn source(
+    rl: &mut ShellEditor,
+    state: &mut ShellState,
+    builtin: &str,
+    mut args: impl Iterator<Item = String>,
+) -> anyhow::Result<ControlFlow<Jump>> {
+    let Some(path) = args.next() else {
+        eprintln!("{builtin}: filename argument required");
+        state.last_status = 2;
+        return Ok(ControlFlow::Continue(()));
+    };
+    let script = match std::fs::read_to_string(&path) {
+        Ok(script) => script,
+        Err(e) => {
+            eprintln!("{builtin}: {path}: {}", io_error_message(&e));
+            state.last_status = 1;
+            return Ok(ControlFlow::Continue(()));
+        }
+    };
+
+    state.last_status = 0;
+    Ok(returned(state, |state| execute_script(rl, state, &script)))
+}
+
+fn cd(args: impl Iterator<Item = String>) -> anyhow::Result<i32> {
+    let mut args = args.peekable();
+    // `--` ends the options, so a directory named `-` or `-x` can follow.
+    let options_ended = args.next_if(|arg| arg == "--").is_some();
+    let Some(arg) = args.next() else {
+        return match std::env::home_dir() {
+            Some(home_dir) => Ok(status_of(change_dir("cd", &home_dir)?)),
+            None => {
+                eprintln!("cd: HOME not set");
+                Ok(1)
+            }
+        };
+    };
+
+    if arg.starts_with('-') && arg != "-" && !options_ended {
+        eprintln!("cd: {arg}: invalid option");
+        return Ok(2);
+    }
+
+    if arg == "-" && !options_ended {
+        match std::env::var_os("OLDPWD") {
+            Some(old_dir) => {
+                if !change_dir("cd", Path::new(&old_dir))? {
+                    return Ok(1);
+                }
+                println!("{}", logical_dir()?.display());
+                Ok(0)
+            }
+            None => {
+                eprintln!("cd: OLDPWD not set");
+                Ok(1)
+            }
+        }
+    } else if let Some(dir) = std::env::var_os("CDPATH")
+        .filter(|_| !Path::new(&arg).is_dir())
+        .and_then(|cdpath| search_cdpath(&cdpath, &arg))
+    {
+        if !change_dir("cd", &dir)? {
+            return Ok(1);
+        }
+        // Say where `cd` went, as it was not where the argument pointed.
+        println!("{}", dir.display());
+        Ok(0)
+    } else {
+        Ok(status_of(change_dir("cd", Path::new(&arg))?))
+    }
+}
+
+/// Looks for `dir` in the directories of `cdpath`, unless it is absolute or
+/// starts with `.` or `..`, which only ever mean the current directory.
+fn search_cdpath(cdpath: &OsStr, dir: &str) -> Option<PathBuf> {
+    let path = Path::new(dir);
+    let first = path.components().next()?;
+    if !matches!(first, std::path::Component::Normal(_)) {
+        return None;
+    }
+    std::env::split_paths(cdpath)
+        .filter(|base| !base.as_os_str().is_empty())
+        .map(|base| base.join(path))
+        .find(|candidate| candidate.is_dir())
+}
+
+/// Maps a builtin's success flag to an exit status.
+fn status_of(success: bool) -> i32 {
+    if success { 0 } else { 1 }
+}
+
+/// Applies the `history` options and returns the listing, if one was asked
+/// for, so pipelines can capture it.
+fn history(rl: &mut ShellEditor, history_info: HistoryInfo) -> anyhow::Result<String> {
+    let mut output = String::new();
+    if history_info.clear {
+        rl.clear_history()
+            .map_err(|e| anyhow::anyhow!("history: {e}"))?;
+    }
+
+    if let Some(read) = history_info.read {
+        rl.load_history(&read)
+            .map_err(|e| anyhow::anyhow!("history: {}: {e}", read.display()))?;
+    } else if let Some(write) = history_info.write {
+        rl.save_history(&write)
+            .map_err(|e| anyhow::anyhow!("history: {}: {e}", write.display()))?;
+        remove_tag(write).context("Remove #V2 tag from history file")?;
+    } else if let Some(append) = history_info.append {
+        rl.append_history(&append)
+            .map_err(|e| anyhow::anyhow!("history: {}: {e}", append.display()))?;
+        remove_tag(append).context("Remove #V2 tag from history file")?;
+    } else if let Some(num) = history_info.num {
+        let history = rl
+            .history()
+            .iter()
+            .rev()
+            .enumerate()
+            .take(num)
+            .collect::<Vec<_>>();
+        for (i, entry) in history.iter().rev() {
+            output.push_str(&format!("  {}  {}\n", rl.history().len() - i, entry));
+        }
+    } else if !history_info.clear {
+        rl.history()
+            .iter()
+            .enumerate()
+            .for_each(|(i, entry)| output.push_str(&format!("    {}  {entry}\n", i + 1)));
+    }
+    Ok(output)
+}
+
+/// `fc -l [first [last]]` lists history entries, by default the last 16.
+/// `fc [first [last]]` writes them, by default the last one, to a file for
+/// `$FCEDIT` or `$EDITOR`, falling back to `vi`, and runs what is saved.
+fn fc(
+    rl: &mut ShellEditor,
+    state: &mut ShellState,
+    args: impl Iterator<Item = String>,
+) -> anyhow::Result<ControlFlow<Jump>> {
+    let mut args = args.peekable();
+    let (mut list, mut numbered, mut reverse) = (false, true, false);
+    // A negative number is an entry counted back from the end, not an option.
+    while let Some(arg) = args.next_if(|arg| arg.starts_with('-') && arg.parse::<i64>().is_err()) {
+        if arg == "--" {
+            break;
+        }
+        for flag in arg.chars().skip(1) {
+            match flag {
+                'l' => list = true,
+                'n' => numbered = false,
+                'r' => reverse = true,
+                _ => {
+                    eprintln!("fc: -{flag}: invalid option");
+                    state.last_status = 2;
+                    return Ok(ControlFlow::Continue(()));
+                }
+            }
+        }
+    }
+    let (first, last) = (args.next(), args.next());
+
+    // The line that ran `fc` is in the history already when it was typed.
+    let mut entries = rl.history().iter().map(String::as_str).collect::<Vec<_>>();
+    if state.interactive {
+        entries.pop();
+    }
+    let Some((start, end)) = history_range(&entries, first.as_deref(), last.as_deref(), list)
+    else {
+        if !(list && entries.is_empty()) {
+            eprintln!("fc: no command found");
+            state.last_status = 1;
+        }
+        return Ok(ControlFlow::Continue(()));
+    };
+    let mut selected = if start <= end {
+        (start..=end).collect::<Vec<_>>()
+    } else {
+        (end..=start).rev().collect()
+    };
+    if reverse {
+        selected.reverse();
+    }
+
+    if list {
+        for i in selected {
+            if numbered {
+                println!("{}\t {}", i + 1, entries[i]);
+            } else {
+                println!("\t {}", entries[i]);
+            }
+        }
+        state.last_status = 0;
+        return Ok(ControlFlow::Continue(()));
+    }
+
+    let mut script = selected
+        .iter()
+        .map(|&i| entries[i])
+        .collect::<Vec<_>>()
+        .join("\n");
+    script.push('\n');
+    let path = match write_fc_file(&script) {
+        Ok(path) => path,
+        Err(e) => {
+            let dir = std::env::temp_dir();
+            eprintln!("fc: {}: {}", dir.display(), io_error_message(&e));
+            state.last_status = 1;
+            return Ok(ControlFlow::Continue(()));
+        }
+    };
+
+    let editor = ["FCEDIT", "EDITOR"]
+        .iter()
+        .find_map(|name| state.var(name).filter(|editor| !editor.is_empty()))
+        .unwrap_or_else(|| String::from("vi"));
+    let edit = format!("{editor} {}", single_quote(&path.to_string_lossy()));
+    state.last_status = 0;
+    let edited = match execute_script(rl, state, &edit) {
+        ControlFlow::Continue(()) if state.last_status == 0 => std::fs::read_to_string(&path),
+        // Nothing is run when the editor fails or is interrupted.
+        flow => {
+            let _ = std::fs::remove_file(&path);
+            return Ok(flow);
+        }
+    };
+    let _ = std::fs::remove_file(&path);
+    let script = match edited {
+        Ok(script) => script,
+        Err(e) => {
+            eprintln!("fc: {}: {}", path.display(), io_error_message(&e));
+            state.last_status = 1;
+            return Ok(ControlFlow::Continue(()));
+        }
+    };
+
+    // Like a history expansion, what runs is shown and remembered.
+    print!("{script}");
+    let entry = script.trim_end();
+    if !entry.is_empty() {
+        rl.add_history_entry(entry).context("add history entry")?;
+    }
+    Ok(execute_script(rl, state, &script))
+}
+
+/// Writes `script` to a new file in the temporary directory for `fc` to edit.
+/// The file must not exist yet, so one that someone else put there under
+/// the same name is never the one edited and run, and only its owner may
+/// read it.
+fn write_fc_file(script: &str) -> std::io::Result<PathBuf> {
+    let dir = std::env::temp_dir();
+    let mut options = File::options();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    for attempt in 0.. {
+        let path = dir.join(format!("fc-{}-{attempt}.sh", std::process::id()));
+        let mut file = match options.open(&path) {
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists && attempt < 100 => continue,
+            file => file?,
+        };
+        if let Err(e) = file.write_all(script.as_bytes()) {
+            let _ = std::fs::remove_file(&path);
+            return Err(e);
+        }
+        return Ok(path);
+    }
+    unreachable!("the attempts stop at 100")
+}
+
+/// The indices of the first and last of `entries` that `fc` takes, which
+/// are named by number, by a negative number counted back from the end or
+/// by how the entry starts. `None` if an entry is not found.
+fn history_range(
+    entries: &[&str],
+    first: Option<&str>,
+    last: Option<&str>,
+    list: bool,
+) -> Option<(usize, usize)> {
+    let newest = entries.len().checked_sub(1)?;
+    // Numbers past either end mean the entry at that end, and 0 the newest.
+    let index = |spec: &str| match spec.parse::<i64>() {
+        Ok(number) if number > 0 => Some((number as usize - 1).min(newest)),
+        Ok(number) => {
+            let back = (number.unsigned_abs() as usize).saturating_sub(1);
+            Some(newest.saturating_sub(back))
+        }
+        Err(_) => entries.iter().rposition(|entry| entry.starts_with(spec)),
+    };
+    let start = match first {
+        Some(first) => index(first)?,
+        None if list => newest.saturating_sub(15),
+        None => newest,
+    };
+    let end = match last {
+        Some(last) => index(last)?,
+        None if list => newest,
+        None => start,
+    };
+    Some((start, end))
+}
+
+fn command_type(com: &str) -> Option<Command> {
+    builtin_type(com).or_else(|| resolve_program(com).map(Command::Program))
+}
+
+/// The names of the builtins, as `builtin_type` knows them.
+const BUILTINS: &[&str] = &[
+    "exit", "echo", "cd", "pwd", "history", "type", "export", "alias", "unalias", "jobs", "fg",
+    "bg", "rehash", "source", ".", "set", "read", "unset", "break", "continue", "return", "exec",
+    "true", "false", ":", "test", "[", "printf", "kill", "wait", "command", "hash", "pushd",
+    "popd", "dirs", "umask", "local", "shift", "fc",
+];
+
+/// The builtin named `com`, if there is one.
+fn builtin_type(com: &str) -> Option<Command> {
+    match com {
+        "exit" => Some(Command::Exit),
+        "echo" => Some(Command::Echo),
+        "cd" => Some(Command::Cd),
+        "pwd" => Some(Command::Pwd),
+        "history" => Some(Command::History),
+        "type" => Some(Command::Type),
+        "export" => Some(Command::Export),
+        "alias" => Some(Command::Alias),
+        "unalias" => Some(Command::Unalias),
+        "jobs" => Some(Command::Jobs),
+        "fg" => Some(Command::Fg),
+        "bg" => Some(Command::Bg),
+        "rehash" => Some(Command::Rehash),
+        "source" | "." => Some(Command::Source),
+        "set" => Some(Command::Set),
+        "read" => Some(Command::Read),
+        "unset" => Some(Command::Unset),
+        "break" => Some(Command::Break),
+        "continue" => Some(Command::Continue),
+        "return" => Some(Command::Return),
+        "exec" => Some(Command::Exec),
+        "umask" => Some(Command::Umask),
+        "local" => Some(Command::Local),
+        "shift" => Some(Command::Shift),
+        "fc" => Some(Command::Fc),
+        "true" => Some(Command::True),
+        "false" => Some(Command::False),
+        ":" => Some(Command::Null),
+        "test" | "[" => Some(Command::Test),
+        "printf" => Some(Command::Printf),
+        "kill" => Some(Command::Kill),
+        "wait" => Some(Command::Wait),
+        "command" => Some(Command::Bypass),
+        "hash" => Some(Command::Hash),
+        "pushd" => Some(Command::Pushd),
+        "popd" => Some(Command::Popd),
+        "dirs" => Some(Command::Dirs),
+        _ => None,
+    }
+}
+
+/// Finds `com` on `PATH`, going through `RESOLVED` so a program is only
+/// searched for the first time it runs.
+fn resolve_program(com: &str) -> Option<PathBuf> {
+    // A name with a slash in it is a path, which `PATH` has no say in.
+    if com.contains('/') {
+        let program = PathBuf::from(com);
+        return is_executable(&program).then_some(program);
+    }
+
+    let mut resolved = RESOLVED.lock().unwrap_or_else(PoisonError::into_inner);
+    let path = std::env::var_os("PATH");
+    if resolved.path != path {
+        resolved.programs.clear();
+        resolved.path = path;
+    }
+
+    // A program removed since it was found is looked for again.
+    if let Some(program) = resolved.programs.get(com)
+        && is_executable(program)
+    {
+        return Some(program.clone());
+    }
+    resolved.programs.remove(com);
+
+    // `PROGRAMS` is not consulted, as it misses programs installed since
+    // the last scan.
+    let program = find_program(resolved.path.as_deref().unwrap_or_default(), com)?;
+    resolved.programs.insert(com.to_string(), program.clone());
+    Some(program)
+}
+
+/// Searches the directories of `paths` in order for an executable named
+/// exactly `com`.
+fn find_program(paths: &OsStr, com: &str) -> Option<PathBuf> {
+    find_programs(paths, com).next()
+}
+
+/// Skips any `command` in front of the name to run. Aliases were only ever
+/// expanded in command position, so what follows runs as the builtin or
+/// program it names. `command -v` is left to the builtin.
+fn skip_command_prefix(
+    mut com: String,
+    words: &mut std::iter::Peekable<std::vec::IntoIter<Word>>,
+) -> String {
+    while com == "command"
+        && let Some(word) = words.next_if(|word| !word.text().starts_with('-'))
+    {
+        com = argument(word);
+    }
+    com
+}
+
+/// Runs `command -v` or `command -V`, which say how each name would run:
+/// `-v` with just the path or name, for scripts, and `-V` in words.
+fn command_builtin(state: &ShellState, args: impl Iterator<Item = String>) -> i32 {
+    let mut args = args.peekable();
+    let verbose = match args.next_if(|arg| arg.starts_with('-')).as_deref() {
+        Some("-v") => false,
+        Some("-V") => true,
+        Some(option) => {
+            eprintln!("command: {option}: invalid option");
+            return 2;
+        }
+        // Just `command`, with nothing to run.
+        None => return 0,
+    };
+
+    let mut status = 0;
+    for name in args {
+        if let Some(value) = state.aliases.get(&name) {
+            if verbose {
+                println!("{name} is aliased to `{value}'");
+            } else {
+                println!("alias {name}={}", single_quote(value));
+            }
+            continue;
+        }
+        if state.functions.contains_key(&name) {
+            if verbose {
+                println!("{name} is a function");
+            } else {
+                println!("{name}");
+            }
+            continue;
+        }
+        match command_type(&name) {
+            Some(Command::Program(path)) if verbose => println!("{name} is {}", path.display()),
+            Some(Command::Program(path)) => println!("{}", path.display()),
+            Some(_) if verbose => println!("{name} is a shell builtin"),
+            Some(_) => println!("{name}"),
+            None => {
+                if verbose {
+                    eprintln!("command: {name}: not found");
+                }
+                status = 1;
+            }
+        }
+    }
+    status
+}
+
+/// The status of a command that names no program.
+const COMMAND_NOT_FOUND_STATUS: i32 = 127;
+
+/// The status of a command that names a file that cannot be run.
+const NOT_EXECUTABLE_STATUS: i32 = 126;
+
+/// The status for a program that failed to start with `e`.
+fn not_runnable_status(e: &std::io::Error) -> i32 {
+    match e.kind() {
+        std::io::ErrorKind::NotFound => COMMAND_NOT_FOUND_STATUS,
+        _ => NOT_EXECUTABLE_STATUS,
+    }
+}
+
+/// Says why `com` cannot be run when it names a file that is there but not
+/// executable, as opposed to nothing at all.
+fn unrunnable(paths: &OsStr, com: &str) -> Option<&'static str> {
+    let path = if com.contains('/') {
+        Some(PathBuf::from(com)).filter(|path| path.exists())
+    } else {
+        std::env::split_paths(paths)
+            .map(|dir| dir.join(com))
+            .find(|path| path.is_file())
+    }?;
+    if path.is_dir() {
+        Some("Is a directory")
+    } else {
+        Some("Permission denied")
+    }
+}
+
+/// Every executable named `com` in the directories of `paths`, in order.
+fn find_programs<'a>(paths: &'a OsStr, com: &'a str) -> impl Iterator<Item = PathBuf> + 'a {
+    std::env::split_paths(paths).filter_map(move |path| {
+        let program = path.join(com);
+        if path.is_dir() && is_executable(&program) {
+            Some(program)
+        } else if is_executable(&path) && path.file_name()? == com {
+            Some(path)
+        } else {
+            None
+        }
+    })
+}
+
+/// Describes how each name in `args` would be run, returning the text and
+/// the exit status, which is 1 if any name was not found. With `-a`, every
+/// alias, builtin and `PATH` match is listed instead of only the one that
+/// would run.
+fn type_builtin(state: &ShellState, args: impl IntoIterator<Item = String>) -> (String, i32) {
+    let mut output = String::new();
+    let mut status = 0;
+    let mut all = false;
+    for name in args {
+        if name == "-a" {
+            all = true;
+            continue;
+        }
+
+        let mut found = Vec::new();
+        if let Some(value) = state.aliases.get(&name) {
+            found.push(format!("aliased to `{value}'"));
+        }
+        if state.functions.contains_key(&name) {
+            found.push(String::from("a function"));
+        }
+        // An alias or function is what runs, unless all of them are asked
+        // for.
+        if all || found.is_empty() {
+            match command_type(&name) {
+                Some(Command::Program(path)) if !all => found.push(path.display().to_string()),
+                Some(Command::Program(_)) | None => {}
+                Some(_) => found.push(String::from("a shell builtin")),
+            }
+        }
+        if all {
+            let paths = std::env::var_os("PATH").unwrap_or_default();
+            found.extend(find_programs(&paths, &name).map(|path| path.display().to_string()));
+        }
+
+        if found.is_empty() {
+            output.push_str(&format!("{name}: not found\n"));
+            status = 1;
+        }
+        for what in found {
+            output.push_str(&format!("{name} is {what}\n"));
+        }
+    }
+    (output, status)
+}
+
+/// Replaces `$NAME` and `${NAME}` outside of single quotes with the value of
+/// the variable, and `$(...)` or backquotes with the output of the command,
+/// leaving the quoting in place for the lexer.
+///
+/// Substituted values are escaped so that the lexer reads them back literally,
+/// while unquoted values are still split on whitespace.
+///
+/// With `set -u`, expanding a variable that is not set is an error.
+///
+/// What `${NAME=word}` assigns only lasts for the expansion, the way it does
+/// in a subshell. `expand_vars_mut` keeps it.
+fn expand_vars(input: &str, state: &ShellState) -> anyhow::Result<String> {
+    expand_parameters(input, state, &mut Vec::new())
+}
+
+/// Expands like `expand_vars`, then assigns what `${NAME=word}` did.
+fn expand_vars_mut(input: &str, state: &mut ShellState) -> anyhow::Result<String> {
+    let mut assigned = Vec::new();
+    let output = expand_parameters(input, state, &mut assigned)?;
+    for (name, value) in assigned {
+        state.set_var(&name, value);
+    }
+    Ok(output)
+}
+
+/// Expands `input` the way `expand_vars` describes, adding the variables
+/// `${NAME=word}` assigns to `assigned`, which later expansions in the same
+/// input see.
+fn expand_parameters(
+    input: &str,
+    state: &ShellState,
+    assigned: &mut Vec<(String, String)>,
+) -> anyhow::Result<String> {
+    let mut output = String::with_capacity(input.len());
+    let mut quote = Quote::None;
+    let mut chars = input.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match (c, quote) {
+            ('\\', Quote::None | Quote::Double) => {
+                output.push(c);
+                if let Some(next) = chars.next() {
+                    output.push(next);
+                }
+                continue;
+            }
+            ('\'', Quote::None) => quote = Quote::Single,
+            ('\'', Quote::Single) => quote = Quote::None,
+            ('"', Quote::None) => quote = Quote::Double,
+            ('"', Quote::Double) => quote = Quote::None,
+            ('$', Quote::None | Quote::Double) if chars.peek() == Some(&'(') => {
+                chars.next();
+                let command = take_parenthesized(&mut chars);
+                push_escaped(&mut output, &command_substitution(state, &command), quote);
+                continue;
+            }
+            ('`', Quote::None | Quote::Double) => {
+                let mut command = String::new();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '`' => break,
+                        '\\' if matches!(chars.peek(), Some('`' | '\\' | '$')) => {
+                            command.extend(chars.next());
+                        }
+                        c => command.push(c),
+                    }
+                }
+                push_escaped(&mut output, &command_substitution(state, &command), quote);
+                continue;
+            }
+            ('$', Quote::None | Quote::Double) => {
+                let body = match chars.peek() {
+                    // Anything unknown between the braces is left as it is.
+                    Some('{') => {
+                        let mut braced = chars.clone();
+                        braced.next();
+                        match take_braced(&mut braced) {
+                            Some(body) if parameter::parse(&body).is_some() => {
+                                chars = braced;
+                                Some(body)
+                            }
+                            _ => None,
+                        }
+                    }
+                    Some(&c) if c.is_ascii_alphabetic() || c == '_' => {
+                        let mut name = String::new();
+                        while let Some(&c) = chars.peek()
+                            && (c.is_ascii_alphanumeric() || c == '_')
+                        {
+                            name.push(c);
+                            chars.next();
+                        }
+                        Some(name)
+                    }
+                    // Only one digit is read, so `$10` is `${1}0`.
+                    Some(&c) if c.is_ascii_digit() || matches!(c, '?' | '#' | '@' | '*') => {
+                        chars.next();
+                        Some(c.to_string())
+                    }
+                    _ => None,
+                };
+
+                match body.as_deref().and_then(parameter::parse) {
+                    // `"$@"` makes a word of every parameter.
+                    Some(Expansion {
+                        name: "@",
+                        operator: None,
+                    }) if quote == Quote::Double => {
+                        for (i, arg) in state.args.iter().skip(1).enumerate() {
+                            if i > 0 {
+                                output.push_str("\" \"");
+                            }
+                            push_escaped(&mut output, arg, quote);
+                        }
+                    }
+                    Some(expansion) => {
+                        let value = expand_parameter(state, &expansion, assigned)?;
+                        push_escaped(&mut output, &value, quote);
+                    }
+                    None => output.push(c),
+                }
+                continue;
+            }
+            _ => {}
+        }
+        output.push(c);
+    }
+
+    Ok(output)
+}
+
+/// The value of a parameter expansion, once its operator is applied.
+fn expand_parameter(
+    state: &ShellState,
+    expansion: &Expansion,
+    assigned: &mut Vec<(String, String)>,
+) -> anyhow::Result<String> {
+    let name = expansion.name;
+    let value = match assigned.iter().rfind(|(assigned, _)| assigned == name) {
+        Some((_, value)) => Some(value.clone()),
+        None => state.var(name),
+    };
+    if value.is_none()
+        && state.options.nounset
+        && !expansion
+            .operator
+            .as_ref()
+            .is_some_and(Operator::allows_unset)
+    {
+        anyhow::bail!("{name}: unbound variable");
+    }
+    let Some(operator) = &expansion.operator else {
+        return Ok(value.unwrap_or_default());
+    };
+
+    let unset = operator.unset(value.as_deref());
+    match *operator {
+        Operator::Default { word, .. } if unset => expand_operand(state, word, assigned),
+        Operator::Assign { word, .. } if unset => {
+            if !is_valid_name(name) {
+                anyhow::bail!("${name}: cannot assign in this way");
+            }
+            let value = expand_operand(state, word, assigned)?;
+            assigned.push((name.to_string(), value.clone()));
+            Ok(value)
+        }
+        Operator::Alternative { word, .. } if !unset => expand_operand(state, word, assigned),
+        Operator::Alternative { .. } => Ok(String::new()),
+        Operator::Error { word, .. } if unset => {
+            let message = match expand_operand(state, word, assigned)? {
+                message if message.is_empty() => String::from("parameter null or not set"),
+                message => message,
+            };
+            anyhow::bail!("{name}: {message}")
+        }
+        Operator::Length => Ok(value.unwrap_or_default().chars().count().to_string()),
+        Operator::Substring { offset, length } => {
+            parameter::substring(&value.unwrap_or_default(), offset, length)
+                .map_err(anyhow::Error::msg)
+        }
+        Operator::RemovePrefix { longest, pattern } => {
+            let pattern = expand_pattern(state, pattern, assigned)?;
+            let value = value.unwrap_or_default();
+            let matches = |text: &str| glob_match(&pattern, &text.chars().collect::<Vec<_>>());
+            Ok(parameter::remove_prefix(&value, longest, matches).to_string())
+        }
+        Operator::RemoveSuffix { longest, pattern } => {
+            let pattern = expand_pattern(state, pattern, assigned)?;
+            let value = value.unwrap_or_default();
+            let matches = |text: &str| glob_match(&pattern, &text.chars().collect::<Vec<_>>());
+            Ok(parameter::remove_suffix(&value, longest, matches).to_string())
+        }
+        _ => Ok(value.unwrap_or_default()),
+    }
+}
+
+/// The text the word of an operator like `:-` stands for: expanded and with
+/// its quotes removed, its words joined by single spaces.
+fn expand_operand(
+    state: &ShellState,
+    word: &str,
+    assigned: &mut Vec<(String, String)>,
+) -> anyhow::Result<String> {
+    let expanded = expand_parameters(word, state, assigned)?;
+    let words = lexer::split(&expanded)?.into_iter().map(argument);
+    Ok(words.collect::<Vec<_>>().join(" "))
+}
+
+/// The pattern the word of `#` or `%` stands for once expanded. What was
+/// quoted in it only matches itself.
+fn expand_pattern(
+    state: &ShellState,
+    word: &str,
+    assigned: &mut Vec<(String, String)>,
+) -> anyhow::Result<Vec<GlobToken>> {
+    let expanded = expand_parameters(word, state, assigned)?;
+    let mut pattern = Vec::new();
+    let mut quote = Quote::None;
+    let mut chars = expanded.chars();
+    while let Some(c) = chars.next() {
+        match (c, quote) {
+            ('\\', Quote::None) => pattern.extend(chars.next().map(|next| (next, true))),
+            ('\\', Quote::Double) => {
+                if let Some(next) = chars.next() {
+                    if !matches!(next, '\\' | '"' | '$' | '`') {
+                        pattern.push((c, true));
+                    }
+                    pattern.push((next, true));
+                }
+            }
+            ('\'', Quote::None) => quote = Quote::Single,
+            ('\'', Quote::Single) => quote = Quote::None,
+            ('"', Quote::None) => quote = Quote::Double,
+            ('"', Quote::Double) => quote = Quote::None,
+            (c, quote) => pattern.push((c, quote != Quote::None)),
+        }
+    }
+    Ok(compile_glob(&pattern))
+}
+
+/// Consumes the body of a `${...}` whose opening brace has already been read,
+/// up to the matching close, or returns `None` if it is never closed. Quoted
+/// and escaped braces don't count.
+fn take_braced(chars: &mut std::iter::Peekable<std::str::Chars>) -> Option<String> {
+    let mut body = String::new();
+    let mut quote = Quote::None;
+    let mut depth = 0;
+
+    while let Some(c) = chars.next() {
+        match (c, quote) {
+            ('\\', Quote::None | Quote::Double) => {
+                body.push(c);
+                body.extend(chars.next());
+                continue;
+            }
+            ('\'', Quote::None) => quote = Quote::Single,
+            ('\'', Quote::Single) => quote = Quote::None,
+            ('"', Quote::None) => quote = Quote::Double,
+            ('"', Quote::Double) => quote = Quote::None,
+            ('{', Quote::None) => depth += 1,
+            ('}', Quote::None) if depth == 0 => return Some(body),
+            ('}', Quote::None) => depth -= 1,
+            _ => {}
+        }
+        body.push(c);
+    }
+    None
+}
+
+/// Consumes the body of a `$(...)` whose opening parenthesis has already been
+/// read, up to the matching close. Quoted parentheses don't count.
+fn take_parenthesized(chars: &mut std::iter::Peekable<std::str::Chars>) -> String {
+    let mut body = String::new();
+    let mut quote = Quote::None;
+    let mut depth = 0;
+
+    while let Some(c) = chars.next() {
+        match (c, quote) {
+            ('\\', Quote::None | Quote::Double) => {
+                body.push(c);
+                body.extend(chars.next());
+                continue;
+            }
+            ('\'', Quote::None) => quote = Quote::Single,
+            ('\'', Quote::Single) => quote = Quote::None,
+            ('"', Quote::None) => quote = Quote::Double,
+            ('"', Quote::Double) => quote = Quote::None,
+            ('(', Quote::None) => depth += 1,
+            (')', Quote::None) if depth == 0 => break,
+            (')', Quote::None) => depth -= 1,
+            _ => {}
+        }
+        body.push(c);
+    }
+
+    body
+}
+
+/// Appends `value` so that the lexer yields it unchanged in the given quoting
+/// context.
+fn push_escaped(output: &mut String, value: &str, quote: Quote) {
+    for c in value.chars() {
+        let escape = match quote {
+            Quote::Double => matches!(c, '\\' | '"' | '$' | '`'),
+            _ => !c.is_whitespace() && !c.is_alphanumeric(),
+        };
+        if escape {
+            output.push('\\');
+        }
+        output.push(c);
+    }
+}
+
+/// Tags each character of raw input with its byte offset and whether it is
+/// live syntax, as opposed to quoted text, an escaped character or a quote.
+fn tag_unquoted(input: &str) -> Vec<(usize, char, bool)> {
+    let mut tagged = Vec::with_capacity(input.len());
+    let mut quote = Quote::None;
+    let mut chars = input.char_indices();
+
+    while let Some((i, c)) = chars.next() {
+        match (c, quote) {
+            ('\\', Quote::None | Quote::Double) => {
+                tagged.push((i, c, false));
+                if let Some((i, c)) = chars.next() {
+                    tagged.push((i, c, false));
+                }
+                continue;
+            }
+            ('\'', Quote::None) => quote = Quote::Single,
+            ('\'', Quote::Single) => quote = Quote::None,
+            ('"', Quote::None) => quote = Quote::Double,
+            ('"', Quote::Double) => quote = Quote::None,
+            (_, Quote::None) => {
+                tagged.push((i, c, true));
+                continue;
+            }
+            _ => {}
+        }
+        tagged.push((i, c, false));
+    }
+
+    tagged
+}
+
+/// Expands `{a,b}` alternatives and `{1..5}` sequences in every word, so
+/// `file{1,2}.txt` becomes `file1.txt file2.txt`.
+fn expand_braces(input: &str) -> String {
+    let mut output = String::with_capacity(input.len());
+    let mut word_start = None;
+
+    for (i, c, live) in tag_unquoted(input) {
+        if live && c.is_whitespace() {
+            if let Some(start) = word_start.take() {
+                output.push_str(&expand_brace_word(&input[start..i]).join(" "));
+            }
+            output.push(c);
+        } else if word_start.is_none() {
+            word_start = Some(i);
+        }
+    }
+    if let Some(start) = word_start {
+        output.push_str(&expand_brace_word(&input[start..]).join(" "));
+    }
+
+    output
+}
+
+/// Expands the first valid brace group in `word`, then recurses into each
+/// result to handle nested groups and the product of several groups.
+fn expand_brace_word(word: &str) -> Vec<String> {
+    let tagged = tag_unquoted(word);
+
+    for (pos, &(open, c, live)) in tagged.iter().enumerate() {
+        // `${VAR}` belongs to variable expansion.
+        if !live || c != '{' || (pos > 0 && tagged[pos - 1].1 == '$' && tagged[pos - 1].2) {
+            continue;
+        }
+
+        let mut depth = 0;
+        let mut commas = Vec::new();
+        let mut close = None;
+        for &(i, c, live) in &tagged[pos + 1..] {
+            match (c, live) {
+                ('{', true) => depth += 1,
+                ('}', true) if depth == 0 => {
+                    close = Some(i);
+                    break;
+                }
+                ('}', true) => depth -= 1,
+                (',', true) if depth == 0 => commas.push(i),
+                _ => {}
+            }
+        }
+        let Some(close) = close else {
+            continue;
+        };
+
+        let alternatives = if commas.is_empty() {
+            match brace_sequence(&word[open + 1..close]) {
+                Some(sequence) => sequence,
+                None => continue,
+            }
+        } else {
+            let mut start = open + 1;
+            let mut alternatives = Vec::new();
+            for comma in commas.into_iter().chain([close]) {
+                alternatives.push(word[start..comma].to_string());
+                start = comma + 1;
+            }
+            alternatives
+        };
+
+        let (prefix, suffix) = (&word[..open], &word[close + 1..]);
+        return alternatives
+            .iter()
+            .flat_map(|alternative| expand_brace_word(&format!("{prefix}{alternative}{suffix}")))
+            .collect();
+    }
+
+    vec![word.to_string()]
+}
+
+/// Expands the body of a `{start..end}` or `{start..end..step}` group over
+/// integers or single characters.
+fn brace_sequence(body: &str) -> Option<Vec<String>> {
+    let parts = body.split("..").collect::<Vec<_>>();
+    let (start, end, step) = match parts[..] {
+        [start, end] => (start, end, 1),
+        [start, end, step] => (start, end, step.parse::<i64>().ok()?.unsigned_abs().max(1)),
+        _ => return None,
+    };
+
+    if let (Ok(from), Ok(to)) = (start.parse::<i64>(), end.parse::<i64>()) {
+        // `{01..10}` pads every number to the widest endpoint.
+        let padded = [start, end].iter().any(|n| {
+            n.trim_start_matches('-').len() > 1 && n.trim_start_matches('-').starts_with('0')
+        });
+        let width = if padded {
+            start.len().max(end.len())
+        } else {
+            0
+        };
+        let numbers = step_between(from, to, step)?;
+        return Some(numbers.map(|n| format!("{n:0width$}")).collect());
+    }
+
+    let mut start = start.chars();
+    let mut end = end.chars();
+    match (start.next(), start.next(), end.next(), end.next()) {
+        (Some(from), None, Some(to), None)
+            if from.is_ascii_alphabetic() && to.is_ascii_alphabetic() =>
+        {
+            let chars = step_between(from as i64, to as i64, step)?;
+            Some(chars.map(|c| (c as u8 as char).to_string()).collect())
+        }
+        _ => None,
+    }
+}
+
+/// Counts from `from` to `to` inclusive, in whichever direction that is, or
+/// `None` if there are too many numbers to count.
+fn step_between(from: i64, to: i64, step: u64) -> Option<impl Iterator<Item = i64>> {
+    let count = (from.abs_diff(to) / step).checked_add(1)?;
+    // Every offset stays between the ends, so the wrapping is never used.
+    Some((0..count).map(move |i| {
+        if from <= to {
+            from.wrapping_add_unsigned(i * step)
+        } else {
+            from.wrapping_sub_unsigned(i * step)
+        }
+    }))
+}
+
+/// Replaces every unquoted word containing `*`, `?` or `[...]` with the
+/// sorted list of paths it matches. Words that match nothing are left as
+/// they are.
+fn expand_globs(input: &str) -> String {
+    let mut output = String::with_capacity(input.len());
+    let mut word = String::new();
+    // The word with its quotes removed, each character tagged with whether
+    // it was quoted and so can't be a wildcard.
+    let mut pattern = Vec::new();
+    let mut quote = Quote::None;
+    let mut chars = input.chars();
+
+    while let Some(c) = chars.next() {
+        match (c, quote) {
+            ('\\', Quote::None) => {
+                word.push(c);
+                if let Some(next) = chars.next() {
+                    word.push(next);
+                    pattern.push((next, true));
+                }
+                continue;
+            }
+            ('\\', Quote::Double) => {
+                word.push(c);
+                if let Some(next) = chars.next() {
+                    word.push(next);
+                    if !matches!(next, '\\' | '"' | '$' | '`') {
+                        pattern.push((c, true));
+                    }
+                    pattern.push((next, true));
+                }
+                continue;
+            }
+            ('\'', Quote::None) => quote = Quote::Single,
+            ('\'', Quote::Single) => quote = Quote::None,
+            ('"', Quote::None) => quote = Quote::Double,
+            ('"', Quote::Double) => quote = Quote::None,
+            (c, Quote::None) if c.is_whitespace() => {
+                push_glob_word(&mut output, &mut word, &mut pattern);
+                output.push(c);
+                continue;
+            }
+            (c, quote) => pattern.push((c, quote != Quote::None)),
+        }
+        word.push(c);
+    }
+    push_glob_word(&mut output, &mut word, &mut pattern);
+
+    output
+}
+
+/// Appends the expansion of the raw `word` to `output`, then resets the word.
+fn push_glob_word(output: &mut String, word: &mut String, pattern: &mut Vec<(char, bool)>) {
+    let matches = if pattern
+        .iter()
+        .any(|&(c, quoted)| !quoted && matches!(c, '*' | '?' | '['))
+    {
+        glob(pattern)
+    } else {
+        Vec::new()
+    };
+
+    if matches.is_empty() {
+        output.push_str(word);
+    } else {
+        let quoted = matches.iter().map(|m| single_quote(m)).collect::<Vec<_>>();
+        output.push_str(&quoted.join(" "));
+    }
+    word.clear();
+    pattern.clear();
+}
+
+/// Matches `pattern` against the filesystem one path component at a time.
+fn glob(pattern: &[(char, bool)]) -> Vec<String> {
+    let components = pattern.split(|&(c, _)| c == '/').collect::<Vec<_>>();
+    let mut paths = vec![String::new()];
+
+    for (i, component) in components.iter().enumerate() {
+        let is_last = i == components.len() - 1;
+        let text = component.iter().map(|&(c, _)| c).collect::<String>();
+        let tokens = compile_glob(component);
+
+        if tokens.iter().all(|t| matches!(t, GlobToken::Literal(_))) {
+            for path in &mut paths {
+                path.push_str(&text);
+            }
+        } else {
+            let mut matched = Vec::new();
+            for path in &paths {
+                let dir = if path.is_empty() { "." } else { path.as_str() };
+                let Ok(entries) = std::fs::read_dir(dir) else {
+                    continue;
+                };
+                for entry in entries.flatten() {
+                    let name = entry.file_name().to_string_lossy().into_owned();
+                    // Hidden files only match a pattern that starts with a dot.
+                    if name.starts_with('.') && !text.starts_with('.') {
+                        continue;
+                    }
+                    if glob_match(&tokens, &name.chars().collect::<Vec<_>>()) {
+                        matched.push(format!("{path}{name}"));
+                    }
+                }
+            }
+            paths = matched;
+        }
+
+        if !is_last {
+            for path in &mut paths {
+                path.push('/');
+            }
+        }
+    }
+
+    let mut paths = paths
+        .into_iter()
+        .filter(|path| std::fs::symlink_metadata(path).is_ok())
+        .collect::<Vec<_>>();
+    paths.sort_unstable();
+    paths
+}
+
+enum GlobToken {
+    Literal(char),
+    /// `?`
+    AnyChar,
+    /// `*`
+    AnyString,
+    /// `[...]`, holding inclusive character ranges.
+    Class {
+        negated: bool,
+        ranges: Vec<(char, char)>,
+    },
+}
+
+impl GlobToken {
+    fn matches(&self, c: char) -> bool {
+        match self {
+            GlobToken::Literal(l) => *l == c,
+            GlobToken::AnyChar | GlobToken::AnyString => true,
+            GlobToken::Class { negated, ranges } => {
+                ranges.iter().any(|&(lo, hi)| (lo..=hi).contains(&c)) != *negated
+            }
+        }
+    }
+}
+
+/// Compiles a pattern whose characters are tagged as quoted or not. Quoted
+/// characters are always literal, and a `[` without a closing `]` is too.
+fn compile_glob(pattern: &[(char, bool)]) -> Vec<GlobToken> {
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < pattern.len() {
+        let (c, quoted) = pattern[i];
+        i += 1;
+        if quoted {
+            tokens.push(GlobToken::Literal(c));
+            continue;
+        }
+        match c {
+            '*' => tokens.push(GlobToken::AnyString),
+            '?' => tokens.push(GlobToken::AnyChar),
+            '[' => {
+                let mut j = i;
+                let negated = matches!(pattern.get(j), Some(('!' | '^', false)));
+                if negated {
+                    j += 1;
+                }
+                // A `]` right after the opening bracket is part of the class.
+                let start = j;
+                if pattern.get(j).is_some_and(|&(c, _)| c == ']') {
+                    j += 1;
+                }
+                while j < pattern.len() && pattern[j] != (']', false) {
+                    j += 1;
+                }
+                if j == pattern.len() {
+                    tokens.push(GlobToken::Literal(c));
+                    continue;
+                }
+
+                let members = &pattern[start..j];
+                let mut ranges = Vec::new();
+                let mut k = 0;
+                while k < members.len() {
+                    let lo = members[k].0;
+                    if k + 2 < members.len() && members[k + 1] == ('-', false) {
+                        ranges.push((lo, members[k + 2].0));
+                        k += 3;
+                    } else {
+                        ranges.push((lo, lo));
+                        k += 1;
+                    }
+                }
+                tokens.push(GlobToken::Class { negated, ranges });
+                i = j + 1;
+            }
+            _ => tokens.push(GlobToken::Literal(c)),
+        }
+    }
+
+    tokens
+}
+
+fn glob_match(tokens: &[GlobToken], text: &[char]) -> bool {
+    match tokens.split_first() {
+        None => text.is_empty(),
+        Some((GlobToken::AnyString, rest)) => {
+            (0..=text.len()).any(|skip| glob_match(rest, &text[skip..]))
+        }
+        Some((token, rest)) => text
+            .split_first()
+            .is_some_and(|(&c, text)| token.matches(c) && glob_match(rest, text)),
+    }
+}
+
+/// Changes to `dir`, saving the current directory on the stack. Without a
+/// directory, swaps the current one with the one saved last.
+fn pushd(state: &mut ShellState, mut args: impl Iterator<Item = String>) -> anyhow::Result<i32> {
+    let current_dir = logical_dir()?;
+    match args.next() {
+        Some(dir) => {
+            if !change_dir("pushd", Path::new(&dir))? {
+                return Ok(1);
+            }
+        }
+        None => {
+            let Some(top) = state.dir_stack.first().cloned() else {
+                eprintln!("pushd: no other directory");
+                return Ok(1);
+            };
+            if !change_dir("pushd", &top)? {
+                return Ok(1);
+            }
+            state.dir_stack.remove(0);
+        }
+    }
+    state.dir_stack.insert(0, current_dir);
+    print_dir_stack(state)?;
+    Ok(0)
+}
+
+/// Changes back to the directory saved last, taking it off the stack.
+fn popd(state: &mut ShellState) -> anyhow::Result<i32> {
+    let Some(top) = state.dir_stack.first().cloned() else {
+        eprintln!("popd: directory stack empty");
+        return Ok(1);
+    };
+    if !change_dir("popd", &top)? {
+        return Ok(1);
+    }
+    state.dir_stack.remove(0);
+    print_dir_stack(state)?;
+    Ok(0)
+}
+
+/// Prints the current directory and the stack, or with `-c`, empties it.
+fn dirs(state: &mut ShellState, mut args: impl Iterator<Item = String>) -> anyhow::Result<i32> {
+    match args.next().as_deref() {
+        None => print_dir_stack(state)?,
+        Some("-c") => state.dir_stack.clear(),
+        Some(arg) => {
+            eprintln!("dirs: {arg}: invalid option");
+            return Ok(2);
+        }
+    }
+    Ok(0)
+}
+
+fn print_dir_stack(state: &ShellState) -> anyhow::Result<()> {
+    let current_dir = logical_dir()?;
+    let dirs = std::iter::once(&current_dir)
+        .chain(&state.dir_stack)
+        .map(|dir| tilde_abbreviate(dir))
+        .collect::<Vec<_>>();
+    println!("{}", dirs.join(" "));
+    Ok(())
+}
+
+/// Changes the working directory to `path`, recording the previous one in
+/// `OLDPWD` and the new one in `PWD`. Returns whether the directory was
+/// changed.
+fn change_dir(builtin: &str, path: &Path) -> anyhow::Result<bool> {
+    let current_dir = logical_dir()?;
+    // `..` goes back the way the user came, even out of a symlink, unless
+    // that way no longer leads anywhere.
+    let logical = normalize_path(&current_dir.join(path));
+    let new_dir = if std::env::set_current_dir(&logical).is_ok() {
+        logical
+    } else {
+        let physical = std::env::current_dir()
+            .context("get current dir")?
+            .join(path);
+        if let Err(e) = std::env::set_current_dir(&physical) {
+            // The error tells a missing directory from a file or a locked one.
+            eprintln!("{builtin}: {}: {}", path.display(), io_error_message(&e));
+            return Ok(false);
+        }
+        std::env::current_dir().context("get current dir")?
+    };
+    set_env_var("OLDPWD", current_dir);
+    set_env_var("PWD", new_dir);
+    Ok(true)
+}
+
+/// The working directory by the path the user took to it, symlinks and all.
+/// `PWD` holds it for as long as it still names the same directory.
+fn logical_dir() -> anyhow::Result<PathBuf> {
+    let physical = std::env::current_dir().context("get current dir")?;
+    if let Some(pwd) = std::env::var_os("PWD").map(PathBuf::from)
+        && pwd.is_absolute()
+        && std::fs::canonicalize(&pwd).ok() == std::fs::canonicalize(&physical).ok()
+    {
+        return Ok(pwd);
+    }
+    Ok(physical)
+}
+
+/// Resolves the `.` and `..` in `path` by name alone, without asking the
+/// file system where a symlink leads.
+fn normalize_path(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            std::path::Component::CurDir => {}
+            std::path::Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+/// What `pwd` prints: the logical working directory, or with `-P` the
+/// physical one, with every symlink resolved.
+fn pwd(args: &[String]) -> anyhow::Result<(String, i32)> {
+    let mut physical = false;
+    for arg in args {
+        match arg.as_str() {
+            "-L" => physical = false,
+            "-P" => physical = true,
+            _ => {
+                eprintln!("pwd: {arg}: invalid option");
+                return Ok((String::new(), 2));
+            }
+        }
+    }
+    let dir = if physical {
+        let dir = std::env::current_dir().context("get current dir")?;
+        std::fs::canonicalize(dir).context("resolve current dir")?
+    } else {
+        logical_dir()?
+    };
+    Ok((format!("{}\n", dir.display()), 0))
+}
+
+/// Runs `umask`, which prints the mask taken off the permissions of the
+/// files the shell and its programs create, in octal or with `-S` as the
+/// permissions left, or sets it to the octal mode given.
+fn umask(args: &[String]) -> (String, i32) {
+    let mut args = args.iter().peekable();
+    let symbolic = args.next_if(|arg| *arg == "-S").is_some();
+    if let Some(arg) = args.next_if(|arg| arg.starts_with('-') && *arg != "--") {
+        eprintln!("umask: {arg}: invalid option");
+        return (String::new(), 2);
+    }
+    args.next_if(|arg| *arg == "--");
+
+    let Some(mode) = args.next() else {
+        return match file_mask() {
+            Some(mask) if symbolic => (format!("{}\n", symbolic_mask(mask)), 0),
+            Some(mask) => (format!("{mask:04o}\n"), 0),
+            None => {
+                eprintln!("umask: not supported on this system");
+                (String::new(), 1)
+            }
+        };
+    };
+    match u32::from_str_radix(mode, 8) {
+        Ok(mask) if mask <= 0o777 => {
+            if set_file_mask(mask) {
+                (String::new(), 0)
+            } else {
+                eprintln!("umask: not supported on this system");
+                (String::new(), 1)
+            }
+        }
+        _ => {
+            eprintln!("umask: {mode}: octal number out of range");
+            (String::new(), 1)
+        }
+    }
+}
+
+/// How `umask -S` shows `mask`: the permissions it leaves for the user, the
+/// group and others.
+fn symbolic_mask(mask: u32) -> String {
+    let classes = [('u', 6), ('g', 3), ('o', 0)];
+    classes
+        .iter()
+        .map(|(class, shift)| {
+            let allowed = !mask >> shift;
+            let permissions = [(4, 'r'), (2, 'w'), (1, 'x')]
+                .iter()
+                .filter(|(bit, _)| allowed & bit != 0)
+                .map(|(_, c)| c)
+                .collect::<String>();
+            format!("{class}={permissions}")
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// The file-creation mask of the shell.
+#[cfg(unix)]
+fn file_mask() -> Option<u32> {
+    // SAFETY: `umask` has no memory safety requirements. It can only be read
+    // by setting it, so it is put back right away.
+    let mask = unsafe { libc::umask(0) };
+    unsafe { libc::umask(mask) };
+    Some(mask as u32)
+}
+
+#[cfg(not(unix))]
+fn file_mask() -> Option<u32> {
+    None
+}
+
+/// Sets the file-creation mask of the shell, returning whether it could.
+#[cfg(unix)]
+fn set_file_mask(mask: u32) -> bool {
+    // SAFETY: `umask` has no memory safety requirements. The mask is at
+    // most `0o777`, which fits any `mode_t`.
+    unsafe { libc::umask(mask as libc::mode_t) };
+    true
+}
+
+#[cfg(not(unix))]
+fn set_file_mask(_: u32) -> bool {
+    false
+}
+
+fn export(state: &mut ShellState, args: impl Iterator<Item = String>) -> i32 {
+    let args = args.collect::<Vec<_>>();
+
+    if args.is_empty() {
+        let mut vars = std::env::vars_os()
+            .map(|(name, value)| {
+                (
+                    name.to_string_lossy().into_owned(),
+                    value.to_string_lossy().into_owned(),
+                )
+            })
+            .collect::<Vec<_>>();
+        vars.sort_unstable();
+        for (name, value) in vars {
+            println!("{name}={value}");
+        }
+        return 0;
+    }
+
+    let mut status = 0;
+    for arg in args {
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value.to_string())),
+            None => (arg.as_str(), None),
+        };
+
+        if !is_valid_name(name) {
+            eprintln!("export: `{arg}': not a valid identifier");
+            status = 1;
+            continue;
+        }
+
+        // `export NAME` only promotes an existing shell variable, it never
+        // changes the value of one that is already exported.
+        let shell_value = state.vars.remove(name);
+        if let Some(value) = value.or(shell_value) {
+            set_env_var(name, value);
+        }
+    }
+    status
+}
+
+fn alias(state: &mut ShellState, args: impl Iterator<Item = String>) -> i32 {
+    let args = args.collect::<Vec<_>>();
+
+    if args.is_empty() {
+        for (name, value) in &state.aliases {
+            println!("{name}={}", single_quote(value));
+        }
+        return 0;
+    }
+
+    let mut status = 0;
+    for arg in args {
+        match arg.split_once('=') {
+            Some((name, value)) => {
+                state.aliases.insert(name.to_string(), value.to_string());
+            }
+            None => match state.aliases.get(&arg) {
+                Some(value) => println!("{arg}={}", single_quote(value)),
+                None => {
+                    eprintln!("alias: {arg}: not found");
+                    status = 1;
+                }
+            },
+        }
+    }
+    status
+}
+
+/// Removes each named variable, whether it is a shell variable or exported,
+/// or after `-f`, each named function. Names that are not set are skipped.
+fn unset(state: &mut ShellState, args: impl Iterator<Item = String>) -> i32 {
+    let mut status = 0;
+    let mut functions = false;
+    for arg in args {
+        match arg.as_str() {
+            "-v" => functions = false,
+            "-f" => functions = true,
+            _ if functions => {
+                state.functions.remove(&arg);
+            }
+            _ if !is_valid_name(&arg) => {
+                eprintln!("unset: `{arg}': not a valid identifier");
+                status = 1;
+            }
+            _ => state.unset_var(&arg),
+        }
+    }
+    status
+}
+
+/// Runs `local`, which declares variables that only the function being run
+/// and the ones it calls see, or lists them.
+fn local(state: &mut ShellState, args: impl Iterator<Item = String>) -> i32 {
+    let Some(scope) = state.scopes.last_mut() else {
+        eprintln!("local: can only be used in a function");
+        return 1;
+    };
+    let args = args.collect::<Vec<_>>();
+    if args.is_empty() {
+        let locals = scope.iter().collect::<BTreeMap<_, _>>();
+        for (name, value) in locals {
+            match value {
+                Some(value) => println!("{name}={}", quote_if_needed(value)),
+                None => println!("{name}"),
+            }
+        }
+        return 0;
+    }
+
+    let mut status = 0;
+    for arg in args {
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value.to_string())),
+            None => (arg.as_str(), None),
+        };
+        if !is_valid_name(name) {
+            eprintln!("local: `{arg}': not a valid identifier");
+            status = 1;
+            continue;
+        }
+        // Declaring a local again without a value keeps the one it has.
+        let local = scope.entry(name.to_string()).or_default();
+        if value.is_some() {
+            *local = value;
+        }
+    }
+    status
+}
+
+/// Runs `shift`, which drops the first positional parameters, one unless
+/// its argument says how many, and renumbers the others. Shifting more than
+/// there are drops them all and fails.
+fn shift(state: &mut ShellState, mut args: impl Iterator<Item = String>) -> i32 {
+    let count = match args.next() {
+        None => 1,
+        Some(arg) => match arg.parse::<i64>() {
+            Ok(count) if count >= 0 => count as usize,
+            Ok(_) => {
+                eprintln!("shift: {arg}: shift count out of range");
+                return 1;
+            }
+            Err(_) => {
+                eprintln!("shift: {arg}: numeric argument required");
+                return 1;
+            }
+        },
+    };
+    // `$0` stays where it is.
+    let positional = state.args.len().saturating_sub(1);
+    let dropped = count.min(positional);
+    if dropped > 0 {
+        state.args.drain(1..=dropped);
+    }
+    if count > positional { 1 } else { 0 }
+}
+
+fn unalias(state: &mut ShellState, args: impl Iterator<Item = String>) -> i32 {
+    let mut status = 0;
+    for arg in args {
+        if arg == "-a" {
+            state.aliases.clear();
+        } else if state.aliases.remove(&arg).is_none() {
+            eprintln!("unalias: {arg}: not found");
+            status = 1;
+        }
+    }
+    status
+}
+
+/// Writes what a builtin printed to its redirected stdout, or the shell's
+/// own. A failed write is reported on its stderr and makes the builtin fail
+/// instead of the whole command line.
+fn write_output(
+    builtin: &str,
+    output: &[u8],
+    stdout: Option<Redirect>,
+    stderr: Option<Redirect>,
+) -> i32 {
+    let written = match stdout {
+        Some(mut file) => file.write_all(output),
+        None => {
+            let mut stdout = std::io::stdout().lock();
+            stdout.write_all(output).and_then(|()| stdout.flush())
+        }
+    };
+    let Err(e) = written else {
+        return 0;
+    };
+    let message = format!("{builtin}: write error: {}", io_error_message(&e));
+    match stderr {
+        Some(mut file) => {
+            // There is nowhere left to report this one.
+            let _ = writeln!(file, "{message}");
+        }
+        None => eprintln!("{message}"),
+    }
+    1
+}
+
+/// Formats what `echo` prints for `args`. Leading flags made of `n`, `e` and
+/// `E` drop the newline or turn escapes like `\t` on and off; the first
+/// argument that is not one is printed along with the rest.
+fn echo(args: &[String]) -> Vec<u8> {
+    let mut newline = true;
+    let mut escapes = false;
+    let mut rest = args;
+    while let Some((flag, tail)) = rest.split_first() {
+        let Some(letters) = flag.strip_prefix('-') else {
+            break;
+        };
+        if letters.is_empty() || !letters.chars().all(|c| matches!(c, 'n' | 'e' | 'E')) {
+            break;
+        }
+        for c in letters.chars() {
+            match c {
+                'n' => newline = false,
+                'e' => escapes = true,
+                _ => escapes = false,
+            }
+        }
+        rest = tail;
+    }
+
+    let text = rest.join(" ");
+    let mut output = if escapes {
+        match echo_escapes(&text) {
+            (decoded, true) => decoded,
+            // `\c` stops the output there, newline and all.
+            (decoded, false) => return decoded,
+        }
+    } else {
+        text.into_bytes()
+    };
+    if newline {
+        output.push(b'\n');
+    }
+    output
+}
+
+/// Decodes the escapes of `echo -e`, and says whether the text went on to
+/// the end rather than stopping at a `\c`. Octal and hex escapes stand for
+/// a single byte.
+fn echo_escapes(text: &str) -> (Vec<u8>, bool) {
+    let mut output = Vec::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    let push = |output: &mut Vec<u8>, c: char| {
+        output.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+    };
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            push(&mut output, c);
+            continue;
+        }
+        let decoded = match chars.next() {
+            Some('a') => '\x07',
+            Some('b') => '\x08',
+            Some('c') => return (output, false),
+            Some('e' | 'E') => '\x1b',
+            Some('f') => '\x0c',
+            Some('n') => '\n',
+            Some('r') => '\r',
+            Some('t') => '\t',
+            Some('v') => '\x0b',
+            Some('\\') => '\\',
+            Some(c @ ('0' | 'x')) => {
+                let (radix, max) = if c == '0' { (8, 3) } else { (16, 2) };
+                let mut digits = String::new();
+                while digits.len() < max
+                    && let Some(digit) = chars.next_if(|digit| digit.is_digit(radix))
+                {
+                    digits.push(digit);
+                }
+                match u8::from_str_radix(&digits, radix) {
+                    Ok(byte) => {
+                        output.push(byte);
+                        continue;
+                    }
+                    Err(_) if c == '0' => '\0',
+                    Err(_) => {
+                        output.push(b'\\');
+                        'x'
+                    }
+                }
+            }
+            Some(c) => {
+                output.push(b'\\');
+                c
+            }
+            None => '\\',
+        };
+        push(&mut output, decoded);
+    }
+    (output, true)
+}
+
+/// Formats what `printf` prints, reporting any bad arguments on the way.
+/// The status is 1 if there were some, as the output still goes out.
+fn printf_builtin(args: &[String]) -> (Vec<u8>, i32) {
+    let args = match args.first() {
+        Some(arg) if arg == "--" => &args[1..],
+        _ => args,
+    };
+    let Some((format, args)) = args.split_first() else {
+        eprintln!("printf: usage: printf format [arguments]");
+        return (Vec::new(), 2);
+    };
+    let (output, errors) = printf::format(format, args);
+    for e in &errors {
+        eprintln!("printf: {e}");
+    }
+    (output, status_of(errors.is_empty()))
+}
+
+/// Runs `test`, or `[` which also wants a closing `]`: 0 when the expression
+/// holds, 1 when it does not and 2 when it is malformed.
+fn test(builtin: &str, mut args: Vec<String>) -> i32 {
+    if builtin == "[" && args.pop().is_none_or(|arg| arg != "]") {
+        eprintln!("[: missing `]'");
+        return 2;
+    }
+    match condition::evaluate(&args) {
+        Ok(result) => status_of(result),
+        Err(e) => {
+            eprintln!("{builtin}: {e}");
+            2
+        }
+    }
+}
+
+/// Changes the shell options given as `-e` or `-o errexit`, turning them off
+/// with `+` instead of `-`. Any other arguments, or all of them after `--`,
+/// replace the positional parameters. Without arguments, lists the variables.
+fn set(state: &mut ShellState, args: impl Iterator<Item = String>) -> i32 {
+    let mut args = args.peekable();
+    if args.peek().is_none() {
+        let mut vars = std::env::vars_os()
+            .map(|(name, value)| {
+                (
+                    name.to_string_lossy().into_owned(),
+                    value.to_string_lossy().into_owned(),
+                )
+            })
+            .collect::<BTreeMap<_, _>>();
+        vars.extend(state.vars.clone());
+        for (name, value) in state.scopes.iter().flatten() {
+            match value {
+                Some(value) => vars.insert(name.clone(), value.clone()),
+                None => vars.remove(name),
+            };
+        }
+        for (name, value) in vars {
+            println!("{name}={}", quote_if_needed(&value));
+        }
+        return 0;
+    }
+
+    let mut double_dash = false;
+    while let Some(arg) = args.next_if(|arg| arg.starts_with(['-', '+'])) {
+        if arg == "--" {
+            double_dash = true;
+            break;
+        }
+        let on = arg.starts_with('-');
+        let flags = &arg[1..];
+        if flags == "o" {
+            let Some(name) = args.next() else {
+                for (_, name) in SHELL_OPTIONS {
+                    let value = if state.options.option(name) {
+                        "on"
+                    } else {
+                        "off"
+                    };
+                    println!("{name:<15}\t{value}");
+                }
+                return 0;
+            };
+            match state.options.option_mut(&name) {
+                Some(option) => *option = on,
+                None => {
+                    eprintln!("set: {name}: invalid option name");
+                    return 2;
+                }
+            }
+            continue;
+        }
+        for flag in flags.chars() {
+            match ShellOptions::flag_name(flag).and_then(|name| state.options.option_mut(name)) {
+                Some(option) => *option = on,
+                None => {
+                    eprintln!("set: {}{flag}: invalid option", &arg[..1]);
+                    return 2;
+                }
+            }
+        }
+    }
+
+    let positional = args.collect::<Vec<_>>();
+    if double_dash || !positional.is_empty() {
+        state.args.truncate(1);
+        state.args.extend(positional);
+    }
+    0
+}
+
+/// Reads a line from stdin and splits it on whitespace into the variables
+/// named in `args`, the last one getting the rest of the line, or `REPLY`
+/// when none are named. `-p` prints a prompt first, and `-r` keeps
+/// backslashes instead of letting them escape the next character.
+fn read(state: &mut ShellState, args: Parser) -> anyhow::Result<i32> {
+    let mut names = args.args.into_iter().peekable();
+    let mut raw = false;
+    while let Some(option) = names.next_if(|arg| arg.starts_with('-')) {
+        match option.as_str() {
+            "-r" => raw = true,
+            "-p" => {
+                let Some(prompt) = names.next() else {
+                    eprintln!("read: -p: option requires an argument");
+                    return Ok(2);
+                };
+                eprint!("{prompt}");
+            }
+            "--" => break,
+            _ => {
+                eprintln!("read: {option}: invalid option");
+                return Ok(2);
+            }
+        }
+    }
+    let mut names = names.collect::<Vec<_>>();
+    if let Some(name) = names.iter().find(|name| !is_valid_name(name)) {
+        eprintln!("read: `{name}': not a valid identifier");
+        return Ok(1);
+    }
+    if names.is_empty() {
+        names.push(String::from("REPLY"));
+    }
+
+    // One byte at a time, so nothing past the line is taken from programs
+    // that read the same stdin later.
+    let mut input = match args.stdin {
+        Some(stdin) => stdin,
+        None => Redirect::File(dup_stream(std::io::stdin())?),
+    };
+    let mut line = Vec::new();
+    let mut byte = [0];
+    let mut eof = false;
+    loop {
+        match input.read(&mut byte) {
+            Ok(0) => {
+                eof = true;
+                break;
+            }
+            Ok(_) if byte[0] == b'\n' => break,
+            Ok(_) => line.push(byte[0]),
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => anyhow::bail!("read: {}", io_error_message(&e)),
+        }
+    }
+    let line = String::from_utf8_lossy(&line);
+
+    let mut fields = read_fields(&line, raw, names.len()).into_iter();
+    for name in names {
+        state.set_var(&name, fields.next().unwrap_or_default());
+    }
+    Ok(status_of(!eof))
+}
+
+/// Splits a line for `read` into at most `count` fields, the last of which
+/// keeps the rest of the line with its inner whitespace.
+fn read_fields(line: &str, raw: bool, count: usize) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    // Where the last field would end if only trailing whitespace followed.
+    let mut end = 0;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if !raw => {
+                field.extend(chars.next());
+                end = field.len();
+            }
+            c if c.is_whitespace() && fields.len() + 1 < count => {
+                if !field.is_empty() {
+                    fields.push(std::mem::take(&mut field));
+                    end = 0;
+                }
+            }
+            c if c.is_whitespace() => {
+                if !field.is_empty() {
+                    field.push(c);
+                }
+            }
+            c => {
+                field.push(c);
+                end = field.len();
+            }
+        }
+    }
+    field.truncate(end);
+    if !field.is_empty() {
+        fields.push(field);
+    }
+    fields
+}
+
+/// Prints the expanded command to stderr under `set -x`.
+fn trace_command(state: &ShellState, words: &[Word]) {
+    if state.options.xtrace {
+        let words = words
+            .iter()
+            .map(|word| quote_if_needed(&argument(word.clone())))
+            .collect::<Vec<_>>();
+        eprintln!("+ {}", words.join(" "));
+    }
+}
+
+/// Single quotes `value` unless it reads back as itself already.
+fn quote_if_needed(value: &str) -> String {
+    let plain = |c: char| c.is_alphanumeric() || "%+,-./:=@_".contains(c);
+    if !value.is_empty() && value.chars().all(plain) {
+        value.to_string()
+    } else {
+        single_quote(value)
+    }
+}
+
+/// Wraps `value` in single quotes, so it reads back as one literal word.
+fn single_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// Whether `name` can be used as a variable name: a letter or underscore
+/// followed by letters, digits or underscores.
+fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Sets an environment variable. A new `PATH` is scanned right away, so
+/// its programs complete and run without a `rehash`.
+fn set_env_var(name: &str, value: impl AsRef<std::ffi::OsStr>) {
+    // SAFETY: the shell never reads or writes the environment from more than
+    // one thread at a time.
+    unsafe { std::env::set_var(name, value) };
+    if name == "PATH" {
+        rehash();
+    }
+}
+
+fn remove_env_var(name: &str) {
+    // SAFETY: as for `set_env_var`.
+    unsafe { std::env::remove_var(name) };
+    if name == "PATH" {
+        rehash();
+    }
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    if let Ok(metadata) = path.metadata() {
+        let permissions = metadata.permissions();
+        metadata.is_file() && permissions.mode() & 0o111 != 0
+    } else {
+        false
+    }
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+/// Runs `commands` as a pipeline, returning its status: that of the last
+/// stage, or with `pipefail`, of the last one that failed. With `capture`,
+/// the last stage's output is collected and returned instead of being
+/// written to the terminal, and a single command is allowed. Without `rl`,
+/// as inside `$(...)`, `history` is unavailable. Programs suspended with
+/// Ctrl-Z are returned, along with the ones after them, to be kept as a job,
+/// and so is the PID of the first program, which names their process group.
+fn execute_pipeline(
+    mut rl: Option<&mut ShellEditor>,
+    state: &ShellState,
+    commands: &[&str],
+    capture: bool,
+) -> anyhow::Result<(String, i32, u32, Vec<Child>)> {
+    if commands.len() < 2 && !capture {
+        anyhow::bail!("Pipeline must have at least 2 commands");
+    }
+
+    // Every stage is checked before any of them runs.
+    let mut stages = Vec::with_capacity(commands.len());
+    for (i, command) in commands.iter().enumerate() {
+        let stage = Stage::new(state, command)?;
+        if let Command::History = stage.command {
+            if rl.is_none() {
+                anyhow::bail!("history cannot be used in pipelines");
+            }
+            let history_info = HistoryInfo::new(stage.parser.args.clone())?;
+            if i < commands.len() - 1 && history_info.read.is_some() {
+                anyhow::bail!("history: -r cannot be used before the end of a pipeline");
+            }
+        }
+        stages.push(stage);
+    }
+
+    // Then each one's stdout is connected to the next one's stdin, and the
+    // last one's to the shell when capturing. A stage's own redirections win
+    // over the pipes, leaving the next stage reading an empty one.
+    let mut input = None;
+    let count = stages.len();
+    for (i, stage) in stages.iter_mut().enumerate() {
+        stage.stdin = match (stage.parser.stdin.take(), input.take()) {
+            (Some(stdin), _) => Source::Redirect(stdin),
+            (None, Some(pipe)) => Source::Pipe(pipe),
+            (None, None) => Source::Inherit,
+        };
+        let (reader, writer) = if i < count - 1 || capture {
+            let (reader, writer) = std::io::pipe().context("create pipe")?;
+            (Some(reader), Some(writer))
+        } else {
+            (None, None)
+        };
+        input = reader;
+        stage.stdout = match (stage.parser.stdout.take(), writer) {
+            (Some(stdout), _) => Sink::Redirect(stdout),
+            (None, Some(pipe)) => Sink::Pipe(pipe),
+            (None, None) => Sink::Inherit,
+        };
+    }
+
+    // With job control, the programs share a group of their own that the
+    // first one starts and puts in the foreground, so Ctrl-C and Ctrl-Z
+    // reach all of them. Captured output is for the shell, which keeps it.
+    let job_control = state.job_control && !capture;
+    #[cfg(unix)]
+    let _terminal = ReclaimTerminal(job_control);
+    let mut leader = None;
+    let mut started = Vec::with_capacity(count);
+    for stage in stages {
+        let group = match leader {
+            _ if !job_control => ProcessGroup::Shell,
+            None => ProcessGroup::Foreground,
+            Some(pgid) => ProcessGroup::Join(pgid),
+        };
+        let stage = stage.start(rl.as_deref_mut(), state, group)?;
+        if let Started::Child(child) = &stage {
+            leader.get_or_insert(child.id());
+        }
+        started.push(stage);
+    }
+
+    // Drain the captured output before waiting, or a child blocked on a full
+    // pipe would never exit.
+    let mut captured = Vec::new();
+    if let Some(mut output) = input {
+        output
+            .read_to_end(&mut captured)
+            .context("read command output")?;
+    }
+    let captured = String::from_utf8_lossy(&captured).into_owned();
+
+    // Every stage is waited for, in order, before its status counts. Once
+    // one stops, the rest are left to the job.
+    #[cfg(unix)]
+    let _interrupts = IgnoreInterrupts::new();
+    let mut statuses = Vec::with_capacity(started.len());
+    let mut stopped = Vec::new();
+    for stage in started {
+        match stage {
+            Started::Builtin(status) => statuses.push(status),
+            Started::Child(child) if !stopped.is_empty() => stopped.push(child),
+            Started::Child(mut child) => match wait_child(&mut child, true)? {
+                Wait::Exited(status) => statuses.push(exit_code(status)),
+                Wait::Stopped => stopped.push(child),
+                Wait::Running => unreachable!("blocking wait returned early"),
+            },
+        }
+    }
+
+    let status = pipeline_status(&statuses, state.options.pipefail);
+    Ok((captured, status, leader.unwrap_or(0), stopped))
+}
+
+/// A command of a pipeline, set up to run.
+struct Stage {
+    com: String,
+    command: Command,
+    parser: Parser,
+    env: Vec<(String, String)>,
+    stdin: Source,
+    stdout: Sink,
+}
+
+/// Where a stage of a pipeline reads from.
+enum Source {
+    /// The shell's own stdin.
+    Inherit,
+    Redirect(Redirect),
+    /// The previous stage's output.
+    Pipe(std::io::PipeReader),
+}
+
+/// Where a stage of a pipeline writes to.
+enum Sink {
+    /// The shell's own stdout.
+    Inherit,
+    Redirect(Redirect),
+    /// The next stage's input, or the shell's when capturing.
+    Pipe(std::io::PipeWriter),
+}
+
+impl From<Source> for Stdio {
+    fn from(source: Source) -> Self {
+        match source {
+            Source::Inherit => Stdio::inherit(),
+            Source::Redirect(redirect) => redirect.into(),
+            Source::Pipe(pipe) => pipe.into(),
+        }
+    }
+}
+
+impl From<Sink> for Stdio {
+    fn from(sink: Sink) -> Self {
+        match sink {
+            Sink::Inherit => Stdio::inherit(),
+            Sink::Redirect(redirect) => redirect.into(),
+            Sink::Pipe(pipe) => pipe.into(),
+        }
+    }
+}
+
+impl Sink {
+    /// Writes what a builtin printed, which it has put together whole first.
+    /// Into a pipe, another thread writes it, so the shell does not block on
+    /// a full pipe before it has started the stages that read it.
+    fn write(self, output: Vec<u8>) -> anyhow::Result<()> {
+        match self {
+            Sink::Inherit => {
+                let mut stdout = std::io::stdout().lock();
+                stdout.write_all(&output).context("write stdout")?;
+                stdout.flush().context("flush stdout")?;
+            }
+            Sink::Redirect(mut redirect) => redirect.write_all(&output)?,
+            Sink::Pipe(mut pipe) => {
+                std::thread::spawn(move || {
+                    // The next stage is free to exit without reading all of it.
+                    let _ = pipe.write_all(&output);
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Stage {
+    /// Expands and parses `command`, failing if it names nothing that can run
+    /// in a pipeline.
+    fn new(state: &ShellState, command: &str) -> anyhow::Result<Self> {
+        let command = expand_globs(&expand_vars(&expand_braces(command), state)?);
+        let mut words = lexer::split_command(&command)?;
+        trace_command(state, &words);
+        let env = take_assignments(&mut words);
+        let mut words = words.into_iter().peekable();
+        let com = argument(words.next().context("parsing command")?);
+        let com = skip_command_prefix(com, &mut words);
+        let parser = Parser::new(words, &state.options)?;
+        if state.functions.contains_key(&com) {
+            anyhow::bail!("{com}: functions cannot be used in pipelines");
+        }
+
+        let command = match command_type(&com) {
+            Some(
+                command @ (Command::Echo
+                | Command::Type
+                | Command::Pwd
+                | Command::True
+                | Command::False
+                | Command::Null
+                | Command::Test
+                | Command::Printf
+                | Command::History
+                | Command::Program(_)),
+            ) => command,
+            Some(
+                Command::Cd
+                | Command::Exit
+                | Command::Export
+                | Command::Alias
+                | Command::Unalias
+                | Command::Jobs
+                | Command::Fg
+                | Command::Bg
+                | Command::Kill
+                | Command::Wait
+                | Command::Bypass
+                | Command::Hash
+                | Command::Pushd
+                | Command::Popd
+                | Command::Dirs
+                | Command::Rehash
+                | Command::Source
+                | Command::Set
+                | Command::Read
+                | Command::Unset
+                | Command::Break
+                | Command::Continue
+                | Command::Return
+                | Command::Exec
+                | Command::Umask
+                | Command::Local
+                | Command::Shift
+                | Command::Fc,
+            ) => anyhow::bail!("{} cannot be used in pipelines", com),
+            None => {
+                let paths = std::env::var_os("PATH").unwrap_or_default();
+                match unrunnable(&paths, &com) {
+                    Some(reason) => anyhow::bail!("{com}: {reason}"),
+                    None => anyhow::bail!("{}: command not found", com),
+                }
+            }
+        };
+
+        Ok(Self {
+            com,
+            command,
+            parser,
+            env,
+            stdin: Source::Inherit,
+            stdout: Sink::Inherit,
+        })
+    }
+
+    /// Runs a builtin to the end, or starts a program in `group`.
+    fn start(
+        self,
+        rl: Option<&mut ShellEditor>,
+        state: &ShellState,
+        group: ProcessGroup,
+    ) -> anyhow::Result<Started> {
+        let (output, status) = match self.command {
+            Command::Program(path) => {
+                let mut process = std::process::Command::new(&path);
+                #[cfg(unix)]
+                process.arg0(&self.com);
+                #[cfg(unix)]
+                prepare_child(&mut process, group);
+                #[cfg(not(unix))]
+                let _ = group;
+                process
+                    .args(&self.parser.args)
+                    .envs(self.env)
+                    .stdin(self.stdin)
+                    .stdout(self.stdout);
+                if let Some(stderr) = self.parser.stderr {
+                    process.stderr(stderr);
+                }
+                let child = process
+                    .spawn()
+                    .with_context(|| format!("spawn process {}", self.com))?;
+                return Ok(Started::Child(child));
+            }
+            Command::History => {
+                let rl = rl.context("history cannot be used in pipelines")?;
+                (
+                    history(rl, HistoryInfo::new(self.parser.args)?)?.into_bytes(),
+                    0,
+                )
+            }
+            _ => execute_builtin_in_pipeline(state, &self.com, &self.parser.args)?,
+        };
+        self.stdout.write(output)?;
+        Ok(Started::Builtin(status))
+    }
+}
+
+/// A stage of a pipeline that has been started, as far as its status goes.
+enum Started {
+    /// A builtin, which has already run.
+    Builtin(i32),
+    Child(Child),
+}
+
+/// The status of a pipeline whose stages ended with `statuses`.
+fn pipeline_status(statuses: &[i32], pipefail: bool) -> i32 {
+    let mut statuses = statuses.iter().rev().copied();
+    if pipefail {
+        statuses.find(|&status| status != 0).unwrap_or(0)
+    } else {
+        statuses.next().unwrap_or(0)
+    }
+}
+
+/// Runs `command` for `$(...)`, returning its output without trailing
+/// newlines. Failures are reported and substitute nothing.
+fn command_substitution(state: &ShellState, command: &str) -> String {
+    if command.trim().is_empty() {
+        return String::new();
+    }
+
+    let commands = split_pipeline(command)
+        .into_iter()
+        .map(str::trim)
+        .collect::<Vec<_>>();
+    match execute_pipeline(None, state, &commands, true) {
+        Ok((mut output, ..)) => {
+            output.truncate(output.trim_end_matches('\n').len());
+            output
+        }
+        Err(e) => {
+            eprintln!("{e:#}");
+            String::new()
+        }
+    }
+}
+
+/// Runs a builtin inside a pipeline, returning what it printed and its
+/// status.
+fn execute_builtin_in_pipeline(
+    state: &ShellState,
+    com: &str,
+    args: &[String],
+) -> anyhow::Result<(Vec<u8>, i32)> {
+    let (output, status) = match com {
+        "echo" => return Ok((echo(args), 0)),
+        "type" => type_builtin(state, args.iter().cloned()),
+        "true" | ":" => (String::new(), 0),
+        "false" => (String::new(), 1),
+        "test" | "[" => (String::new(), test(com, args.to_vec())),
+        "printf" => return Ok(printf_builtin(args)),
+        "pwd" => pwd(args)?,
+        _ => anyhow::bail!("Unknown builtin: {}", com),
+    };
+    Ok((output.into_bytes(), status))
+}
+
+#[cfg(not(unix))]
+fn spawn_command(
+    path: &Path,
+    _: &str,
+    args: Parser,
+    env: &[(String, String)],
+    _: ProcessGroup,
+) -> anyhow::Result<Child> {
+    let mut settings = std::process::Command::new(path);
+    settings.args(&args.args).envs(env.iter().cloned());
+
+    if let Some(stdin) = args.stdin {
+        settings.stdin(stdin);
+    }
+
+    if let Some(stdout) = args.stdout {
+        settings.stdout(stdout);
+    }
+
+    if let Some(stderr) = args.stderr {
+        settings.stderr(stderr);
+    }
+
+    settings.spawn().context("spawn child process")
+}
+
+#[cfg(unix)]
+fn spawn_command(
+    path: &Path,
+    com: &str,
+    args: Parser,
+    env: &[(String, String)],
+    group: ProcessGroup,
+) -> anyhow::Result<Child> {
+    let mut settings = std::process::Command::new(path);
+    settings.arg0(com);
+    prepare_child(&mut settings, group);
+    settings.args(&args.args).envs(env.iter().cloned());
+
+    if let Some(stdin) = args.stdin {
+        settings.stdin(stdin);
+    }
+
+    if let Some(stdout) = args.stdout {
+        settings.stdout(stdout);
+    }
+
+    if let Some(stderr) = args.stderr {
+        settings.stderr(stderr);
+    }
+
+    settings.spawn().context("spawn child process")
+}
+
+/// Runs `exec`: the program its arguments name replaces the shell, which
+/// only keeps running if the program cannot start. With no program, its
+/// redirections apply to the shell itself from then on.
+fn exec(
+    state: &mut ShellState,
+    args: Parser,
+    env: &[(String, String)],
+) -> anyhow::Result<ControlFlow<Jump>> {
+    let Some(com) = args.args.first().cloned() else {
+        state.last_status = match redirect_shell(args) {
+            Ok(()) => 0,
+            Err(e) => {
+                eprintln!("exec: {}", io_error_message(&e));
+                1
+            }
+        };
+        return Ok(ControlFlow::Continue(()));
+    };
+    // A path is tried as it is, so the reason it cannot run is reported.
+    let path = if com.contains('/') {
+        Some(PathBuf::from(&com))
+    } else {
+        resolve_program(&com)
+    };
+    let Some(path) = path else {
+        eprintln!("exec: {com}: not found");
+        state.last_status = COMMAND_NOT_FOUND_STATUS;
+        return Ok(ControlFlow::Continue(()));
+    };
+
+    // Nothing printed so far may be lost with the shell.
+    std::io::stdout().flush().context("flush stdout")?;
+    match replace_shell(&path, args, env) {
+        Ok(code) => Ok(ControlFlow::Break(Jump::Exit(code))),
+        Err(e) => {
+            eprintln!("exec: {com}: {}", io_error_message(&e));
+            state.last_status = not_runnable_status(&e);
+            Ok(ControlFlow::Continue(()))
+        }
+    }
+}
+
+/// Runs the program at `path` in place of the shell, which never returns
+/// unless it fails to start.
+#[cfg(unix)]
+fn replace_shell(path: &Path, args: Parser, env: &[(String, String)]) -> std::io::Result<i32> {
+    let mut settings = std::process::Command::new(path);
+    settings.arg0(&args.args[0]);
+    prepare_child(&mut settings, ProcessGroup::Shell);
+    settings.args(&args.args[1..]).envs(env.iter().cloned());
+
+    if let Some(stdin) = args.stdin {
+        settings.stdin(stdin);
+    }
+
+    if let Some(stdout) = args.stdout {
+        settings.stdout(stdout);
+    }
+
+    if let Some(stderr) = args.stderr {
+        settings.stderr(stderr);
+    }
+
+    Err(settings.exec())
+}
+
+/// Without a way to replace the process, the program runs to the end and
+/// the shell exits with its status.
+#[cfg(not(unix))]
+fn replace_shell(path: &Path, args: Parser, env: &[(String, String)]) -> std::io::Result<i32> {
+    let com = args.args[0].clone();
+    let args = Parser {
+        args: args.args[1..].to_vec(),
+        ..args
+    };
+    let mut child = spawn_command(path, &com, args, env, ProcessGroup::Shell)
+        .map_err(|e| std::io::Error::other(format!("{e:#}")))?;
+    Ok(exit_code(child.wait()?))
+}
+
+/// Points the shell's own streams where the redirections of `args` say, for
+/// every command that runs after it.
+#[cfg(unix)]
+fn redirect_shell(args: Parser) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let streams = [
+        (args.stdin, libc::STDIN_FILENO),
+        (args.stdout, libc::STDOUT_FILENO),
+        (args.stderr, libc::STDERR_FILENO),
+    ];
+    for (redirect, fd) in streams {
+        let file = match redirect {
+            None => continue,
+            Some(Redirect::File(file)) => file,
+            Some(Redirect::Null) => OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open("/dev/null")?,
+        };
+        // What was printed so far goes where the stream pointed before.
+        std::io::stdout().flush()?;
+        // SAFETY: both are open file descriptors.
+        if unsafe { libc::dup2(file.as_raw_fd(), fd) } == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
 }
 
-fn execute_builtin_in_pipeline(
-    com: &str,
-    mut args: Shlex,
-    needs_output: bool,
-) -> anyhow::Result<PipeOutput> {
-    let mut output = String::new();
+#[cfg(not(unix))]
+fn redirect_shell(args: Parser) -> std::io::Result<()> {
+    if args.stdin.is_none() && args.stdout.is_none() && args.stderr.is_none() {
+        return Ok(());
+    }
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "redirecting the shell is not supported on this system",
+    ))
+}
 
-    match com {
-        "echo" => {
-            let arg = args.collect::<Vec<_>>().join(" ");
-            if needs_output {
-                output = format!("{}\n", arg);
-            } else {
-                println!("{}", arg);
+/// Prints the jobs that have finished or been stopped since the last report,
+/// forgetting the finished ones, along with the ones still going when
+/// `running` is set.
+fn report_jobs(state: &mut ShellState, running: bool) {
+    let count = state.jobs.len();
+    let mut index = 0;
+    state.jobs.retain_mut(|job| {
+        // `+` marks the most recent job and `-` the one before it.
+        let marker = match count - index {
+            1 => '+',
+            2 => '-',
+            _ => ' ',
+        };
+        index += 1;
+
+        match wait_job(job, false) {
+            Ok(Wait::Exited(status)) => {
+                let status = match exit_code(status) {
+                    0 => "Done".to_string(),
+                    code => format!("Exit {code}"),
+                };
+                println!("[{}]{marker}  {status:<24}{}", job.id, job.command);
+                false
+            }
+            Ok(Wait::Stopped) if !job.stopped => {
+                job.stopped = true;
+                println!("[{}]{marker}  {:<24}{}", job.id, "Stopped", job.command);
+                true
+            }
+            Ok(Wait::Stopped | Wait::Running) | Err(_) => {
+                if running && job.stopped {
+                    println!("[{}]{marker}  {:<24}{}", job.id, "Stopped", job.command);
+                } else if running {
+                    println!("[{}]{marker}  {:<24}{} &", job.id, "Running", job.command);
+                }
+                true
             }
         }
-        "type" => {
-            if let Some(name) = args.next() {
-                let command = command_type(&name);
-                let result = match command {
-                    Some(Command::Program(ref path)) => format!("{} is {}", name, path.display()),
-                    Some(_) => format!("{} is a shell builtin", name),
-                    None => format!("{}: not found", name),
-                };
-                if needs_output {
-                    output = format!("{}\n", result);
-                } else {
-                    println!("{}", result);
+    });
+}
+
+/// Finds the job named by a `%N`, `N`, `%%`, `%+` or `%-` spec, defaulting
+/// to the most recent one.
+fn find_job(state: &ShellState, builtin: &str, spec: Option<&str>) -> Option<usize> {
+    let index = match spec.map(|spec| spec.strip_prefix('%').unwrap_or(spec)) {
+        None | Some("%" | "+") => state.jobs.len().checked_sub(1),
+        Some("-") => state.jobs.len().checked_sub(2),
+        Some(id) => id
+            .parse()
+            .ok()
+            .and_then(|id: usize| state.jobs.iter().position(|job| job.id == id)),
+    };
+    if index.is_none() {
+        eprintln!("{builtin}: {}: no such job", spec.unwrap_or("current"));
+    }
+    index
+}
+
+/// Brings a job back to the foreground, continuing it if it was stopped, and
+/// waits for it to finish.
+fn fg(state: &mut ShellState, mut args: impl Iterator<Item = String>) -> anyhow::Result<i32> {
+    let Some(index) = find_job(state, "fg", args.next().as_deref()) else {
+        return Ok(1);
+    };
+    let mut job = state.jobs.remove(index);
+    println!("{}", job.command);
+
+    #[cfg(unix)]
+    if state.job_control {
+        set_foreground(job.pgid as libc::pid_t);
+    }
+    if job.stopped {
+        continue_job(&job, state.job_control)?;
+        job.stopped = false;
+    }
+
+    wait_foreground(state, job)
+}
+
+/// Continues a stopped job in the background.
+#[cfg(unix)]
+fn bg(state: &mut ShellState, mut args: impl Iterator<Item = String>) -> i32 {
+    let Some(index) = find_job(state, "bg", args.next().as_deref()) else {
+        return 1;
+    };
+    let marker = if index + 1 == state.jobs.len() {
+        '+'
+    } else {
+        '-'
+    };
+    let job_control = state.job_control;
+    let job = &mut state.jobs[index];
+    if !job.stopped {
+        eprintln!("bg: job {} already in background", job.id);
+        return 0;
+    }
+
+    if let Err(e) = continue_job(job, job_control) {
+        eprintln!("bg: {e:#}");
+        return 1;
+    }
+    job.stopped = false;
+    println!("[{}]{marker} {} &", job.id, job.command);
+    0
+}
+
+#[cfg(not(unix))]
+fn bg(_: &mut ShellState, _: impl Iterator<Item = String>) -> i32 {
+    eprintln!("bg: job control is not supported on this platform");
+    1
+}
+
+/// Waits for the jobs given by job spec or PID, or for all of them, and
+/// returns the status of the last. Ctrl-C stops waiting, leaving the jobs
+/// running.
+fn wait(state: &mut ShellState, args: impl Iterator<Item = String>) -> anyhow::Result<i32> {
+    let args = args.collect::<Vec<_>>();
+    let mut status = 0;
+    let mut pids = Vec::new();
+    if args.is_empty() {
+        pids.extend(state.jobs.iter().map(|job| job.pid));
+    }
+    for arg in args {
+        if arg.starts_with('%') {
+            match find_job(state, "wait", Some(&arg)) {
+                Some(index) => pids.push(state.jobs[index].pid),
+                None => status = 127,
+            }
+            continue;
+        }
+        match arg.parse() {
+            Ok(pid) if state.jobs.iter().any(|job| job.pid == pid) => pids.push(pid),
+            Ok(pid) => {
+                eprintln!("wait: pid {pid} is not a child of this shell");
+                status = 127;
+            }
+            Err(_) => {
+                eprintln!("wait: `{arg}': not a pid or valid job spec");
+                status = 2;
+            }
+        }
+    }
+
+    for pid in pids {
+        let Some(index) = state.jobs.iter().position(|job| job.pid == pid) else {
+            continue;
+        };
+        // A stopped job cannot finish until something continues it.
+        if state.jobs[index].stopped {
+            status = STOPPED_STATUS;
+            continue;
+        }
+        // Polling keeps Ctrl-C working, which a blocking wait would retry
+        // straight through.
+        status = loop {
+            if INTERRUPTED.load(Ordering::Relaxed) {
+                return Ok(INTERRUPTED_STATUS);
+            }
+            let job = &mut state.jobs[index];
+            match wait_job(job, false)? {
+                Wait::Exited(exit) => {
+                    state.jobs.remove(index);
+                    break exit_code(exit);
                 }
+                Wait::Stopped => {
+                    job.stopped = true;
+                    println!("[{}]+  {:<24}{}", job.id, "Stopped", job.command);
+                    break STOPPED_STATUS;
+                }
+                Wait::Running => std::thread::sleep(std::time::Duration::from_millis(10)),
             }
+        };
+    }
+    Ok(status)
+}
+
+/// The signals `kill` knows by name, in the order `kill -l` lists them.
+#[cfg(unix)]
+const SIGNALS: &[(&str, libc::c_int)] = &[
+    ("HUP", libc::SIGHUP),
+    ("INT", libc::SIGINT),
+    ("QUIT", libc::SIGQUIT),
+    ("ILL", libc::SIGILL),
+    ("TRAP", libc::SIGTRAP),
+    ("ABRT", libc::SIGABRT),
+    ("BUS", libc::SIGBUS),
+    ("FPE", libc::SIGFPE),
+    ("KILL", libc::SIGKILL),
+    ("USR1", libc::SIGUSR1),
+    ("SEGV", libc::SIGSEGV),
+    ("USR2", libc::SIGUSR2),
+    ("PIPE", libc::SIGPIPE),
+    ("ALRM", libc::SIGALRM),
+    ("TERM", libc::SIGTERM),
+    ("CHLD", libc::SIGCHLD),
+    ("CONT", libc::SIGCONT),
+    ("STOP", libc::SIGSTOP),
+    ("TSTP", libc::SIGTSTP),
+    ("TTIN", libc::SIGTTIN),
+    ("TTOU", libc::SIGTTOU),
+    ("URG", libc::SIGURG),
+    ("XCPU", libc::SIGXCPU),
+    ("XFSZ", libc::SIGXFSZ),
+    ("VTALRM", libc::SIGVTALRM),
+    ("PROF", libc::SIGPROF),
+    ("WINCH", libc::SIGWINCH),
+    ("SYS", libc::SIGSYS),
+];
+
+/// Looks a signal up by number, or by name with or without `SIG`.
+#[cfg(unix)]
+fn parse_signal(spec: &str) -> Option<libc::c_int> {
+    if let Ok(number) = spec.parse() {
+        return (number == 0 || SIGNALS.iter().any(|&(_, signal)| signal == number))
+            .then_some(number);
+    }
+    let name = spec.to_ascii_uppercase();
+    let name = name.strip_prefix("SIG").unwrap_or(&name);
+    SIGNALS
+        .iter()
+        .find(|&&(known, _)| known == name)
+        .map(|&(_, signal)| signal)
+}
+
+/// Sends a signal, `TERM` unless given as `-KILL`, `-9`, `-s KILL` or
+/// `-n 9`, to processes and jobs. `kill -l` lists the signal names instead.
+#[cfg(unix)]
+fn kill(state: &ShellState, args: impl Iterator<Item = String>) -> i32 {
+    let mut args = args.peekable();
+    let mut signal = libc::SIGTERM;
+    match args.peek().map(String::as_str) {
+        Some("-l" | "-L") => {
+            args.next();
+            return list_signals(args);
+        }
+        Some("-s" | "-n") => {
+            args.next();
+            let Some(spec) = args.next() else {
+                eprintln!("kill: option requires an argument");
+                return 2;
+            };
+            let Some(parsed) = parse_signal(&spec) else {
+                eprintln!("kill: {spec}: invalid signal specification");
+                return 1;
+            };
+            signal = parsed;
+        }
+        Some("--") => {
+            args.next();
+        }
+        Some(arg) if arg.len() > 1 && arg.starts_with('-') => {
+            let Some(parsed) = parse_signal(&arg[1..]) else {
+                eprintln!("kill: {}: invalid signal specification", &arg[1..]);
+                return 1;
+            };
+            signal = parsed;
+            args.next();
         }
-        "pwd" => {
-            let dir = std::env::current_dir()
-                .context("get current dir")?
-                .display()
-                .to_string();
-            if needs_output {
-                output = format!("{}\n", dir);
+        _ => {}
+    }
+    if args.peek().is_none() {
+        eprintln!("kill: usage: kill [-s sigspec | -n signum | -sigspec] pid | jobspec ...");
+        return 2;
+    }
+
+    let mut status = 0;
+    for target in args {
+        // A job is signalled as a whole, which is its process group when
+        // the shell gave it one.
+        let (pid, stopped) = if target.starts_with('%') {
+            let Some(index) = find_job(state, "kill", Some(&target)) else {
+                status = 1;
+                continue;
+            };
+            let job = &state.jobs[index];
+            let pid = if state.job_control {
+                -(job.pgid as libc::pid_t)
             } else {
-                println!("{}", dir);
+                job.pid as libc::pid_t
+            };
+            (pid, job.stopped)
+        } else {
+            match target.parse() {
+                Ok(pid) => (pid, false),
+                Err(_) => {
+                    eprintln!("kill: {target}: arguments must be process or job IDs");
+                    status = 1;
+                    continue;
+                }
             }
+        };
+        // SAFETY: `kill` has no memory safety requirements.
+        if unsafe { libc::kill(pid, signal) } == -1 {
+            let e = std::io::Error::last_os_error();
+            eprintln!("kill: ({target}) - {}", io_error_message(&e));
+            status = 1;
+            continue;
+        }
+        // A stopped job only sees the signal once it runs again.
+        if stopped && signal != libc::SIGKILL && signal != libc::SIGCONT {
+            // SAFETY: as above.
+            unsafe { libc::kill(pid, libc::SIGCONT) };
         }
-        _ => anyhow::bail!("Unknown builtin: {}", com),
     }
+    status
+}
 
-    Ok(PipeOutput::Buffer(output))
+/// Prints the signal names, or with arguments, the name of each signal
+/// number or of the signal that an exit status above 128 stands for.
+#[cfg(unix)]
+fn list_signals(args: impl Iterator<Item = String>) -> i32 {
+    let mut args = args.peekable();
+    if args.peek().is_none() {
+        let names = SIGNALS.iter().map(|&(name, _)| name).collect::<Vec<_>>();
+        println!("{}", names.join(" "));
+        return 0;
+    }
+    let mut status = 0;
+    for arg in args {
+        let name = match arg.parse::<libc::c_int>() {
+            Ok(number) => {
+                let number = if number > 128 { number - 128 } else { number };
+                SIGNALS
+                    .iter()
+                    .find(|&&(_, signal)| signal == number)
+                    .map(|&(name, _)| name.to_string())
+            }
+            Err(_) => parse_signal(&arg).map(|signal| signal.to_string()),
+        };
+        match name {
+            Some(name) => println!("{name}"),
+            None => {
+                eprintln!("kill: {arg}: invalid signal specification");
+                status = 1;
+            }
+        }
+    }
+    status
 }
 
 #[cfg(not(unix))]
-fn run_command(path: &Path, _: &str, mut args: Parser) -> anyhow::Result<()> {
-    let mut settings = std::process::Command::new(path);
-    settings.args(&mut args);
+fn kill(_: &ShellState, _: impl Iterator<Item = String>) -> i32 {
+    eprintln!("kill: signals are not supported on this platform");
+    1
+}
 
-    if let Some(stdout) = args.stdout {
-        settings.stdout(stdout);
+/// Sends `SIGCONT` to a stopped job, or to its whole process group when it
+/// has one of its own.
+#[cfg(unix)]
+fn continue_job(job: &Job, group: bool) -> anyhow::Result<()> {
+    let target = if group {
+        -(job.pgid as libc::pid_t)
+    } else {
+        job.pid as libc::pid_t
+    };
+    // SAFETY: `kill` has no memory safety requirements.
+    if unsafe { libc::kill(target, libc::SIGCONT) } == -1 {
+        return Err(std::io::Error::last_os_error()).context("continue job");
     }
+    Ok(())
+}
 
-    if let Some(stderr) = args.stderr {
-        settings.stderr(stderr);
+/// Without signals, no job is ever stopped.
+#[cfg(not(unix))]
+fn continue_job(_: &Job, _: bool) -> anyhow::Result<()> {
+    Ok(())
+}
+
+/// Waits for a program running in the foreground. One suspended with Ctrl-Z
+/// is kept as a stopped job and the shell takes the terminal back.
+fn wait_foreground(state: &mut ShellState, mut job: Job) -> anyhow::Result<i32> {
+    #[cfg(unix)]
+    let interrupts = IgnoreInterrupts::new();
+    let wait = wait_job(&mut job, true);
+    #[cfg(unix)]
+    drop(interrupts);
+    #[cfg(unix)]
+    if state.job_control {
+        // SAFETY: `getpgrp` has no memory safety requirements.
+        set_foreground(unsafe { libc::getpgrp() });
     }
 
-    let mut child = settings.spawn().context("spawn child process")?;
+    match wait? {
+        Wait::Exited(status) => {
+            // The prompt should not end up after the `^C` the terminal echoed.
+            #[cfg(unix)]
+            if status.signal() == Some(libc::SIGINT) {
+                println!();
+                INTERRUPTED.store(true, Ordering::Relaxed);
+            }
+            Ok(exit_code(status))
+        }
+        Wait::Stopped => Ok(keep_stopped(state, job)),
+        Wait::Running => unreachable!("blocking wait returned early"),
+    }
+}
 
-    child.wait().context("wait for child process")?;
-    Ok(())
+/// Keeps `job`, suspended with Ctrl-Z, as a stopped job, and returns the
+/// status for that.
+fn keep_stopped(state: &mut ShellState, mut job: Job) -> i32 {
+    job.stopped = true;
+    println!();
+    println!("[{}]+  {:<24}{}", job.id, "Stopped", job.command);
+    state.jobs.push(job);
+    STOPPED_STATUS
 }
 
+/// Waits for every program of `job` to exit, or for one of them to stop, or
+/// only checks on them unless `block` is set. A job that has exited has the
+/// status of its last program.
+fn wait_job(job: &mut Job, block: bool) -> anyhow::Result<Wait> {
+    let mut stopped = false;
+    let mut index = 0;
+    while index < job.children.len() {
+        match wait_child(&mut job.children[index], block)? {
+            Wait::Exited(status) => {
+                if index == job.children.len() - 1 {
+                    job.status = Some(status);
+                }
+                // `wait_child` has reaped it already.
+                drop(job.children.remove(index));
+            }
+            Wait::Stopped if block => return Ok(Wait::Stopped),
+            Wait::Stopped => {
+                stopped = true;
+                index += 1;
+            }
+            Wait::Running => index += 1,
+        }
+    }
+    Ok(match job.status {
+        Some(status) if job.children.is_empty() => Wait::Exited(status),
+        _ if stopped => Wait::Stopped,
+        _ => Wait::Running,
+    })
+}
+
+/// The status of a command that was suspended, `128 + SIGTSTP`.
 #[cfg(unix)]
-fn run_command(path: &Path, com: &str, mut args: Parser) -> anyhow::Result<()> {
-    let mut settings = std::process::Command::new(path);
-    settings.arg0(com);
-    settings.args(&mut args);
+const STOPPED_STATUS: i32 = 128 + libc::SIGTSTP;
+#[cfg(not(unix))]
+const STOPPED_STATUS: i32 = 148;
 
-    if let Some(stdout) = args.stdout {
-        settings.stdout(stdout);
+/// What a waited-for child was found doing.
+enum Wait {
+    Exited(ExitStatus),
+    Stopped,
+    Running,
+}
+
+/// Waits for `child` to exit or stop, or only checks on it unless `block` is
+/// set. `Child::wait` cannot be used as it never reports a stop.
+#[cfg(unix)]
+fn wait_child(child: &mut Child, block: bool) -> anyhow::Result<Wait> {
+    let flags = if block {
+        libc::WUNTRACED
+    } else {
+        libc::WUNTRACED | libc::WNOHANG
+    };
+    let mut status = 0;
+    loop {
+        // SAFETY: `status` is a valid pointer for the duration of the call.
+        match unsafe { libc::waitpid(child.id() as libc::pid_t, &mut status, flags) } {
+            -1 => {
+                let e = std::io::Error::last_os_error();
+                if e.kind() != std::io::ErrorKind::Interrupted {
+                    return Err(e).context("wait for child process");
+                }
+            }
+            0 => return Ok(Wait::Running),
+            _ if libc::WIFSTOPPED(status) => return Ok(Wait::Stopped),
+            _ => return Ok(Wait::Exited(ExitStatus::from_raw(status))),
+        }
     }
+}
 
-    if let Some(stderr) = args.stderr {
-        settings.stderr(stderr);
+#[cfg(not(unix))]
+fn wait_child(child: &mut Child, block: bool) -> anyhow::Result<Wait> {
+    if block {
+        let status = child.wait().context("wait for child process")?;
+        return Ok(Wait::Exited(status));
+    }
+    match child.try_wait().context("wait for child process")? {
+        Some(status) => Ok(Wait::Exited(status)),
+        None => Ok(Wait::Running),
+    }
+}
+
+/// Takes over the terminal when the shell is interactive: the shell moves
+/// into its own process group and ignores the signals meant for whatever is
+/// in the foreground. Returns whether job control is available.
+#[cfg(unix)]
+fn init_job_control() -> bool {
+    // SAFETY: these calls have no memory safety requirements, and the only
+    // dispositions installed are `SIG_IGN`.
+    unsafe {
+        if libc::isatty(libc::STDIN_FILENO) == 0 {
+            return false;
+        }
+        for signal in JOB_CONTROL_SIGNALS {
+            libc::signal(signal, libc::SIG_IGN);
+        }
+        let pid = libc::getpid();
+        if libc::getpgrp() != pid && libc::setpgid(0, 0) == -1 {
+            return false;
+        }
+        libc::tcsetpgrp(libc::STDIN_FILENO, pid) == 0
     }
+}
 
-    let mut child = settings.spawn().context("spawn child process")?;
+/// Signals the shell ignores while it has job control, which the programs it
+/// runs get back with their default behavior.
+#[cfg(unix)]
+const JOB_CONTROL_SIGNALS: [libc::c_int; 3] = [libc::SIGTSTP, libc::SIGTTIN, libc::SIGTTOU];
 
-    child.wait().context("wait for child process")?;
-    Ok(())
+/// Signals the terminal sends for Ctrl-C and Ctrl-\.
+#[cfg(unix)]
+const INTERRUPT_SIGNALS: [libc::c_int; 2] = [libc::SIGINT, libc::SIGQUIT];
+
+/// Set when Ctrl-C interrupts the shell or the program it waits on, and
+/// cleared before the next line is run.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// The status of a command that was interrupted, `128 + SIGINT`.
+const INTERRUPTED_STATUS: i32 = 130;
+
+/// Makes Ctrl-C set `INTERRUPTED` instead of killing the shell, so a loop of
+/// builtins can still be stopped.
+#[cfg(unix)]
+fn catch_interrupts() {
+    extern "C" fn on_interrupt(_: libc::c_int) {
+        INTERRUPTED.store(true, Ordering::Relaxed);
+    }
+    // SAFETY: the handler only stores to an atomic, which is
+    // async-signal-safe.
+    unsafe {
+        let handler = on_interrupt as extern "C" fn(libc::c_int);
+        libc::signal(libc::SIGINT, handler as libc::sighandler_t);
+    }
+}
+
+/// Keeps Ctrl-C and Ctrl-\ from killing the shell while it waits on a
+/// program that shares its process group, putting the previous handlers back
+/// once dropped.
+#[cfg(unix)]
+struct IgnoreInterrupts([libc::sighandler_t; 2]);
+
+#[cfg(unix)]
+impl IgnoreInterrupts {
+    fn new() -> Self {
+        // SAFETY: `SIG_IGN` has no memory safety requirements.
+        Self(INTERRUPT_SIGNALS.map(|signal| unsafe { libc::signal(signal, libc::SIG_IGN) }))
+    }
+}
+
+#[cfg(unix)]
+impl Drop for IgnoreInterrupts {
+    fn drop(&mut self) {
+        for (signal, handler) in INTERRUPT_SIGNALS.into_iter().zip(self.0) {
+            // SAFETY: `handler` was the disposition before `new` replaced it.
+            unsafe {
+                libc::signal(signal, handler);
+            }
+        }
+    }
+}
+
+/// Gives the terminal back to the shell once dropped, if it was handed to a
+/// pipeline.
+#[cfg(unix)]
+struct ReclaimTerminal(bool);
+
+#[cfg(unix)]
+impl Drop for ReclaimTerminal {
+    fn drop(&mut self) {
+        if self.0 {
+            // SAFETY: `getpgrp` has no memory safety requirements.
+            set_foreground(unsafe { libc::getpgrp() });
+        }
+    }
+}
+
+/// Hands the terminal to the process group `pgid`. Failing to only costs job
+/// control, so errors are ignored.
+#[cfg(unix)]
+fn set_foreground(pgid: libc::pid_t) {
+    // SAFETY: `tcsetpgrp` has no memory safety requirements.
+    unsafe {
+        libc::tcsetpgrp(libc::STDIN_FILENO, pgid);
+    }
+}
+
+/// Sets up a program to run in `group`, with the signals the shell ignores
+/// back to their defaults.
+#[cfg(unix)]
+fn prepare_child(process: &mut std::process::Command, group: ProcessGroup) {
+    match group {
+        ProcessGroup::Shell => {}
+        ProcessGroup::Join(pgid) => {
+            process.process_group(pgid as i32);
+        }
+        ProcessGroup::Foreground | ProcessGroup::Background => {
+            process.process_group(0);
+        }
+    }
+    let foreground = matches!(group, ProcessGroup::Foreground);
+    // SAFETY: the closure only makes async-signal-safe calls.
+    unsafe {
+        process.pre_exec(move || {
+            // The child takes the terminal itself while `SIGTTOU` is still
+            // ignored, so it never runs before it is in the foreground.
+            if foreground {
+                libc::tcsetpgrp(libc::STDIN_FILENO, libc::getpid());
+            }
+            for signal in JOB_CONTROL_SIGNALS.into_iter().chain(INTERRUPT_SIGNALS) {
+                libc::signal(signal, libc::SIG_DFL);
+            }
+            Ok(())
+        });
+    }
+}
+
+/// Converts a child's exit status into a shell status, reporting death by
+/// signal as `128 + signal` like other shells do.
+fn exit_code(status: ExitStatus) -> i32 {
+    #[cfg(unix)]
+    if let Some(signal) = status.signal() {
+        return 128 + signal;
+    }
+    status.code().unwrap_or(1)
+}
+
+/// The arguments of a command with its redirections separated out and their
+/// files already opened.
+struct Parser {
+    args: Vec<String>,
+    stdin: Option<Redirect>,
+    stdout: Option<Redirect>,
+    stderr: Option<Redirect>,
+}
+
+/// Where a redirection points a stream.
+#[derive(Debug)]
+enum Redirect {
+    File(File),
+    /// `/dev/null`, which needs no file opened for it, on any system.
+    Null,
+}
+
+impl Redirect {
+    fn try_clone(&self) -> std::io::Result<Self> {
+        match self {
+            Redirect::File(file) => file.try_clone().map(Redirect::File),
+            Redirect::Null => Ok(Redirect::Null),
+        }
+    }
+}
+
+impl From<Redirect> for Stdio {
+    fn from(redirect: Redirect) -> Self {
+        match redirect {
+            Redirect::File(file) => file.into(),
+            Redirect::Null => Stdio::null(),
+        }
+    }
+}
+
+impl Read for Redirect {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Redirect::File(file) => file.read(buf),
+            Redirect::Null => Ok(0),
+        }
+    }
+}
+
+impl Write for Redirect {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Redirect::File(file) => file.write(buf),
+            Redirect::Null => Ok(buf.len()),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Redirect::File(file) => file.flush(),
+            Redirect::Null => Ok(()),
+        }
+    }
+}
+
+impl Parser {
+    /// Reads the rest of a command, opening every redirection target up front
+    /// so a bad one fails the command before anything is run. With the
+    /// `noclobber` option, `>` only creates files.
+    fn new(words: impl IntoIterator<Item = Word>, options: &ShellOptions) -> anyhow::Result<Self> {
+        let mut input = words.into_iter();
+        let mut parser = Self {
+            args: Vec::new(),
+            stdin: None,
+            stdout: None,
+            stderr: None,
+        };
+
+        let clobber = if options.noclobber {
+            Opening::CreateNew
+        } else {
+            Opening::Truncate
+        };
+
+        while let Some(next) = input.next() {
+            // Only an operator written without quotes redirects anything.
+            let operator = if next.is_unquoted() {
+                next.text()
+            } else {
+                String::new()
+            };
+            match operator.as_str() {
+                "<" | "0<" => parser.stdin = Some(open_redirect(&mut input, Opening::Read)?),
+                // By now a here-document is the quoted word after its `<<`.
+                "<<" | "<<<" => {
+                    let Some(mut text) = input.next().map(argument) else {
+                        anyhow::bail!("syntax error near unexpected token `newline'");
+                    };
+                    if operator == "<<<" {
+                        text.push('\n');
+                    }
+                    parser.stdin = Some(Redirect::File(here_document(text)?));
+                }
+                ">" | "1>" => parser.stdout = Some(open_redirect(&mut input, clobber)?),
+                "2>" => parser.stderr = Some(open_redirect(&mut input, clobber)?),
+                ">|" | "1>|" => parser.stdout = Some(open_redirect(&mut input, Opening::Truncate)?),
+                "2>|" => parser.stderr = Some(open_redirect(&mut input, Opening::Truncate)?),
+                ">>" | "1>>" => parser.stdout = Some(open_redirect(&mut input, Opening::Append)?),
+                "2>>" => parser.stderr = Some(open_redirect(&mut input, Opening::Append)?),
+                // Duplications are resolved against whatever the other stream
+                // points at right now, so `> out 2>&1` and `2>&1 > out` differ.
+                "2>&1" => {
+                    parser.stderr = Some(match &parser.stdout {
+                        Some(stdout) => stdout.try_clone().context("duplicate stdout")?,
+                        None => Redirect::File(dup_stream(std::io::stdout())?),
+                    })
+                }
+                "1>&2" | ">&2" => {
+                    parser.stdout = Some(match &parser.stderr {
+                        Some(stderr) => stderr.try_clone().context("duplicate stderr")?,
+                        None => Redirect::File(dup_stream(std::io::stderr())?),
+                    })
+                }
+                _ => parser.args.push(argument(next)),
+            }
+        }
+
+        Ok(parser)
+    }
+}
+
+/// How a redirection opens its file.
+#[derive(Clone, Copy)]
+enum Opening {
+    /// `<`.
+    Read,
+    /// `>` and `>|`, which empty the file.
+    Truncate,
+    /// `>` with the `noclobber` option, which only creates files.
+    CreateNew,
+    /// `>>`, with or without `noclobber`, as it never loses what the file
+    /// had.
+    Append,
+}
+
+/// The permissions a redirection creates a file with, before the umask takes
+/// its part off, as for any file a shell creates.
+#[cfg(unix)]
+const REDIRECT_MODE: u32 = 0o666;
+
+impl Opening {
+    fn options(self) -> OpenOptions {
+        let mut options = File::options();
+        match self {
+            Opening::Read => options.read(true),
+            Opening::Truncate => options.write(true).create(true).truncate(true),
+            Opening::CreateNew => options.write(true).create_new(true),
+            Opening::Append => options.append(true).create(true),
+        };
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(REDIRECT_MODE);
+        }
+        options
+    }
+}
+
+/// Opens the file named by the word following a redirection operator, the
+/// way `opening` says. `/dev/null` is not opened, so it discards output even
+/// where there is no such file.
+fn open_redirect(
+    input: &mut impl Iterator<Item = Word>,
+    opening: Opening,
+) -> anyhow::Result<Redirect> {
+    let Some(path) = input.next().map(argument) else {
+        anyhow::bail!("syntax error near unexpected token `newline'");
+    };
+    if path == "/dev/null" {
+        return Ok(Redirect::Null);
+    }
+    let file = match opening.options().open(&path) {
+        // Only `noclobber` refuses existing files, and just regular ones, so
+        // `>` still writes to a terminal or a pipe.
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+            if Path::new(&path).is_file() {
+                anyhow::bail!("{path}: cannot overwrite existing file");
+            }
+            File::options().write(true).open(&path)
+        }
+        file => file,
+    };
+    file.map(Redirect::File)
+        .map_err(|e| anyhow::anyhow!("{path}: {}", io_error_message(&e)))
+}
+
+/// Feeds `text` to a command's stdin through a pipe. Another thread writes
+/// it, so a text bigger than the pipe cannot block the shell before the
+/// command starts reading.
+fn here_document(text: String) -> anyhow::Result<File> {
+    let (reader, mut writer) = std::io::pipe().context("create pipe")?;
+    std::thread::spawn(move || {
+        // The command is free to exit without reading all of it.
+        let _ = writer.write_all(text.as_bytes());
+    });
+    #[cfg(unix)]
+    let file = File::from(std::os::fd::OwnedFd::from(reader));
+    #[cfg(not(unix))]
+    let file = File::from(std::os::windows::io::OwnedHandle::from(reader));
+    Ok(file)
+}
+
+/// Duplicates one of the shell's own standard streams so it can be handed to
+/// a child like any redirection target.
+#[cfg(unix)]
+fn dup_stream(stream: impl std::os::fd::AsFd) -> anyhow::Result<File> {
+    let fd = stream
+        .as_fd()
+        .try_clone_to_owned()
+        .context("duplicate file descriptor")?;
+    Ok(File::from(fd))
+}
+
+#[cfg(not(unix))]
+fn dup_stream(stream: impl std::os::windows::io::AsHandle) -> anyhow::Result<File> {
+    let handle = stream
+        .as_handle()
+        .try_clone_to_owned()
+        .context("duplicate handle")?;
+    Ok(File::from(handle))
+}
+
+/// Formats `e` the way shells report failed system calls, without Rust's
+/// `(os error N)` suffix.
+fn io_error_message(e: &std::io::Error) -> String {
+    let message = e.to_string();
+    match message.find(" (os error") {
+        Some(end) => message[..end].to_string(),
+        None => message,
+    }
+}
+
+/// Removes the `NAME=value` words from the front of a command, returning the
+/// assignments they make.
+fn take_assignments(words: &mut Vec<Word>) -> Vec<(String, String)> {
+    let mut assignments = Vec::new();
+    for word in words.iter() {
+        let Some((name, _)) = word.unquoted_prefix().split_once('=') else {
+            break;
+        };
+        if !is_valid_name(name) {
+            break;
+        }
+        let value = word.text()[name.len() + 1..].to_string();
+        assignments.push((name.to_string(), value));
+    }
+    words.drain(..assignments.len());
+    assignments
+}
+
+/// The text a command receives for `word`, with a tilde at its start expanded
+/// unless any of `~user/` was quoted.
+fn argument(word: Word) -> String {
+    let prefix = word.unquoted_prefix();
+    let tilde_prefix_unquoted = prefix.contains('/') || word.parts.len() == 1;
+    if prefix.starts_with('~') && tilde_prefix_unquoted {
+        expand_tilde(&word.text()).to_string_lossy().into_owned()
+    } else {
+        word.text()
+    }
 }
 
-struct Parser<'de> {
-    stdout: Option<File>,
-    stderr: Option<File>,
-    shlex: Shlex<'de>,
-}
+/// Expands a leading `~` or `~user` in `token` to the matching home
+/// directory. Tokens that don't start with a tilde, or name an unknown user,
+/// are returned unchanged.
+fn expand_tilde(token: &str) -> PathBuf {
+    let Some(rest) = token.strip_prefix('~') else {
+        return PathBuf::from(token);
+    };
+    let (user, path) = match rest.split_once('/') {
+        Some((user, path)) => (user, path),
+        None => (rest, ""),
+    };
 
-impl<'de> Parser<'de> {
-    fn new(input: Shlex<'de>) -> Self {
-        Self {
-            stdout: None,
-            stderr: None,
-            shlex: input,
-        }
+    let home = if user.is_empty() {
+        std::env::home_dir()
+    } else {
+        user_home_dir(user)
+    };
+
+    match home {
+        Some(home) if path.is_empty() => home,
+        Some(home) => home.join(path),
+        None => PathBuf::from(token),
     }
 }
 
-impl Iterator for &mut Parser<'_> {
-    type Item = String;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        let mut next = self.shlex.next()?;
+#[cfg(unix)]
+fn user_home_dir(user: &str) -> Option<PathBuf> {
+    use std::ffi::{CStr, CString, OsStr};
+    use std::os::unix::ffi::OsStrExt;
 
-        // TODO: Handle error
-        if next == ">" || next == "1>" {
-            self.stdout = Some(File::create(self.shlex.next()?).unwrap());
-            next = self.shlex.next()?;
-        } else if next == "2>" {
-            self.stderr = Some(File::create(self.shlex.next()?).unwrap());
-            next = self.shlex.next()?;
-        } else if next == ">>" || next == "1>>" {
-            self.stdout = Some(
-                File::options()
-                    .append(true)
-                    .create(true)
-                    .open(self.shlex.next()?)
-                    .unwrap(),
-            );
-            next = self.shlex.next()?;
-        } else if next == "2>>" {
-            self.stderr = Some(
-                File::options()
-                    .append(true)
-                    .create(true)
-                    .open(self.shlex.next()?)
-                    .unwrap(),
-            );
-            next = self.shlex.next()?;
+    let name = CString::new(user).ok()?;
+    // SAFETY: `name` is a valid C string, and the returned entry is copied out
+    // before any other passwd lookup can overwrite it.
+    unsafe {
+        let passwd = libc::getpwnam(name.as_ptr());
+        if passwd.is_null() || (*passwd).pw_dir.is_null() {
+            return None;
         }
-
-        Some(next)
+        let dir = CStr::from_ptr((*passwd).pw_dir);
+        Some(PathBuf::from(OsStr::from_bytes(dir.to_bytes())))
     }
 }
 
+#[cfg(not(unix))]
+fn user_home_dir(_: &str) -> Option<PathBuf> {
+    None
+}
+
 struct HistoryInfo {
+    clear: bool,
     read: Option<PathBuf>,
     write: Option<PathBuf>,
     append: Option<PathBuf>,
@@ -553,29 +5748,30 @@ struct HistoryInfo {
 }
 
 impl HistoryInfo {
-    fn new(mut shlex: Shlex<'_>) -> anyhow::Result<Self> {
+    fn new(args: impl IntoIterator<Item = String>) -> anyhow::Result<Self> {
+        let mut args = args.into_iter();
+        let mut clear = false;
         let mut read = None;
         let mut write = None;
         let mut append = None;
         let mut num = None;
 
-        while let Some(next) = shlex.next() {
+        while let Some(next) = args.next() {
             match &next[..] {
-                "-r" => read = Some(PathBuf::from(shlex.next().context("Load hitstory file")?)),
-                "-w" => {
-                    write = Some(PathBuf::from(
-                        shlex.next().context("Parsing history file to write")?,
-                    ))
+                "-c" => clear = true,
+                "-r" => read = Some(history_path(&mut args)?),
+                "-w" => write = Some(history_path(&mut args)?),
+                "-a" => append = Some(history_path(&mut args)?),
+                _ => {
+                    num =
+                        Some(next.parse().ok().with_context(|| {
+                            format!("history: {next}: numeric argument required")
+                        })?)
                 }
-                "-a" => {
-                    append = Some(PathBuf::from(
-                        shlex.next().context("Parsing history file to append")?,
-                    ))
-                }
-                _ => num = Some(next.parse().context("parsing arg into number")?),
             }
         }
         Ok(HistoryInfo {
+            clear,
             read,
             write,
             append,
@@ -584,6 +5780,14 @@ impl HistoryInfo {
     }
 }
 
+/// The file operand of a `history` option, defaulting to the history file.
+fn history_path(args: &mut impl Iterator<Item = String>) -> anyhow::Result<PathBuf> {
+    args.next()
+        .map(PathBuf::from)
+        .or_else(history_file)
+        .context("history: HISTFILE not set")
+}
+
 // TODO: this function is not good enough, just to make codecrafter happy.
 fn remove_tag(path: PathBuf) -> anyhow::Result<()> {
     let file = File::open(&path).context("Open history file for reading")?;
@@ -607,12 +5811,1140 @@ fn remove_tag(path: PathBuf) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Held by the tests that change the environment, and by the ones that
+/// depend on it and would see it half changed.
+#[cfg(test)]
+static ENVIRONMENT: Mutex<()> = Mutex::new(());
+
+#[cfg(test)]
+fn lock_environment() -> std::sync::MutexGuard<'static, ()> {
+    ENVIRONMENT.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[test]
+fn test_valid_name() {
+    assert!(is_valid_name("FOO"));
+    assert!(is_valid_name("_foo_1"));
+    assert!(!is_valid_name("1abc"));
+    assert!(!is_valid_name("a-b"));
+    assert!(!is_valid_name(""));
+}
+
+#[test]
+fn test_expand_vars() {
+    let mut state = ShellState::default();
+    state.vars.insert("FOO".into(), "a b".into());
+
+    assert_eq!(expand_vars("echo $FOO", &state).unwrap(), "echo a b");
+    assert_eq!(
+        expand_vars("echo ${FOO}bar", &state).unwrap(),
+        "echo a bbar"
+    );
+    assert_eq!(
+        expand_vars("echo \"$FOO\"", &state).unwrap(),
+        "echo \"a b\""
+    );
+    assert_eq!(expand_vars("echo '$FOO'", &state).unwrap(), "echo '$FOO'");
+    assert_eq!(expand_vars("echo \\$FOO", &state).unwrap(), "echo \\$FOO");
+    assert_eq!(
+        expand_vars("echo $UNSET_VAR_FOR_TEST.", &state).unwrap(),
+        "echo ."
+    );
+    assert_eq!(expand_vars("echo $ ${1x}", &state).unwrap(), "echo $ ${1x}");
+    assert_eq!(
+        expand_vars("echo $(echo $FOO)", &state).unwrap(),
+        "echo a b"
+    );
+    assert_eq!(
+        expand_vars("echo \"`echo '*'`\"", &state).unwrap(),
+        "echo \"*\""
+    );
+}
+
+#[test]
+fn test_expand_positional_parameters() {
+    let state = ShellState {
+        args: ["script", "a b", "c", "d", "e", "f", "g", "h", "i", "j", "k"]
+            .map(String::from)
+            .to_vec(),
+        ..Default::default()
+    };
+
+    assert_eq!(expand_vars("$0 $1 $3", &state).unwrap(), "script a b d");
+    assert_eq!(expand_vars("$10 ${10} ${11}", &state).unwrap(), "a b0 k ");
+    assert_eq!(expand_vars("$#", &state).unwrap(), "10");
+    assert_eq!(expand_vars("$*", &state).unwrap(), "a b c d e f g h i j k");
+    assert_eq!(
+        expand_vars("\"$*\"", &state).unwrap(),
+        "\"a b c d e f g h i j k\""
+    );
+    assert_eq!(
+        expand_vars("\"x$@y\"", &state).unwrap(),
+        "\"xa b\" \"c\" \"d\" \"e\" \"f\" \"g\" \"h\" \"i\" \"j\" \"ky\""
+    );
+}
+
+#[test]
+fn test_expand_defaults() {
+    let mut state = ShellState::default();
+    state.vars.insert("EMPTY".into(), String::new());
+    state.vars.insert("FOO".into(), "a b".into());
+
+    let expand = |input: &str, state: &ShellState| expand_vars(input, state).unwrap();
+    assert_eq!(expand("${UNSET_VAR_FOR_TEST:-x}", &state), "x");
+    assert_eq!(expand("${EMPTY:-x},${EMPTY-x}", &state), "x,");
+    assert_eq!(expand("${FOO:-x}", &state), "a b");
+    assert_eq!(expand("\"${UNSET_VAR_FOR_TEST:-$FOO}\"", &state), "\"a b\"");
+    assert_eq!(expand("${EMPTY:+x},${EMPTY+x},${FOO:+y}", &state), ",x,y");
+    assert_eq!(expand("${UNSET_VAR_FOR_TEST:-'}'}", &state), "\\}");
+    assert!(matches!(
+        expand_vars("${EMPTY:?}", &state),
+        Err(e) if e.to_string() == "EMPTY: parameter null or not set"
+    ));
+    assert!(expand_vars("${EMPTY?}", &state).is_ok());
+
+    // An assignment is seen later in the same line, but only kept by
+    // `expand_vars_mut`.
+    assert_eq!(expand("${NEW_FOR_TEST:=n} $NEW_FOR_TEST", &state), "n n");
+    assert!(!state.vars.contains_key("NEW_FOR_TEST"));
+    assert_eq!(
+        expand_vars_mut("${NEW_FOR_TEST:=n}", &mut state).unwrap(),
+        "n"
+    );
+    assert_eq!(state.vars["NEW_FOR_TEST"], "n");
+}
+
+#[test]
+fn test_expand_parts() {
+    let mut state = ShellState::default();
+    state.vars.insert("P".into(), "/usr/lib/a*b.tar.gz".into());
+
+    let expand = |input: &str| expand_vars(input, &state).unwrap();
+    assert_eq!(expand("${#P} ${#UNSET_VAR_FOR_TEST}"), "19 0");
+    assert_eq!(expand("\"${P##*/}\""), "\"a*b.tar.gz\"");
+    assert_eq!(
+        expand("\"${P%.*}\" \"${P%%.*}\""),
+        "\"/usr/lib/a*b.tar\" \"/usr/lib/a*b\""
+    );
+    // A quoted `*` only matches a star.
+    assert_eq!(expand("\"${P#*'*'}\""), "\"b.tar.gz\"");
+    assert_eq!(expand("\"${P#/usr/lib/a'?'}\""), "\"/usr/lib/a*b.tar.gz\"");
+    assert_eq!(
+        expand("\"${P:9:3}\" \"${P: -2}\" \"${P:99}\""),
+        "\"a*b\" \"gz\" \"\""
+    );
+    assert!(expand_vars("${P:9:-99}", &state).is_err());
+}
+
+#[test]
+fn test_expand_tilde() {
+    let _environment = lock_environment();
+    let home = std::env::home_dir().unwrap();
+    assert_eq!(expand_tilde("~"), home);
+    assert_eq!(expand_tilde("~/notes.txt"), home.join("notes.txt"));
+    assert_eq!(expand_tilde("a~b"), PathBuf::from("a~b"));
+    assert_eq!(
+        expand_tilde("~no_such_user_for_test/x"),
+        PathBuf::from("~no_such_user_for_test/x")
+    );
+    #[cfg(unix)]
+    assert_eq!(expand_tilde("~root"), user_home_dir("root").unwrap());
+}
+
+#[test]
+fn test_split_unquoted() {
+    assert_eq!(
+        split_unquoted("a && b || c", &["&&", "||"]),
+        vec![("a ", Some("&&")), (" b ", Some("||")), (" c", None)]
+    );
+    assert_eq!(
+        split_unquoted("echo '&&' \"||\" \\&&", &["&&", "||"]),
+        vec![("echo '&&' \"||\" \\&&", None)]
+    );
+    assert_eq!(
+        split_unquoted("a | b", &["&&", "||"]),
+        vec![("a | b", None)]
+    );
+}
+
+#[test]
+fn test_pipeline_status() {
+    assert_eq!(pipeline_status(&[1, 0], false), 0);
+    assert_eq!(pipeline_status(&[1, 0], true), 1);
+    assert_eq!(pipeline_status(&[0, 2, 3, 0], true), 3);
+    assert_eq!(pipeline_status(&[0, 1], false), 1);
+    assert_eq!(pipeline_status(&[0, 0], true), 0);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_pipeline_streams_large_output() {
+    let _environment = lock_environment();
+    let state = ShellState::default();
+    // Far more than a pipe holds, so every stage has to run at once.
+    let text = "x".repeat(1 << 20);
+    let echo = format!("echo {text}");
+    let (output, status, ..) =
+        execute_pipeline(None, &state, &[&echo, "cat", "cat"], true).unwrap();
+    assert_eq!(status, 0);
+    assert_eq!(output.len(), text.len() + 1);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_pipeline_stages() {
+    let _environment = lock_environment();
+    let state = ShellState::default();
+    let run = |commands: &[&str]| {
+        let (output, status, ..) = execute_pipeline(None, &state, commands, true).unwrap();
+        (output, status)
+    };
+
+    assert_eq!(run(&["echo x", "grep x", "wc -l"]), ("1\n".into(), 0));
+    assert_eq!(
+        run(&["echo a", "type echo", "cat"]),
+        ("echo is a shell builtin\n".into(), 0)
+    );
+    assert_eq!(
+        run(&["pwd", "cat", "wc -l", "tr -d ' '"]),
+        ("1\n".into(), 0)
+    );
+    assert_eq!(
+        run(&["printf '%s\\n' c a b", "sort", "head -n 2", "tr a-z A-Z"]),
+        ("A\nB\n".into(), 0)
+    );
+    assert_eq!(run(&["echo a", "false", "cat"]), (String::new(), 0));
+    assert_eq!(run(&["cat /dev/null", "true", "false"]).1, 1);
+    assert!(execute_pipeline(None, &state, &["echo", "cd", "cat"], true).is_err());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_pipeline_stops() {
+    let _environment = lock_environment();
+    let state = ShellState::default();
+    let commands = ["true", "sh -c 'kill -STOP $$'", "cat"];
+    let (_, _, pgid, stopped) = execute_pipeline(None, &state, &commands, false).unwrap();
+    // The stage that stopped and the one after it are left to the job.
+    let mut job = state.new_job("stopped", pgid, stopped);
+    for child in &mut job.children {
+        child.kill().unwrap();
+    }
+    assert_eq!(job.children.len(), 2);
+    assert!(matches!(wait_job(&mut job, true), Ok(Wait::Exited(_))));
+    assert!(job.children.is_empty());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_pipeline_stops_after_leader_exits() {
+    let _environment = lock_environment();
+    // As in the shell, so handing the terminal around cannot stop the test
+    // when it runs in one.
+    // SAFETY: `signal` has no memory safety requirements.
+    unsafe { libc::signal(libc::SIGTTOU, libc::SIG_IGN) };
+    let state = ShellState {
+        job_control: true,
+        ..Default::default()
+    };
+    let commands = ["/bin/sh -c exit", "sh -c 'kill -STOP $$'", "cat"];
+    let (_, _, pgid, stopped) = execute_pipeline(None, &state, &commands, false).unwrap();
+    let mut job = state.new_job("stopped", pgid, stopped);
+    // The first program is gone, but its PID still names the group the
+    // others are in.
+    assert_ne!(job.pgid, job.pid);
+    let continued = continue_job(&job, true);
+    for child in &mut job.children {
+        child.kill().unwrap();
+    }
+    assert!(continued.is_ok());
+    assert!(matches!(wait_job(&mut job, true), Ok(Wait::Exited(_))));
+}
+
+#[test]
+fn test_split_pipeline() {
+    assert_eq!(split_pipeline("grep a | wc -l"), ["grep a ", " wc -l"]);
+    assert_eq!(
+        split_pipeline("echo \"x|y\" 'a|b' c\\|d"),
+        ["echo \"x|y\" 'a|b' c\\|d"]
+    );
+    assert_eq!(split_pipeline("a || b|c"), ["a || b", "c"]);
+    assert_eq!(split_pipeline("a >| out | b"), ["a >| out ", " b"]);
+    assert_eq!(
+        split_pipeline("echo $(ls | wc) `a|b` | cat"),
+        ["echo $(ls | wc) `a|b` ", " cat"]
+    );
+}
+
+#[test]
+fn test_search_cdpath() {
+    let base = std::env::temp_dir().join(format!("shell-cdpath-{}", std::process::id()));
+    std::fs::create_dir_all(base.join("b/project")).unwrap();
+    let cdpath = std::env::join_paths([base.join("a"), base.join("b")]).unwrap();
+
+    assert_eq!(
+        search_cdpath(&cdpath, "project"),
+        Some(base.join("b/project"))
+    );
+    assert_eq!(search_cdpath(&cdpath, "missing"), None);
+    assert_eq!(search_cdpath(&cdpath, "./project"), None);
+    assert_eq!(search_cdpath(&cdpath, "../project"), None);
+    assert_eq!(search_cdpath(&cdpath, "/project"), None);
+
+    std::fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn test_normalize_path() {
+    assert_eq!(normalize_path(Path::new("/a/./b/../c")), Path::new("/a/c"));
+    assert_eq!(normalize_path(Path::new("/a/link/..")), Path::new("/a"));
+    assert_eq!(normalize_path(Path::new("/../..")), Path::new("/"));
+}
+
+#[test]
+fn test_expand_history() {
+    let history = ["echo a", "ls -l", "echo b"];
+    let expand = |line| expand_history(line, &history).map(Cow::into_owned);
+    assert_eq!(expand("!!"), Ok("echo b".into()));
+    assert_eq!(expand("sudo !! | wc"), Ok("sudo echo b | wc".into()));
+    assert_eq!(expand("!1; !-2"), Ok("echo a; ls -l".into()));
+    assert_eq!(expand("!ec x"), Ok("echo b x".into()));
+    assert_eq!(expand("\"!l\""), Ok("\"ls -l\"".into()));
+    assert_eq!(
+        expand("echo '!!' \\!! ! x != $! !"),
+        Ok("echo '!!' \\!! ! x != $! !".into())
+    );
+    assert_eq!(expand("!nope"), Err("!nope: event not found".into()));
+    assert_eq!(expand("!9"), Err("!9: event not found".into()));
+}
+
+#[test]
+fn test_expand_prompt() {
+    let _environment = lock_environment();
+    let dir = tilde_abbreviate(&std::env::current_dir().unwrap());
+    assert_eq!(expand_prompt("\\w> ", 0, false), format!("{dir}> "));
+    assert_eq!(expand_prompt("a\\\\b\\q\\", 0, false), "a\\b\\q\\");
+    assert!(matches!(
+        expand_prompt("\\$ ", 0, false).as_str(),
+        "$ " | "# "
+    ));
+    assert!(expand_prompt("\\$", 1, true).starts_with("\x1b[31m"));
+    assert_eq!(tilde_abbreviate(&std::env::home_dir().unwrap()), "~");
+}
+
+#[test]
+fn test_git_branch() {
+    let repo = std::env::temp_dir().join(format!("shell-git-branch-{}", std::process::id()));
+    std::fs::create_dir_all(repo.join(".git")).unwrap();
+    std::fs::create_dir_all(repo.join("src")).unwrap();
+
+    std::fs::write(repo.join(".git/HEAD"), "ref: refs/heads/feature/x\n").unwrap();
+    assert_eq!(git_branch(&repo.join("src")).as_deref(), Some("feature/x"));
+    std::fs::write(repo.join(".git/HEAD"), "0123456789abcdef\n").unwrap();
+    assert_eq!(git_branch(&repo).as_deref(), Some("0123456"));
+
+    std::fs::remove_dir_all(repo).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_program_names_keep_extensions() {
+    let dir = std::env::temp_dir().join(format!("shell-programs-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    for name in ["foo.sh", "bar"] {
+        std::fs::write(dir.join(name), "#!/bin/sh\n").unwrap();
+        std::fs::set_permissions(dir.join(name), std::fs::Permissions::from_mode(0o755)).unwrap();
+    }
+    std::fs::write(dir.join("notes.txt"), "").unwrap();
+
+    let paths = dir.as_os_str();
+    let programs = scan_programs(paths);
+    assert_eq!(programs.into_iter().collect::<Vec<_>>(), ["bar", "foo.sh"]);
+    assert_eq!(find_program(paths, "foo.sh"), Some(dir.join("foo.sh")));
+    assert_eq!(find_program(paths, "foo"), None);
+    assert_eq!(find_program(paths, "bar"), Some(dir.join("bar")));
+    assert_eq!(find_program(paths, "notes.txt"), None);
+    assert_eq!(unrunnable(paths, "notes.txt"), Some("Permission denied"));
+    assert_eq!(unrunnable(paths, "missing"), None);
+    assert_eq!(
+        unrunnable(paths, &dir.to_string_lossy()),
+        Some("Is a directory")
+    );
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_path_changes_rescan() {
+    let _environment = lock_environment();
+    let dir = std::env::temp_dir().join(format!("shell-new-path-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let program = dir.join("new_path_program");
+    std::fs::write(&program, "#!/bin/sh\n").unwrap();
+    std::fs::set_permissions(&program, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let old = std::env::var_os("PATH").unwrap_or_default();
+    let mut paths = std::env::split_paths(&old).collect::<Vec<_>>();
+    paths.push(dir.clone());
+    set_env_var("PATH", std::env::join_paths(paths).unwrap());
+    assert!(programs().contains("new_path_program"));
+    let history = rustyline::history::MemHistory::new();
+    let ctx = rustyline::Context::new(&history);
+    let (_, candidates) = ShellHelper::new(Rc::default())
+        .complete("new_path_pro", 12, &ctx)
+        .unwrap();
+    assert_eq!(candidates[0].display, "new_path_program");
+
+    let mut state = ShellState::default();
+    state.unset_var("PATH");
+    let emptied = programs().is_empty();
+    set_env_var("PATH", &old);
+    assert!(emptied);
+    assert!(!programs().contains("new_path_program"));
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_type_builtin() {
+    let _environment = lock_environment();
+    let mut state = ShellState::default();
+    state.aliases.insert("ll".into(), "ls -la".into());
+    state.aliases.insert("cd".into(), "cd -P".into());
+    let type_builtin = |line: &str| {
+        let args = lexer::split(line).unwrap().into_iter().map(argument);
+        type_builtin(&state, args)
+    };
+    assert_eq!(
+        type_builtin("ll"),
+        ("ll is aliased to `ls -la'\n".into(), 0)
+    );
+    assert_eq!(
+        type_builtin("-a cd"),
+        (
+            "cd is aliased to `cd -P'\ncd is a shell builtin\n".into(),
+            0
+        )
+    );
+    assert_eq!(type_builtin("pwd"), ("pwd is a shell builtin\n".into(), 0));
+    assert_eq!(
+        type_builtin("pwd no-such-command"),
+        (
+            "pwd is a shell builtin\nno-such-command: not found\n".into(),
+            1
+        )
+    );
+    let (output, status) = type_builtin("-a echo");
+    assert_eq!(status, 0);
+    assert!(output.starts_with("echo is a shell builtin\n"));
+
+    for name in BUILTINS {
+        assert!(builtin_type(name).is_some(), "{name}");
+    }
+}
+
+#[test]
+fn test_completion_word() {
+    assert_eq!(completion_word("ec"), (0, true));
+    assert_eq!(completion_word("  ec"), (2, true));
+    assert_eq!(completion_word("echo /usr/lo"), (5, false));
+    assert_eq!(completion_word("echo 'a b"), (5, false));
+    assert_eq!(completion_word("echo a\\ b"), (5, false));
+    assert_eq!(completion_word("ls | gr"), (5, true));
+    assert_eq!(completion_word("ls;gr"), (3, true));
+    assert_eq!(completion_word("ls 2>&1 "), (8, false));
+}
+
+#[test]
+fn test_complete_commands() {
+    let _environment = lock_environment();
+    let history = rustyline::history::MemHistory::new();
+    let ctx = rustyline::Context::new(&history);
+    let (start, candidates) = ShellHelper::new(Rc::default())
+        .complete("  e", 3, &ctx)
+        .unwrap();
+    assert_eq!(start, 2);
+    let names = candidates
+        .iter()
+        .map(|candidate| candidate.display.as_str())
+        .collect::<Vec<_>>();
+    // Builtins first, and `echo` only once even if a program has its name.
+    assert_eq!(names[..4], ["echo", "exec", "exit", "export"]);
+    assert_eq!(names.iter().filter(|name| **name == "echo").count(), 1);
+
+    // Aliases and functions are sorted in with the builtins.
+    let state = Rc::new(RefCell::new(ShellState::default()));
+    let helper = ShellHelper::new(Rc::clone(&state));
+    let mut session = state.borrow_mut();
+    session
+        .aliases
+        .insert(String::from("ex_alias"), String::from("ls"));
+    session
+        .functions
+        .insert(String::from("eb_function"), Rc::new([]));
+    drop(session);
+    let (_, candidates) = helper.complete("e", 1, &ctx).unwrap();
+    let names = candidates
+        .iter()
+        .map(|candidate| candidate.display.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(names[..4], ["eb_function", "echo", "ex_alias", "exec"]);
+    assert_eq!(helper.finish_completion("", "ex_alias"), "ex_alias ");
+}
+
+#[test]
+fn test_symbolic_mask() {
+    assert_eq!(symbolic_mask(0o022), "u=rwx,g=rx,o=rx");
+    assert_eq!(symbolic_mask(0o077), "u=rwx,g=,o=");
+    assert_eq!(symbolic_mask(0o751), "u=,g=w,o=rw");
+}
+
+#[test]
+fn test_highlight_search() {
+    assert_eq!(search_query("(reverse-i-search)`ec': "), Some("ec"));
+    assert_eq!(search_query("(failed reverse-i-search)`x': "), Some("x"));
+    assert_eq!(search_query("$ "), None);
+
+    assert_eq!(
+        highlight_match("echo echo", 5, "ec"),
+        "echo \x1b[1mec\x1b[0mho"
+    );
+    assert_eq!(
+        highlight_match("echo echo", 1, "ec"),
+        "echo \x1b[1mec\x1b[0mho"
+    );
+    assert_eq!(highlight_match("ls", 0, "x"), "ls");
+    assert_eq!(highlight_match("ls", 0, ""), "ls");
+}
+
+#[test]
+fn test_highlight_command() {
+    let mut state = ShellState::default();
+    assert_eq!(
+        highlight_command("echo hi", &state),
+        "\x1b[32mecho\x1b[0m hi"
+    );
+    assert_eq!(
+        highlight_command("  if true", &state),
+        "  \x1b[32mif\x1b[0m true"
+    );
+    assert_eq!(
+        highlight_command("no-such-command-here|cat", &state),
+        "\x1b[31mno-such-command-here\x1b[0m|cat"
+    );
+    assert_eq!(highlight_command("X=1 env", &state), "X=1 env");
+    assert_eq!(highlight_command("'echo' hi", &state), "'echo' hi");
+    assert_eq!(highlight_command("", &state), "");
+
+    state
+        .aliases
+        .insert(String::from("ll"), String::from("ls -l"));
+    assert_eq!(highlight_command("ll", &state), "\x1b[32mll\x1b[0m");
+}
+
+#[test]
+fn test_history_hint() {
+    let mut history = rustyline::history::MemHistory::new();
+    history.add("echo hello").unwrap();
+    history.add("echo world").unwrap();
+    history.add("ls").unwrap();
+    let ctx = rustyline::Context::new(&history);
+    let helper = ShellHelper::new(Rc::default());
+    assert_eq!(helper.hint("echo ", 5, &ctx).as_deref(), Some("world"));
+    assert_eq!(helper.hint("echo h", 6, &ctx).as_deref(), Some("ello"));
+    assert_eq!(helper.hint("echo h", 2, &ctx), None);
+    assert_eq!(helper.hint("ls", 2, &ctx), None);
+    assert_eq!(helper.hint("", 0, &ctx), None);
+}
+
+#[test]
+fn test_completed_path() {
+    let dir = std::env::temp_dir().join(format!("completed-path-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("sub dir")).unwrap();
+    std::fs::write(dir.join("file"), "").unwrap();
+    std::fs::write(dir.join("file.txt"), "").unwrap();
+    std::fs::write(dir.join("only"), "").unwrap();
+    let word = |name: &str| format!("{}/{name}", dir.display());
+
+    assert_eq!(completed_path(&word("sub\\ dir"), None), Some(true));
+    assert_eq!(completed_path(&word("sub dir"), Some('"')), Some(true));
+    assert_eq!(completed_path(&word("only"), None), Some(false));
+    assert_eq!(completed_path(&word("file"), None), None);
+    assert_eq!(completed_path(&word("fil"), None), None);
+    assert_eq!(completed_path(&word("sub dir/"), None), None);
+    assert_eq!(completed_path(&word("missing"), None), None);
+
+    let only = word("only");
+    let helper = ShellHelper::new(Rc::default());
+    assert_eq!(helper.finish_completion("cat ", &only), format!("{only} "));
+    assert_eq!(
+        helper.finish_completion("cat \"", &only),
+        format!("{only}\" ")
+    );
+    assert_eq!(
+        helper.finish_completion("cat '", &only),
+        format!("{only}' ")
+    );
+    assert_eq!(
+        helper.finish_completion("cat \"", &word("sub dir")),
+        word("sub dir/")
+    );
+    assert_eq!(helper.finish_completion("cat ", &word("fil")), word("fil"));
+    assert_eq!(open_quote("echo 'a\\' \"b"), Some('"'));
+    assert_eq!(open_quote("echo \\\" 'a'"), None);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_complete_spec() {
+    let helper = ShellHelper::new(Rc::default());
+    let displays = |line: &str| {
+        let start = line.rfind(' ').unwrap() + 1;
+        helper
+            .complete_spec(line, start, &line[start..])
+            .into_iter()
+            .map(|pair| pair.display)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        displays("git c"),
+        ["checkout", "cherry-pick", "clone", "commit", "config"]
+    );
+    assert_eq!(displays("cargo build --r"), ["--release"]);
+    assert!(displays("git commit ").is_empty());
+    assert!(displays("ls -").is_empty());
+}
+
+#[test]
+fn test_split_background() {
+    assert_eq!(split_background("sleep 5 &"), [("sleep 5 ", true)]);
+    assert_eq!(split_background("sleep 5&  "), [("sleep 5", true)]);
+    assert_eq!(split_background("sleep 5"), [("sleep 5", false)]);
+    assert_eq!(split_background("echo '&'"), [("echo '&'", false)]);
+    assert_eq!(split_background("echo \\&"), [("echo \\&", false)]);
+    assert_eq!(split_background("ls 2>&"), [("ls 2>&", false)]);
+    assert_eq!(split_background("&"), [("", true)]);
+    assert_eq!(
+        split_background("sleep 1 & echo hi"),
+        [("sleep 1 ", true), (" echo hi", false)]
+    );
+    assert_eq!(
+        split_background("a && b 2>&1 & c&d &"),
+        [("a && b 2>&1 ", true), (" c", true), ("d ", true)]
+    );
+    assert_eq!(split_background("& echo"), [("", true), (" echo", false)]);
+}
+
+#[test]
+fn test_glob_match() {
+    let matches = |pattern: &str, text: &str| {
+        let pattern = pattern.chars().map(|c| (c, false)).collect::<Vec<_>>();
+        glob_match(&compile_glob(&pattern), &text.chars().collect::<Vec<_>>())
+    };
+
+    assert!(matches("*.rs", "main.rs"));
+    assert!(!matches("*.rs", "main.rc"));
+    assert!(matches("ma?n.*", "main.rs"));
+    assert!(matches("[a-c]x", "bx"));
+    assert!(!matches("[!a-c]x", "bx"));
+    assert!(matches("[]]", "]"));
+    assert!(matches("[ab", "[ab"));
+}
+
+#[test]
+fn test_expand_globs_respects_quotes() {
+    assert_eq!(expand_globs("echo '*' \"*\" \\*"), "echo '*' \"*\" \\*");
+    assert_eq!(
+        expand_globs("ls /no_such_dir_for_test/*"),
+        "ls /no_such_dir_for_test/*"
+    );
+}
+
+#[test]
+fn test_expand_braces() {
+    assert_eq!(
+        expand_braces("echo file{1,2,3}.txt"),
+        "echo file1.txt file2.txt file3.txt"
+    );
+    assert_eq!(expand_braces("mkdir dir{1..3}"), "mkdir dir1 dir2 dir3");
+    assert_eq!(expand_braces("echo {1..10..4}"), "echo 1 5 9");
+    assert_eq!(expand_braces("echo {3..1} {c..a}"), "echo 3 2 1 c b a");
+    assert_eq!(expand_braces("echo {08..10}"), "echo 08 09 10");
+    assert_eq!(expand_braces("echo a{b,c{d,e}}"), "echo ab acd ace");
+    assert_eq!(expand_braces("echo {a,b}{1,2}"), "echo a1 a2 b1 b2");
+    assert_eq!(
+        expand_braces("echo {a} {} ${HOME} {1..}"),
+        "echo {a} {} ${HOME} {1..}"
+    );
+    // A sequence too long to count is left as it is.
+    assert_eq!(
+        expand_braces("echo {-9223372036854775808..9223372036854775807}"),
+        "echo {-9223372036854775808..9223372036854775807}"
+    );
+    assert_eq!(
+        expand_braces("echo {9223372036854775807..-9223372036854775808..9223372036854775807}"),
+        "echo 9223372036854775807 0 -9223372036854775807"
+    );
+    assert_eq!(
+        expand_braces("echo '{a,b}' \"{a,b}\""),
+        "echo '{a,b}' \"{a,b}\""
+    );
+}
+
+#[test]
+fn test_expand_aliases() {
+    let mut state = ShellState::default();
+    state.aliases.insert("ll".into(), "ls -la".into());
+    state.aliases.insert("ls".into(), "ls -F".into());
+    state.aliases.insert("sudo".into(), "sudo ".into());
+
+    let expand = |line: &str| expand_aliases(&state, line, &mut Vec::new());
+    assert_eq!(expand("ll /tmp"), "ls -F -la /tmp");
+    assert_eq!(expand("echo ll; ll|ll"), "echo ll; ls -F -la|ls -F -la");
+    assert_eq!(expand("'ll' \\ll"), "'ll' \\ll");
+    assert_eq!(expand("sudo ll"), "sudo  ls -F -la");
+    assert_eq!(expand("cmd 2>&1 ll"), "cmd 2>&1 ll");
+}
+
+#[test]
+fn test_redirect_expands_tilde() {
+    let _environment = lock_environment();
+    let home = std::env::temp_dir().join(format!("redirect-home-{}", std::process::id()));
+    std::fs::create_dir_all(&home).unwrap();
+    let old = std::env::var_os("HOME");
+    set_env_var("HOME", &home);
+
+    let parser = Parser::new(
+        lexer::split_command("hi > ~/out").unwrap(),
+        &ShellOptions::default(),
+    );
+    match old {
+        Some(old) => set_env_var("HOME", old),
+        None => remove_env_var("HOME"),
+    }
+    let parser = parser.unwrap();
+    assert_eq!(parser.args, ["hi"]);
+    assert!(parser.stdout.is_some());
+    assert!(home.join("out").exists());
+    std::fs::remove_dir_all(home).unwrap();
+}
+
 #[test]
 fn test_parser() {
-    let mut parser = Shlex::new("arg1 'arg2' arg3 'ar''g''4'");
+    let mut parser = lexer::split("arg1 'arg2' arg3 'ar''g''4'")
+        .unwrap()
+        .into_iter()
+        .map(argument);
     assert_eq!(parser.next().as_deref(), Some("arg1"));
     assert_eq!(parser.next().as_deref(), Some("arg2"));
     assert_eq!(parser.next().as_deref(), Some("arg3"));
     assert_eq!(parser.next().as_deref(), Some("arg4"));
     assert_eq!(parser.next().as_deref(), None);
 }
+
+#[test]
+fn test_redirect_to_dev_null() {
+    let mut parser = Parser::new(
+        lexer::split_command("cat < /dev/null > /dev/null 2>&1").unwrap(),
+        &ShellOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(parser.args, ["cat"]);
+    assert!(matches!(parser.stdin, Some(Redirect::Null)));
+    assert!(matches!(parser.stdout, Some(Redirect::Null)));
+    assert!(matches!(parser.stderr, Some(Redirect::Null)));
+
+    let mut stdin = parser.stdin.take().unwrap();
+    assert_eq!(stdin.read(&mut [0; 8]).unwrap(), 0);
+    assert_eq!(write_output("echo", b"discarded", parser.stdout, None), 0);
+}
+
+#[test]
+fn test_attached_redirections() {
+    let parse = |line: &str| {
+        Parser::new(
+            lexer::split_command(line).unwrap(),
+            &ShellOptions::default(),
+        )
+        .unwrap()
+    };
+    let parser = parse("cat</dev/null 2>/dev/null a>/dev/null");
+    assert_eq!(parser.args, ["cat", "a"]);
+    assert!(matches!(parser.stdin, Some(Redirect::Null)));
+    assert!(matches!(parser.stdout, Some(Redirect::Null)));
+    assert!(matches!(parser.stderr, Some(Redirect::Null)));
+
+    // Written against its neighbors, a duplication still comes in order.
+    let parser = parse("echo a 2>&1 >/dev/null");
+    assert_eq!(parser.args, ["echo", "a"]);
+    assert!(matches!(parser.stdout, Some(Redirect::Null)));
+    assert!(matches!(parser.stderr, Some(Redirect::File(_))));
+    let parser = parse("echo a >/dev/null 2>&1");
+    assert!(matches!(parser.stderr, Some(Redirect::Null)));
+}
+
+#[test]
+fn test_noclobber() {
+    let path = std::env::temp_dir().join(format!("noclobber-{}", std::process::id()));
+    std::fs::write(&path, "kept").unwrap();
+    let options = ShellOptions {
+        noclobber: true,
+        ..Default::default()
+    };
+    let redirect = |operator: &str| {
+        let line = format!("echo {operator} {}", path.display());
+        Parser::new(lexer::split_command(&line).unwrap(), &options).map(|_| ())
+    };
+
+    let error = redirect(">").unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        format!("{}: cannot overwrite existing file", path.display())
+    );
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "kept");
+    assert!(redirect(">>").is_ok());
+    assert!(redirect(">|").is_ok());
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "");
+
+    std::fs::remove_file(&path).unwrap();
+    assert!(redirect(">").is_ok());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_redirect_mode() {
+    let path = std::env::temp_dir().join(format!("redirect-mode-{}", std::process::id()));
+    let mask = file_mask().unwrap();
+    for operator in [">", ">>", "2>"] {
+        let line = format!("echo {operator} {}", path.display());
+        Parser::new(
+            lexer::split_command(&line).unwrap(),
+            &ShellOptions::default(),
+        )
+        .unwrap();
+        let mode = path.metadata().unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, REDIRECT_MODE & !mask);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
+
+#[test]
+fn test_parser_honors_quoting() {
+    let parser = Parser::new(
+        lexer::split_command(r#"'>' out '~' \~ "~"/x"#).unwrap(),
+        &ShellOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(parser.args, [">", "out", "~", "~", "~/x"]);
+    assert!(parser.stdout.is_none());
+}
+
+#[test]
+fn test_set() {
+    let mut state = ShellState {
+        args: vec![String::from("shell")],
+        ..Default::default()
+    };
+    let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+
+    assert_eq!(
+        set(&mut state, args(&["-euC", "-o", "xtrace"]).into_iter()),
+        0
+    );
+    assert!(state.options.errexit && state.options.nounset && state.options.xtrace);
+    assert!(state.options.noclobber);
+    assert_eq!(set(&mut state, args(&["+e", "a", "-b"]).into_iter()), 0);
+    assert!(!state.options.errexit);
+    assert_eq!(state.args, ["shell", "a", "-b"]);
+    assert_eq!(set(&mut state, args(&["--"]).into_iter()), 0);
+    assert_eq!(state.args, ["shell"]);
+    assert_eq!(set(&mut state, args(&["-q"]).into_iter()), 2);
+    assert!(matches!(
+        expand_vars("$UNSET_VAR_FOR_TEST", &state),
+        Err(e) if e.to_string() == "UNSET_VAR_FOR_TEST: unbound variable"
+    ));
+}
+
+#[test]
+fn test_unset() {
+    let _environment = lock_environment();
+    let mut state = ShellState::default();
+    state.set_var("UNSET_SHELL_FOR_TEST", String::from("shell"));
+    set_env_var("UNSET_ENV_FOR_TEST", String::from("exported"));
+    state
+        .functions
+        .insert(String::from("UNSET_SHELL_FOR_TEST"), Default::default());
+    let unset = |state: &mut ShellState, args: &[&str]| {
+        unset(state, args.iter().map(|arg| arg.to_string()))
+    };
+    assert_eq!(
+        unset(&mut state, &["UNSET_SHELL_FOR_TEST", "UNSET_ENV_FOR_TEST"]),
+        0
+    );
+    assert_eq!(state.var("UNSET_SHELL_FOR_TEST"), None);
+    assert_eq!(std::env::var_os("UNSET_ENV_FOR_TEST"), None);
+    assert!(state.functions.contains_key("UNSET_SHELL_FOR_TEST"));
+    assert_eq!(unset(&mut state, &["-f", "UNSET_SHELL_FOR_TEST"]), 0);
+    assert!(state.functions.is_empty());
+    assert_eq!(unset(&mut state, &["1x", "NEVER_SET_FOR_TEST"]), 1);
+
+    state.set_var("UNSET_SHELL_FOR_TEST", String::from("global"));
+    state.scopes.push(HashMap::from([(
+        String::from("UNSET_SHELL_FOR_TEST"),
+        Some(String::from("local")),
+    )]));
+    assert_eq!(unset(&mut state, &["UNSET_SHELL_FOR_TEST"]), 0);
+    assert_eq!(state.var("UNSET_SHELL_FOR_TEST"), None);
+    state.scopes.pop();
+    assert_eq!(state.var("UNSET_SHELL_FOR_TEST").as_deref(), Some("global"));
+}
+
+#[test]
+fn test_local() {
+    let mut state = ShellState::default();
+    state.set_var("LOCAL_FOR_TEST", String::from("global"));
+    assert_eq!(local(&mut state, std::iter::once(String::from("x"))), 1);
+
+    state.scopes.push(HashMap::new());
+    let args = ["LOCAL_FOR_TEST=inner", "DECLARED_FOR_TEST"].map(String::from);
+    assert_eq!(local(&mut state, args.into_iter()), 0);
+    assert_eq!(state.var("LOCAL_FOR_TEST").as_deref(), Some("inner"));
+    assert_eq!(state.var("DECLARED_FOR_TEST"), None);
+    state.set_var("LOCAL_FOR_TEST", String::from("changed"));
+    state.unset_var("DECLARED_FOR_TEST");
+    assert_eq!(state.var("LOCAL_FOR_TEST").as_deref(), Some("changed"));
+
+    state.scopes.pop();
+    assert_eq!(state.var("LOCAL_FOR_TEST").as_deref(), Some("global"));
+}
+
+#[test]
+fn test_shift() {
+    let mut state = ShellState {
+        args: ["script", "a", "b", "c"].map(String::from).to_vec(),
+        ..Default::default()
+    };
+    let shift = |state: &mut ShellState, args: &[&str]| {
+        shift(state, args.iter().map(|arg| arg.to_string()))
+    };
+    assert_eq!(shift(&mut state, &[]), 0);
+    assert_eq!(state.args, ["script", "b", "c"]);
+    assert_eq!(shift(&mut state, &["0"]), 0);
+    assert_eq!(shift(&mut state, &["x"]), 1);
+    assert_eq!(shift(&mut state, &["-1"]), 1);
+    assert_eq!(state.args, ["script", "b", "c"]);
+    assert_eq!(shift(&mut state, &["2"]), 0);
+    assert_eq!(state.args, ["script"]);
+
+    state.args.push(String::from("a"));
+    assert_eq!(shift(&mut state, &["5"]), 1);
+    assert_eq!(state.args, ["script"]);
+}
+
+#[test]
+fn test_return() {
+    let mut rl: ShellEditor = Editor::new().unwrap();
+    let mut state = ShellState {
+        args: vec![String::from("sh")],
+        ..Default::default()
+    };
+    let script = "\
+f() {
+  for i in 1 2 3; do
+    while true; do
+      [ $i = 2 ] && return 7
+      break
+    done
+    REACHED_FOR_TEST=$i
+  done
+  REACHED_FOR_TEST=never
+}
+f
+STATUS_FOR_TEST=$?
+g() { f; AFTER_FOR_TEST=yes; return; }
+g
+";
+    assert!(execute_script(&mut rl, &mut state, script).is_continue());
+    assert_eq!(state.var("REACHED_FOR_TEST").as_deref(), Some("1"));
+    assert_eq!(state.var("STATUS_FOR_TEST").as_deref(), Some("7"));
+    assert_eq!(state.var("AFTER_FOR_TEST").as_deref(), Some("yes"));
+    assert_eq!(state.last_status, 0);
+    assert_eq!((state.return_depth, state.loop_depth), (0, 0));
+
+    assert!(execute_script(&mut rl, &mut state, "h() { return 300; }\nh").is_continue());
+    assert_eq!(state.last_status, 44);
+    assert!(execute_script(&mut rl, &mut state, "h() { return x; }\nh").is_continue());
+    assert_eq!(state.last_status, 2);
+    assert!(execute_script(&mut rl, &mut state, "return 3").is_continue());
+    assert_eq!(state.last_status, 1);
+}
+
+#[test]
+fn test_write_fc_file() {
+    let taken = std::env::temp_dir().join(format!("fc-{}-0.sh", std::process::id()));
+    std::fs::write(&taken, "planted\n").unwrap();
+    let path = write_fc_file("echo a\n").unwrap();
+    assert_ne!(path, taken);
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "echo a\n");
+    assert_eq!(std::fs::read_to_string(&taken).unwrap(), "planted\n");
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+    std::fs::remove_file(path).unwrap();
+    std::fs::remove_file(taken).unwrap();
+}
+
+#[test]
+fn test_history_range() {
+    let entries = ["echo a", "ls", "echo b", "pwd"];
+    assert_eq!(history_range(&entries, None, None, false), Some((3, 3)));
+    assert_eq!(history_range(&entries, None, None, true), Some((0, 3)));
+    assert_eq!(
+        history_range(&entries, Some("2"), None, false),
+        Some((1, 1))
+    );
+    assert_eq!(history_range(&entries, Some("2"), None, true), Some((1, 3)));
+    assert_eq!(
+        history_range(&entries, Some("-2"), Some("-1"), false),
+        Some((2, 3))
+    );
+    assert_eq!(
+        history_range(&entries, Some("echo"), Some("1"), true),
+        Some((2, 0))
+    );
+    // Numbers past either end are clamped, but text has to match.
+    assert_eq!(
+        history_range(&entries, Some("-9"), Some("9"), true),
+        Some((0, 3))
+    );
+    assert_eq!(history_range(&entries, Some("cat"), None, false), None);
+    assert_eq!(history_range(&[], None, None, true), None);
+}
+
+#[test]
+fn test_echo() {
+    let echo = |args: &[&str]| echo(&args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>());
+    assert_eq!(echo(&["a", "b"]), b"a b\n");
+    assert_eq!(echo(&["-n", "a"]), b"a");
+    assert_eq!(echo(&["-ne", "a\\tb"]), b"a\tb");
+    assert_eq!(echo(&["-e", "-E", "a\\tb"]), b"a\\tb\n");
+    assert_eq!(echo(&["a", "-n"]), b"a -n\n");
+    assert_eq!(echo(&["-nx", "-"]), b"-nx -\n");
+    assert_eq!(echo(&["-e", "\\0101\\x42\\q\\"]), b"AB\\q\\\n");
+    assert_eq!(echo(&["-e", "a\\cb", "c"]), b"a");
+    assert_eq!(echo(&["-e", "\\0377\\xff"]), b"\xff\xff\n");
+}
+
+#[cfg(unix)]
+#[test]
+fn test_time_format() {
+    use std::time::Duration;
+
+    assert_eq!(strip_time("time ls | wc"), Some(" ls | wc"));
+    assert_eq!(strip_time("  time"), Some(""));
+    assert_eq!(strip_time("timeout 1 ls"), None);
+
+    let times = TimeReport {
+        real: Duration::from_millis(61_500),
+        user: Duration::from_micros(1_234),
+        sys: Duration::ZERO,
+    };
+    assert_eq!(
+        times.format(DEFAULT_TIMEFORMAT),
+        "\nreal\t1m1.500s\nuser\t0m0.001s\nsys\t0m0.000s"
+    );
+    assert_eq!(
+        times.format(POSIX_TIMEFORMAT),
+        "real 61.50\nuser 0.00\nsys 0.00"
+    );
+    assert_eq!(times.format("%0R%% %6U %x"), "62% 0.001234 %x");
+}
+
+#[test]
+fn test_parse_signal() {
+    assert_eq!(parse_signal("9"), Some(libc::SIGKILL));
+    assert_eq!(parse_signal("TERM"), Some(libc::SIGTERM));
+    assert_eq!(parse_signal("sigint"), Some(libc::SIGINT));
+    assert_eq!(parse_signal("0"), Some(0));
+    assert_eq!(parse_signal("NOPE"), None);
+    assert_eq!(parse_signal("999"), None);
+}
+
+#[test]
+fn test_skip_command_prefix() {
+    let skip = |line: &str| {
+        let mut words = lexer::split(line).unwrap().into_iter().peekable();
+        let com = argument(words.next().unwrap());
+        let com = skip_command_prefix(com, &mut words);
+        (com, words.map(String::from).collect::<Vec<_>>())
+    };
+    assert_eq!(skip("command ls -l"), ("ls".into(), vec!["-l".into()]));
+    assert_eq!(skip("command command cd"), ("cd".into(), vec![]));
+    assert_eq!(
+        skip("command -v ls"),
+        ("command".into(), vec!["-v".into(), "ls".into()])
+    );
+    assert_eq!(
+        skip("echo command"),
+        ("echo".into(), vec!["command".into()])
+    );
+}
+
+#[test]
+fn test_read_fields() {
+    assert_eq!(read_fields("  a  b  c  ", false, 1), ["a  b  c"]);
+    assert_eq!(read_fields("  a  b  c  ", false, 2), ["a", "b  c"]);
+    assert_eq!(read_fields("a b", false, 3), ["a", "b"]);
+    assert_eq!(read_fields(r"a\ b c\\", false, 2), ["a b", "c\\"]);
+    assert_eq!(read_fields(r"a\ b", true, 2), ["a\\", "b"]);
+    assert!(read_fields("   ", false, 1).is_empty());
+}
+
+#[test]
+fn test_take_assignments() {
+    let mut words = lexer::split(r#"A=1 B="x y" C= 'D=2' E=3"#).unwrap();
+    assert_eq!(
+        take_assignments(&mut words),
+        [("A", "1"), ("B", "x y"), ("C", "")].map(|(name, value)| (name.into(), value.into()))
+    );
+    assert_eq!(
+        words.into_iter().map(String::from).collect::<Vec<_>>(),
+        ["D=2", "E=3"]
+    );
+
+    let mut words = lexer::split("1A=2 x").unwrap();
+    assert!(take_assignments(&mut words).is_empty());
+    assert_eq!(words.len(), 2);
+}
+
+#[test]
+fn test_is_incomplete() {
+    for input in [
+        "echo 'a",
+        "echo \"a\nb",
+        "echo a \\",
+        "ls |",
+        "true &&",
+        "false ||  ",
+        "if true; then",
+        "for x in a\ndo",
+    ] {
+        assert!(is_incomplete(input), "{input:?}");
+    }
+    for input in [
+        "echo 'a\nb'",
+        "echo a \\\\",
+        "echo '|'",
+        "sleep 1 &",
+        "echo \\|",
+        "if true; then echo; fi",
+        "",
+    ] {
+        assert!(!is_incomplete(input), "{input:?}");
+    }
+}
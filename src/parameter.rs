@@ -0,0 +1,384 @@
+//! Parses the `${...}` forms of parameter expansion, and takes the parts
+//! of a value that they ask for.
+
+/// A `${...}` expansion: the parameter it names and what is done with its
+/// value.
+#[derive(Debug, PartialEq)]
+pub struct Expansion<'a> {
+    pub name: &'a str,
+    pub operator: Option<Operator<'a>>,
+}
+
+/// What an expansion does with its parameter besides taking its value. With
+/// `colon`, as in `${name:-word}`, a parameter that is set but empty counts
+/// as unset.
+#[derive(Debug, PartialEq)]
+pub enum Operator<'a> {
+    /// `${name-word}`: `word` if the parameter is unset.
+    Default { colon: bool, word: &'a str },
+    /// `${name=word}`: `word` if the parameter is unset, which it is then set
+    /// to.
+    Assign { colon: bool, word: &'a str },
+    /// `${name+word}`: `word` only if the parameter is set.
+    Alternative { colon: bool, word: &'a str },
+    /// `${name?word}`: an error saying `word` if the parameter is unset.
+    Error { colon: bool, word: &'a str },
+    /// `${#name}`: the number of characters in the value.
+    Length,
+    /// `${name:offset:length}`: the characters from `offset` on, counted
+    /// from the end if it is negative, and up to `length` of them, or all
+    /// but that many at the end if it is negative.
+    Substring { offset: i64, length: Option<i64> },
+    /// `${name#pattern}`: the value without the shortest start that matches
+    /// `pattern`, or with `##`, the longest.
+    RemovePrefix { longest: bool, pattern: &'a str },
+    /// `${name%pattern}`: the value without the shortest end that matches
+    /// `pattern`, or with `%%`, the longest.
+    RemoveSuffix { longest: bool, pattern: &'a str },
+}
+
+impl Operator<'_> {
+    /// Whether the operator treats `value` as unset.
+    pub fn unset(&self, value: Option<&str>) -> bool {
+        match self {
+            Operator::Default { colon, .. }
+            | Operator::Assign { colon, .. }
+            | Operator::Alternative { colon, .. }
+            | Operator::Error { colon, .. } => value.is_none_or(|value| *colon && value.is_empty()),
+            _ => value.is_none(),
+        }
+    }
+
+    /// Whether the operator is there to handle an unset parameter, which
+    /// `set -u` then lets through.
+    pub fn allows_unset(&self) -> bool {
+        matches!(
+            self,
+            Operator::Default { .. }
+                | Operator::Assign { .. }
+                | Operator::Alternative { .. }
+                | Operator::Error { .. }
+        )
+    }
+}
+
+/// Parses `body`, what is between the braces of `${...}`. Anything but a
+/// parameter name and a known operator is not an expansion.
+pub fn parse(body: &str) -> Option<Expansion<'_>> {
+    // `${#}` is the number of positional parameters, not a length.
+    if let Some(name) = body.strip_prefix('#')
+        && name_len(name) == Some(name.len())
+    {
+        return Some(Expansion {
+            name,
+            operator: Some(Operator::Length),
+        });
+    }
+
+    let name_len = name_len(body)?;
+    let (name, rest) = body.split_at(name_len);
+    if rest.is_empty() {
+        return Some(Expansion {
+            name,
+            operator: None,
+        });
+    }
+
+    let removal = match rest.chars().next()? {
+        '#' => Some(false),
+        '%' => Some(true),
+        _ => None,
+    };
+    if let Some(suffix) = removal {
+        let longest = rest[1..].starts_with(&rest[..1]);
+        let pattern = &rest[1 + usize::from(longest)..];
+        let operator = if suffix {
+            Operator::RemoveSuffix { longest, pattern }
+        } else {
+            Operator::RemovePrefix { longest, pattern }
+        };
+        return Some(Expansion {
+            name,
+            operator: Some(operator),
+        });
+    }
+
+    let (colon, rest) = match rest.strip_prefix(':') {
+        Some(rest) => (true, rest),
+        None => (false, rest),
+    };
+    // A negative offset needs a space or parentheses to not be `:-`.
+    if colon && !rest.starts_with(['-', '=', '+', '?']) {
+        let (offset, length) = match rest.split_once(':') {
+            Some((offset, length)) => (offset, Some(integer(length)?)),
+            None => (rest, None),
+        };
+        return Some(Expansion {
+            name,
+            operator: Some(Operator::Substring {
+                offset: integer(offset)?,
+                length,
+            }),
+        });
+    }
+
+    // Every operator is a single ASCII character, which the word follows.
+    let word = rest.get(1..).unwrap_or_default();
+    let operator = match rest.chars().next()? {
+        '-' => Operator::Default { colon, word },
+        '=' => Operator::Assign { colon, word },
+        '+' => Operator::Alternative { colon, word },
+        '?' => Operator::Error { colon, word },
+        _ => return None,
+    };
+    Some(Expansion {
+        name,
+        operator: Some(operator),
+    })
+}
+
+/// Reads an offset or length, which may be in parentheses, as in
+/// `${name:(-2)}`.
+fn integer(text: &str) -> Option<i64> {
+    let text = text.trim();
+    let text = text
+        .strip_prefix('(')
+        .and_then(|text| text.strip_suffix(')'))
+        .unwrap_or(text);
+    text.trim().parse().ok()
+}
+
+/// The characters of `value` that `${name:offset:length}` takes, or an
+/// error if a negative `length` ends them before `offset`.
+pub fn substring(value: &str, offset: i64, length: Option<i64>) -> Result<String, String> {
+    let len = value.chars().count() as i64;
+    let start = if offset < 0 {
+        len.saturating_add(offset)
+    } else {
+        offset
+    };
+    if start < 0 || start > len {
+        return Ok(String::new());
+    }
+    let end = match length {
+        None => len,
+        Some(length) if length < 0 => len.saturating_add(length),
+        Some(length) => start.saturating_add(length).min(len),
+    };
+    if let Some(length) = length
+        && end < start
+    {
+        return Err(format!("{length}: substring expression < 0"));
+    }
+    let chars = value.chars().skip(start as usize);
+    Ok(chars.take((end - start) as usize).collect())
+}
+
+/// `value` without the start `matches` accepts, the shortest one or, with
+/// `longest`, the longest.
+pub fn remove_prefix(value: &str, longest: bool, matches: impl Fn(&str) -> bool) -> &str {
+    let mut ends = boundaries(value);
+    if longest {
+        ends.reverse();
+    }
+    match ends.into_iter().find(|&end| matches(&value[..end])) {
+        Some(end) => &value[end..],
+        None => value,
+    }
+}
+
+/// `value` without the end `matches` accepts, the shortest one or, with
+/// `longest`, the longest.
+pub fn remove_suffix(value: &str, longest: bool, matches: impl Fn(&str) -> bool) -> &str {
+    let mut starts = boundaries(value);
+    if !longest {
+        starts.reverse();
+    }
+    match starts.into_iter().find(|&start| matches(&value[start..])) {
+        Some(start) => &value[..start],
+        None => value,
+    }
+}
+
+/// Every byte offset in `text` a character starts at, and its end, in
+/// order.
+fn boundaries(text: &str) -> Vec<usize> {
+    let mut offsets = text.char_indices().map(|(i, _)| i).collect::<Vec<_>>();
+    offsets.push(text.len());
+    offsets
+}
+
+/// The length of the parameter name `body` starts with: a variable name, a
+/// positional parameter or one of `?`, `#`, `@` and `*`.
+fn name_len(body: &str) -> Option<usize> {
+    let first = body.chars().next()?;
+    let len = if first.is_ascii_alphabetic() || first == '_' {
+        body.find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+            .unwrap_or(body.len())
+    } else if first.is_ascii_digit() {
+        body.find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(body.len())
+    } else if matches!(first, '?' | '#' | '@' | '*') {
+        1
+    } else {
+        return None;
+    };
+    Some(len)
+}
+
+#[test]
+fn test_parse() {
+    let expansion = |name, operator| {
+        Some(Expansion {
+            name,
+            operator: Some(operator),
+        })
+    };
+    assert_eq!(
+        parse("HOME"),
+        Some(Expansion {
+            name: "HOME",
+            operator: None
+        })
+    );
+    assert_eq!(parse("10").map(|e| e.name), Some("10"));
+    assert_eq!(parse("#").map(|e| e.name), Some("#"));
+    assert_eq!(
+        parse("X:-a b"),
+        expansion(
+            "X",
+            Operator::Default {
+                colon: true,
+                word: "a b"
+            }
+        )
+    );
+    assert_eq!(
+        parse("X="),
+        expansion(
+            "X",
+            Operator::Assign {
+                colon: false,
+                word: ""
+            }
+        )
+    );
+    assert_eq!(
+        parse("1:+set"),
+        expansion(
+            "1",
+            Operator::Alternative {
+                colon: true,
+                word: "set"
+            }
+        )
+    );
+    assert_eq!(
+        parse("X:?no X"),
+        expansion(
+            "X",
+            Operator::Error {
+                colon: true,
+                word: "no X"
+            }
+        )
+    );
+    assert_eq!(parse("#X"), expansion("X", Operator::Length));
+    assert_eq!(
+        parse("X: -2:1"),
+        expansion(
+            "X",
+            Operator::Substring {
+                offset: -2,
+                length: Some(1)
+            }
+        )
+    );
+    assert_eq!(
+        parse("X:(-2)"),
+        expansion(
+            "X",
+            Operator::Substring {
+                offset: -2,
+                length: None
+            }
+        )
+    );
+    assert_eq!(
+        parse("X##*/"),
+        expansion(
+            "X",
+            Operator::RemovePrefix {
+                longest: true,
+                pattern: "*/"
+            }
+        )
+    );
+    assert_eq!(
+        parse("X%.*"),
+        expansion(
+            "X",
+            Operator::RemoveSuffix {
+                longest: false,
+                pattern: ".*"
+            }
+        )
+    );
+    assert_eq!(parse(""), None);
+    assert_eq!(parse("1x"), None);
+    assert_eq!(parse("X:"), None);
+    assert_eq!(parse("X:a"), None);
+    assert_eq!(parse("X/a/b"), None);
+}
+
+#[test]
+fn test_substring() {
+    assert_eq!(substring("hello", 1, None), Ok(String::from("ello")));
+    assert_eq!(substring("hello", 1, Some(3)), Ok(String::from("ell")));
+    assert_eq!(substring("hello", -3, None), Ok(String::from("llo")));
+    assert_eq!(substring("hello", -3, Some(-1)), Ok(String::from("ll")));
+    assert_eq!(substring("hello", 1, Some(-1)), Ok(String::from("ell")));
+    assert_eq!(substring("hello", 2, Some(10)), Ok(String::from("llo")));
+    assert_eq!(substring("héllo", 1, Some(2)), Ok(String::from("él")));
+    // Past either end there is nothing left.
+    assert_eq!(substring("hello", 5, None), Ok(String::new()));
+    assert_eq!(substring("hello", 9, None), Ok(String::new()));
+    assert_eq!(substring("hello", -9, None), Ok(String::new()));
+    assert_eq!(substring("hello", 2, Some(0)), Ok(String::new()));
+    assert_eq!(substring("", 0, None), Ok(String::new()));
+    assert_eq!(substring("abc", 1, Some(i64::MAX)), Ok(String::from("bc")));
+    assert_eq!(substring("abc", i64::MIN, None), Ok(String::new()));
+    assert_eq!(
+        substring("abc", 0, Some(i64::MIN)),
+        Err(format!("{}: substring expression < 0", i64::MIN))
+    );
+    assert_eq!(
+        substring("hello", 3, Some(-3)),
+        Err(String::from("-3: substring expression < 0"))
+    );
+}
+
+#[test]
+fn test_remove_affixes() {
+    let star_slash = |text: &str| text.ends_with('/');
+    assert_eq!(remove_prefix("/a/b/c", false, star_slash), "a/b/c");
+    assert_eq!(remove_prefix("/a/b/c", true, star_slash), "c");
+    assert_eq!(remove_prefix("abc", false, |text| text == "x"), "abc");
+    // A pattern that matches nothing removes nothing, however long.
+    assert_eq!(remove_prefix("abc", true, str::is_empty), "abc");
+    assert_eq!(remove_prefix("abc", true, |_| true), "");
+
+    let dot_star = |text: &str| text.starts_with('.');
+    assert_eq!(remove_suffix("a.tar.gz", false, dot_star), "a.tar");
+    assert_eq!(remove_suffix("a.tar.gz", true, dot_star), "a");
+    assert_eq!(remove_suffix("héllo", false, |text| text == "llo"), "hé");
+}
+
+#[test]
+fn test_unset() {
+    let default = |colon| Operator::Default { colon, word: "" };
+    assert!(default(false).unset(None));
+    assert!(!default(false).unset(Some("")));
+    assert!(default(true).unset(Some("")));
+    assert!(!default(true).unset(Some("x")));
+}
@@ -0,0 +1,483 @@
+//! Formats the output of `printf`, with the C conversions a script is
+//! likely to use.
+
+use std::iter::Peekable;
+use std::str::Chars;
+
+/// What `printf` prints for `format` and `args`, along with the problems it
+/// ran into on the way. None of them stop the output: a bad number is
+/// printed as 0, like in other shells. It is bytes, as escapes like `\xff`
+/// write ones that are not UTF-8.
+pub fn format(format: &str, args: &[String]) -> (Vec<u8>, Vec<String>) {
+    let mut printer = Printer {
+        args: args.iter(),
+        output: Vec::new(),
+        errors: Vec::new(),
+    };
+    // The format is reused for as long as there are arguments left, so
+    // `printf '%s\n' a b` prints two lines.
+    loop {
+        let remaining = printer.args.len();
+        if !printer.run(format) {
+            break;
+        }
+        if printer.args.len() == 0 || printer.args.len() == remaining {
+            break;
+        }
+    }
+    (printer.output, printer.errors)
+}
+
+struct Printer<'a> {
+    args: std::slice::Iter<'a, String>,
+    output: Vec<u8>,
+    errors: Vec<String>,
+}
+
+/// A conversion like `%-5.2f`, without its `%`.
+#[derive(Default)]
+struct Spec {
+    left: bool,
+    zero: bool,
+    plus: bool,
+    space: bool,
+    alternate: bool,
+    width: usize,
+    precision: Option<usize>,
+}
+
+impl Printer<'_> {
+    /// Prints `format` once, and says whether to go on, which a `\c` in a
+    /// `%b` argument stops.
+    fn run(&mut self, format: &str) -> bool {
+        let mut chars = format.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => escape(&mut chars, false, &mut self.output),
+                '%' if chars.peek() == Some(&'%') => {
+                    chars.next();
+                    self.output.push(b'%');
+                }
+                '%' => {
+                    if !self.convert(&mut chars) {
+                        return false;
+                    }
+                }
+                c => push_char(&mut self.output, c),
+            }
+        }
+        true
+    }
+
+    /// Prints the conversion that `chars` starts at.
+    fn convert(&mut self, chars: &mut Peekable<Chars>) -> bool {
+        let mut spec = Spec::default();
+        while let Some(flag) = chars.next_if(|c| "-0+ #".contains(*c)) {
+            match flag {
+                '-' => spec.left = true,
+                '0' => spec.zero = true,
+                '+' => spec.plus = true,
+                ' ' => spec.space = true,
+                _ => spec.alternate = true,
+            }
+        }
+        if chars.next_if_eq(&'*').is_some() {
+            let width = self.integer();
+            spec.left |= width < 0;
+            spec.width = width.unsigned_abs() as usize;
+        } else {
+            spec.width = take_number(chars);
+        }
+        if chars.next_if_eq(&'.').is_some() {
+            spec.precision = Some(if chars.next_if_eq(&'*').is_some() {
+                usize::try_from(self.integer()).unwrap_or(0)
+            } else {
+                take_number(chars)
+            });
+        }
+
+        let Some(conversion) = chars.next() else {
+            self.errors
+                .push(String::from("%: missing format character"));
+            self.output.push(b'%');
+            return true;
+        };
+        let body = match conversion {
+            's' => {
+                let arg = self.string();
+                match spec.precision {
+                    Some(precision) => arg.chars().take(precision).collect(),
+                    None => arg,
+                }
+            }
+            'b' => {
+                let arg = self.string();
+                let mut chars = arg.chars().peekable();
+                let mut body = Vec::new();
+                let mut go_on = true;
+                while let Some(c) = chars.next() {
+                    if c != '\\' {
+                        push_char(&mut body, c);
+                    } else if chars.next_if_eq(&'c').is_some() {
+                        go_on = false;
+                        break;
+                    } else {
+                        escape(&mut chars, true, &mut body);
+                    }
+                }
+                self.output.extend(pad(&body, &spec));
+                return go_on;
+            }
+            'c' => self.string().chars().take(1).collect(),
+            'd' | 'i' => {
+                let value = self.integer();
+                let digits = value.unsigned_abs().to_string();
+                let sign = if value < 0 {
+                    "-"
+                } else if spec.plus {
+                    "+"
+                } else if spec.space {
+                    " "
+                } else {
+                    ""
+                };
+                self.push_integer(sign, &digits, &spec);
+                return true;
+            }
+            'u' | 'o' | 'x' | 'X' => {
+                // Negative numbers wrap around, as they do in C.
+                let value = self.integer() as u64;
+                let (digits, prefix) = match conversion {
+                    'u' => (value.to_string(), ""),
+                    'o' => (format!("{value:o}"), "0"),
+                    'x' => (format!("{value:x}"), "0x"),
+                    _ => (format!("{value:X}"), "0X"),
+                };
+                let prefix = if spec.alternate && value != 0 {
+                    prefix
+                } else {
+                    ""
+                };
+                self.push_integer(prefix, &digits, &spec);
+                return true;
+            }
+            'f' | 'F' | 'e' | 'E' | 'g' | 'G' => {
+                let value = self.float();
+                let sign = if value.is_sign_negative() {
+                    "-"
+                } else if spec.plus {
+                    "+"
+                } else if spec.space {
+                    " "
+                } else {
+                    ""
+                };
+                let digits = float(value.abs(), conversion, &spec);
+                self.output.extend(pad_number(sign, &digits, &spec));
+                return true;
+            }
+            c => {
+                self.errors.push(format!("%{c}: invalid format character"));
+                return false;
+            }
+        };
+        self.output.extend(pad(body.as_bytes(), &spec));
+        true
+    }
+
+    /// Pads an integer, where a precision is the least number of digits.
+    fn push_integer(&mut self, prefix: &str, digits: &str, spec: &Spec) {
+        let digits = match spec.precision {
+            Some(0) if digits == "0" => String::new(),
+            Some(precision) => format!("{}{digits}", padding(digits, precision, '0')),
+            None => digits.to_string(),
+        };
+        let spec = Spec {
+            zero: spec.zero && spec.precision.is_none(),
+            ..*spec
+        };
+        self.output.extend(pad_number(prefix, &digits, &spec));
+    }
+
+    fn string(&mut self) -> String {
+        self.args.next().cloned().unwrap_or_default()
+    }
+
+    /// The next argument as an integer, which may be written in hex with
+    /// `0x`, in octal with `0`, or as the character after a quote.
+    fn integer(&mut self) -> i64 {
+        let Some(arg) = self.args.next() else {
+            return 0;
+        };
+        if let Some(quoted) = arg.strip_prefix(['\'', '"']) {
+            return quoted.chars().next().map_or(0, |c| i64::from(u32::from(c)));
+        }
+        let trimmed = arg.trim();
+        let (negative, digits) = match trimmed.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, trimmed.strip_prefix('+').unwrap_or(trimmed)),
+        };
+        let parsed = if let Some(hex) = digits
+            .strip_prefix("0x")
+            .or_else(|| digits.strip_prefix("0X"))
+        {
+            i64::from_str_radix(hex, 16)
+        } else if digits.len() > 1
+            && let Some(octal) = digits.strip_prefix('0')
+        {
+            i64::from_str_radix(octal, 8)
+        } else {
+            digits.parse()
+        };
+        match parsed {
+            Ok(value) if negative => -value,
+            Ok(value) => value,
+            Err(_) => {
+                self.errors.push(format!("{arg}: invalid number"));
+                0
+            }
+        }
+    }
+
+    fn float(&mut self) -> f64 {
+        let Some(arg) = self.args.next() else {
+            return 0.0;
+        };
+        if let Some(quoted) = arg.strip_prefix(['\'', '"']) {
+            return quoted
+                .chars()
+                .next()
+                .map_or(0.0, |c| f64::from(u32::from(c)));
+        }
+        arg.trim().parse().unwrap_or_else(|_| {
+            self.errors.push(format!("{arg}: invalid number"));
+            0.0
+        })
+    }
+}
+
+/// Formats a float that is not negative the way `conversion` asks.
+fn float(value: f64, conversion: char, spec: &Spec) -> String {
+    if !value.is_finite() {
+        let text = if value.is_nan() { "nan" } else { "inf" };
+        return if conversion.is_ascii_uppercase() {
+            text.to_uppercase()
+        } else {
+            text.to_string()
+        };
+    }
+    let precision = spec.precision.unwrap_or(6);
+    let text = match conversion.to_ascii_lowercase() {
+        'f' => fixed(value, precision),
+        'e' => exponential(value, precision),
+        _ => {
+            // `%g` picks whichever of `%f` and `%e` is shorter for the
+            // number of significant digits, then drops trailing zeros.
+            let significant = precision.max(1);
+            let rounded = exponential(value, significant - 1);
+            let exponent = rounded
+                .rsplit_once('e')
+                .and_then(|(_, exponent)| exponent.parse::<i64>().ok())
+                .unwrap_or(0);
+            let text = if -4 <= exponent && exponent < significant as i64 {
+                let decimals = (significant as i64 - 1 - exponent) as usize;
+                fixed(value, decimals)
+            } else {
+                rounded
+            };
+            if spec.alternate {
+                text
+            } else {
+                trim_zeros(&text)
+            }
+        }
+    };
+    if conversion.is_ascii_uppercase() {
+        text.to_uppercase()
+    } else {
+        text
+    }
+}
+
+/// The most digits an `f64` has after the point, past which they are all
+/// zeros. `format!` cannot be asked for more than `u16::MAX` of them.
+const MAX_DIGITS: usize = 1074;
+
+/// Formats `value` with `precision` digits after the point.
+fn fixed(value: f64, precision: usize) -> String {
+    let digits = precision.min(MAX_DIGITS);
+    let mut text = format!("{value:.digits$}");
+    text.extend(std::iter::repeat_n('0', precision - digits));
+    text
+}
+
+/// Formats `value` like C's `%e`, with at least two exponent digits.
+fn exponential(value: f64, precision: usize) -> String {
+    let digits = precision.min(MAX_DIGITS);
+    let text = format!("{value:.digits$e}");
+    let (mantissa, exponent) = text.split_once('e').unwrap_or((&text, "0"));
+    let (sign, digits) = match exponent.strip_prefix('-') {
+        Some(digits) => ('-', digits),
+        None => ('+', exponent),
+    };
+    let zeros = "0".repeat(precision - precision.min(MAX_DIGITS));
+    format!("{mantissa}{zeros}e{sign}{digits:0>2}")
+}
+
+/// Drops the zeros at the end of the fraction of a `%g` number, and the dot
+/// if nothing is left after it.
+fn trim_zeros(text: &str) -> String {
+    let (number, exponent) = match text.find('e') {
+        Some(index) => text.split_at(index),
+        None => (text, ""),
+    };
+    let number = if number.contains('.') {
+        number.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        number
+    };
+    format!("{number}{exponent}")
+}
+
+/// Pads a number to its width, putting any zeros after the sign or prefix.
+fn pad_number(prefix: &str, digits: &str, spec: &Spec) -> Vec<u8> {
+    if spec.zero && !spec.left {
+        let width = spec.width.saturating_sub(prefix.len());
+        format!("{prefix}{}{digits}", padding(digits, width, '0')).into_bytes()
+    } else {
+        pad(format!("{prefix}{digits}").as_bytes(), spec)
+    }
+}
+
+/// Pads `body` to its width, which counts bytes that are not UTF-8 as one
+/// character each.
+fn pad(body: &[u8], spec: &Spec) -> Vec<u8> {
+    let spaces = padding(&String::from_utf8_lossy(body), spec.width, ' ');
+    if spec.left {
+        [body, spaces.as_bytes()].concat()
+    } else {
+        [spaces.as_bytes(), body].concat()
+    }
+}
+
+/// The `fill` that makes `text` `width` characters long. It is built by hand
+/// as `format!` cannot pad to more than `u16::MAX`.
+fn padding(text: &str, width: usize, fill: char) -> String {
+    std::iter::repeat_n(fill, width.saturating_sub(text.chars().count())).collect()
+}
+
+fn take_number(chars: &mut Peekable<Chars>) -> usize {
+    let mut number = 0usize;
+    while let Some(digit) = chars.next_if(char::is_ascii_digit) {
+        number = number
+            .saturating_mul(10)
+            .saturating_add(digit as usize - '0' as usize);
+    }
+    number
+}
+
+fn push_char(output: &mut Vec<u8>, c: char) {
+    output.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+}
+
+/// Decodes the escape after a backslash into `output`. In a `%b` argument
+/// octal escapes are written `\0nnn`, as for `echo -e`, and in the format
+/// `\nnn`. Either way they, and `\xHH`, stand for a single byte.
+fn escape(chars: &mut Peekable<Chars>, argument: bool, output: &mut Vec<u8>) {
+    let c = match chars.next() {
+        Some('a') => '\x07',
+        Some('b') => '\x08',
+        Some('e' | 'E') => '\x1b',
+        Some('f') => '\x0c',
+        Some('n') => '\n',
+        Some('r') => '\r',
+        Some('t') => '\t',
+        Some('v') => '\x0b',
+        Some(c @ ('\\' | '"' | '\'')) => c,
+        Some(c @ '0'..='7') => {
+            let (mut value, max) = match (argument, c) {
+                (true, '0') => (0, 3),
+                _ => (c.to_digit(8).unwrap_or(0), 2),
+            };
+            for _ in 0..max {
+                match chars.next_if(|c| c.is_digit(8)) {
+                    Some(digit) => value = value * 8 + digit.to_digit(8).unwrap_or(0),
+                    None => break,
+                }
+            }
+            output.push(value as u8);
+            return;
+        }
+        Some('x') if chars.peek().is_some_and(char::is_ascii_hexdigit) => {
+            let mut value = 0;
+            for _ in 0..2 {
+                match chars.next_if(char::is_ascii_hexdigit) {
+                    Some(digit) => value = value * 16 + digit.to_digit(16).unwrap_or(0),
+                    None => break,
+                }
+            }
+            output.push(value as u8);
+            return;
+        }
+        // Anything else is printed as written, backslash and all.
+        Some(c) => {
+            output.push(b'\\');
+            c
+        }
+        None => '\\',
+    };
+    push_char(output, c);
+}
+
+#[test]
+fn test_format() {
+    let printf = |format: &str, args: &[&str]| {
+        let args = args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        self::format(format, &args)
+    };
+    let output = |format: &str, args: &[&str]| String::from_utf8(printf(format, args).0).unwrap();
+    assert_eq!(output("%s-%s\\n", &["a", "b", "c"]), "a-b\nc-\n");
+    assert_eq!(output("100%% %c%c", &["xy", "z"]), "100% xz");
+    assert_eq!(
+        output("[%5s|%-5s|%.2s]", &["ab", "cd", "efg"]),
+        "[   ab|cd   |ef]"
+    );
+    assert_eq!(
+        output("%d %i %05d %+d %.3d", &["42", "-7", "-42", "3", "5"]),
+        "42 -7 -0042 +3 005"
+    );
+    assert_eq!(
+        output("%x %X %#x %o %u", &["255", "0xff", "255", "8", "010"]),
+        "ff FF 0xff 10 8"
+    );
+    assert_eq!(output("%d", &["'A"]), "65");
+    assert_eq!(
+        output("%.2f %8.3f %-6.1f|", &["3.14159", "-2.5", "1"]),
+        "3.14   -2.500 1.0   |"
+    );
+    assert_eq!(
+        output("%e %g %g %g", &["1234.5", "0.0001", "1e10", "100"]),
+        "1.234500e+03 0.0001 1e+10 100"
+    );
+    assert_eq!(output("%*d|%-*d|", &["4", "1", "3", "2"]), "   1|2  |");
+    assert_eq!(output("a\\tb\\101\\x42\\q", &[]), "a\tbAB\\q");
+    assert_eq!(output("%b|%s", &["a\\tb\\0101", "a\\tb"]), "a\tbA|a\\tb");
+    assert_eq!(output("%b%s\\n", &["x\\cy", "z"]), "x");
+    assert_eq!(output("%s %d\\n", &[]), " 0\n");
+    // Escapes above 0x7f are single bytes, not the UTF-8 of that code point.
+    assert_eq!(printf("\\xff\\377%b", &["\\0200"]).0, b"\xff\xff\x80");
+    assert_eq!(printf("[%3b]", &["\\xff"]).0, b"[  \xff]");
+    // Widths and precisions are not limited to what `format!` can pad.
+    assert_eq!(output("%70000d", &["1"]).len(), 70000);
+    assert_eq!(output("%-*s|", &["70000", "a"]).len(), 70001);
+    assert_eq!(output("%.70000d", &["1"]).len(), 70000);
+    let fixed = output("%.70000f", &["1.5"]);
+    assert_eq!((fixed.len(), &fixed[..4]), (70002, "1.50"));
+    assert!(output("%.70000e", &["1"]).ends_with("0e+00"));
+
+    assert_eq!(
+        printf("%d", &["4x"]),
+        ("0".into(), vec!["4x: invalid number".into()])
+    );
+    assert_eq!(printf("%y", &["a"]).1, ["%y: invalid format character"]);
+}
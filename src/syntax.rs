@@ -0,0 +1,456 @@
+//! Groups the lines of a script into the compound commands, like `if`, that
+//! the shell runs as a whole. The simple commands in between are left as
+//! written for the expansion passes to take apart.
+
+use std::iter::Peekable;
+use std::vec::IntoIter;
+
+/// A piece of a script, run as a unit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Statement {
+    /// Commands joined by `&&` and `||`, as they were written.
+    List(String),
+    /// `if ...; then ...; elif ...; then ...; else ...; fi`.
+    If {
+        branches: Vec<Branch>,
+        otherwise: Vec<Statement>,
+    },
+    /// `for name in words; do ...; done`, where the words are expanded when
+    /// the loop starts. Without `in`, the loop goes over `"$@"`.
+    For {
+        name: String,
+        words: Option<String>,
+        body: Vec<Statement>,
+    },
+    /// `while ...; do ...; done`, or with `until`, which loops while the
+    /// condition fails instead.
+    While {
+        until: bool,
+        condition: Vec<Statement>,
+        body: Vec<Statement>,
+    },
+    /// `{ ...; }`, which runs its statements in the current shell.
+    Group(Vec<Statement>),
+    /// `name() { ...; }`, which defines a function running the body.
+    Function { name: String, body: Vec<Statement> },
+}
+
+/// A condition and the statements run when it succeeds.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Branch {
+    pub condition: Vec<Statement>,
+    pub body: Vec<Statement>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SyntaxError {
+    /// The input stops inside a block, so more of it has to be read.
+    #[error("syntax error: unexpected end of file")]
+    Incomplete,
+    #[error("syntax error near unexpected token `{0}'")]
+    Unexpected(String),
+}
+
+/// The words that are reserved in command position.
+pub const KEYWORDS: &[&str] = &[
+    "if", "then", "elif", "else", "fi", "for", "while", "until", "do", "done", "{", "}",
+];
+
+/// A reserved word in command position, the `name()` that starts a function
+/// definition, or the rest of a command.
+#[derive(Debug)]
+enum Token<'a> {
+    Keyword(&'a str),
+    Function(&'a str),
+    Command(&'a str),
+}
+
+/// Parses `source` into statements, which fails with
+/// [`SyntaxError::Incomplete`] if a block is still open at the end.
+pub fn parse(source: &str) -> Result<Vec<Statement>, SyntaxError> {
+    let mut tokens = segments(source)
+        .into_iter()
+        .flat_map(tokens)
+        .collect::<Vec<_>>()
+        .into_iter()
+        .peekable();
+    parse_statements(&mut tokens, &[])
+}
+
+/// Splits `source` on the `;` and newlines that end a command, skipping any
+/// inside quotes or `$(...)` and the newlines after a `|`, `&&` or `||`,
+/// where the command goes on.
+fn segments(source: &str) -> Vec<&str> {
+    let mut segments = Vec::new();
+    let mut quote = None;
+    let mut escaped = false;
+    let mut depth = 0usize;
+    let mut start = 0;
+    // The last character that was not whitespace, if it was unquoted.
+    let mut last = None;
+
+    for (i, c) in source.char_indices() {
+        if escaped {
+            escaped = false;
+            last = None;
+            continue;
+        }
+        let and = last == Some('&') && source[..i].trim_end().ends_with("&&");
+        if c == '\n' && (last == Some('|') || and) {
+            continue;
+        }
+        if !c.is_whitespace() {
+            last = quote.is_none().then_some(c);
+        }
+        match (c, quote) {
+            ('\\', Some('\'')) => {}
+            ('\\', _) => escaped = true,
+            ('\'' | '"' | '`', None) => quote = Some(c),
+            (c, Some(open)) if c == open => quote = None,
+            ('(', None) => depth += 1,
+            (')', None) => depth = depth.saturating_sub(1),
+            (';' | '\n', None) if depth == 0 => {
+                segments.push(&source[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    segments.push(&source[start..]);
+
+    segments
+}
+
+/// Splits the reserved words off the front of a segment. A word is only
+/// reserved where a command could start, so `echo if` is a plain command.
+fn tokens(segment: &str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut rest = segment.trim();
+    while !rest.is_empty() {
+        if let Some((name, body)) = function_header(rest) {
+            tokens.push(Token::Function(name));
+            rest = body.trim_start();
+            continue;
+        }
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        let word = &rest[..end];
+        if !KEYWORDS.contains(&word) {
+            tokens.push(Token::Command(rest));
+            break;
+        }
+        tokens.push(Token::Keyword(word));
+        rest = rest[end..].trim_start();
+    }
+    tokens
+}
+
+/// Splits `name()` or `name ()` off the front of `segment`, returning the
+/// name and what follows.
+fn function_header(segment: &str) -> Option<(&str, &str)> {
+    let end = segment
+        .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '-'))
+        .unwrap_or(segment.len());
+    let name = &segment[..end];
+    if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+    let rest = segment[end..].trim_start().strip_prefix("()")?;
+    Some((name, rest))
+}
+
+type Tokens<'a> = Peekable<IntoIter<Token<'a>>>;
+
+/// Parses statements up to, but not including, one of the `terminators`.
+/// Running out of tokens is only fine at the top level, where there are
+/// none.
+fn parse_statements(
+    tokens: &mut Tokens,
+    terminators: &[&str],
+) -> Result<Vec<Statement>, SyntaxError> {
+    let mut statements = Vec::new();
+    loop {
+        match tokens.peek() {
+            None if terminators.is_empty() => return Ok(statements),
+            None => return Err(SyntaxError::Incomplete),
+            Some(Token::Keyword(keyword)) if terminators.contains(keyword) => {
+                return Ok(statements);
+            }
+            Some(Token::Keyword("if")) => statements.push(parse_if(tokens)?),
+            Some(Token::Keyword("for")) => statements.push(parse_for(tokens)?),
+            Some(Token::Keyword("while" | "until")) => statements.push(parse_while(tokens)?),
+            Some(Token::Keyword("{")) => statements.push(Statement::Group(parse_group(tokens)?)),
+            Some(Token::Function(_)) => statements.push(parse_function(tokens)?),
+            Some(Token::Keyword(keyword)) => {
+                return Err(SyntaxError::Unexpected(keyword.to_string()));
+            }
+            Some(Token::Command(command)) => {
+                statements.push(Statement::List(command.to_string()));
+                tokens.next();
+            }
+        }
+    }
+}
+
+/// Parses the statements of a block part that ends at one of `terminators`,
+/// which must not be empty.
+fn parse_block(tokens: &mut Tokens, terminators: &[&str]) -> Result<Vec<Statement>, SyntaxError> {
+    let statements = parse_statements(tokens, terminators)?;
+    if statements.is_empty() {
+        return match tokens.peek() {
+            Some(Token::Keyword(keyword)) => Err(SyntaxError::Unexpected(keyword.to_string())),
+            _ => Err(SyntaxError::Incomplete),
+        };
+    }
+    Ok(statements)
+}
+
+fn parse_if(tokens: &mut Tokens) -> Result<Statement, SyntaxError> {
+    let mut branches = Vec::new();
+    let mut otherwise = Vec::new();
+    // Consumes the `if`, and then each `elif`.
+    while let Some(Token::Keyword("if" | "elif")) = tokens.next() {
+        let condition = parse_block(tokens, &["then"])?;
+        tokens.next();
+        let body = parse_block(tokens, &["elif", "else", "fi"])?;
+        branches.push(Branch { condition, body });
+
+        match tokens.peek() {
+            Some(Token::Keyword("elif")) => {}
+            Some(Token::Keyword("else")) => {
+                tokens.next();
+                otherwise = parse_block(tokens, &["fi"])?;
+                break;
+            }
+            _ => break,
+        }
+    }
+    // Only `fi` can be left, as every part ends at one.
+    tokens.next();
+
+    Ok(Statement::If {
+        branches,
+        otherwise,
+    })
+}
+
+fn parse_for(tokens: &mut Tokens) -> Result<Statement, SyntaxError> {
+    tokens.next();
+    let header = match tokens.next() {
+        Some(Token::Command(header)) => header,
+        Some(Token::Function(name)) => return Err(SyntaxError::Unexpected(name.to_string())),
+        Some(Token::Keyword(keyword)) => return Err(SyntaxError::Unexpected(keyword.to_string())),
+        None => return Err(SyntaxError::Incomplete),
+    };
+    let (name, rest) = header
+        .split_once(char::is_whitespace)
+        .unwrap_or((header, ""));
+    let rest = rest.trim_start();
+    let words = match rest.split_once(char::is_whitespace).unwrap_or((rest, "")) {
+        ("", _) => None,
+        ("in", words) => Some(words.trim().to_string()),
+        (word, _) => return Err(SyntaxError::Unexpected(word.to_string())),
+    };
+
+    let body = parse_do(tokens)?;
+    Ok(Statement::For {
+        name: name.to_string(),
+        words,
+        body,
+    })
+}
+
+fn parse_while(tokens: &mut Tokens) -> Result<Statement, SyntaxError> {
+    let until = matches!(tokens.next(), Some(Token::Keyword("until")));
+    let condition = parse_block(tokens, &["do"])?;
+    let body = parse_do(tokens)?;
+    Ok(Statement::While {
+        until,
+        condition,
+        body,
+    })
+}
+
+/// Parses a `{ ...; }` group, returning the statements in it.
+fn parse_group(tokens: &mut Tokens) -> Result<Vec<Statement>, SyntaxError> {
+    tokens.next();
+    let body = parse_block(tokens, &["}"])?;
+    tokens.next();
+    Ok(body)
+}
+
+fn parse_function(tokens: &mut Tokens) -> Result<Statement, SyntaxError> {
+    let Some(Token::Function(name)) = tokens.next() else {
+        unreachable!("not at a function definition");
+    };
+    let body = match tokens.peek() {
+        Some(Token::Keyword("{")) => parse_group(tokens)?,
+        Some(Token::Keyword(keyword)) => return Err(SyntaxError::Unexpected(keyword.to_string())),
+        Some(Token::Function(name)) => return Err(SyntaxError::Unexpected(name.to_string())),
+        Some(Token::Command(command)) => return Err(SyntaxError::Unexpected(command.to_string())),
+        None => return Err(SyntaxError::Incomplete),
+    };
+    Ok(Statement::Function {
+        name: name.to_string(),
+        body,
+    })
+}
+
+/// Parses the `do ...; done` body of a loop.
+fn parse_do(tokens: &mut Tokens) -> Result<Vec<Statement>, SyntaxError> {
+    match tokens.next() {
+        Some(Token::Keyword("do")) => {}
+        Some(Token::Keyword(keyword)) => return Err(SyntaxError::Unexpected(keyword.to_string())),
+        Some(Token::Function(name)) => return Err(SyntaxError::Unexpected(name.to_string())),
+        Some(Token::Command(command)) => return Err(SyntaxError::Unexpected(command.to_string())),
+        None => return Err(SyntaxError::Incomplete),
+    }
+    let body = parse_block(tokens, &["done"])?;
+    tokens.next();
+    Ok(body)
+}
+
+#[test]
+fn test_parse_if() {
+    let list = |command: &str| Statement::List(command.to_string());
+    assert_eq!(
+        parse("echo a; if true; then echo b; elif x\nthen y; else z; fi\necho c").unwrap(),
+        [
+            list("echo a"),
+            Statement::If {
+                branches: vec![
+                    Branch {
+                        condition: vec![list("true")],
+                        body: vec![list("echo b")],
+                    },
+                    Branch {
+                        condition: vec![list("x")],
+                        body: vec![list("y")],
+                    },
+                ],
+                otherwise: vec![list("z")],
+            },
+            list("echo c"),
+        ]
+    );
+
+    let nested = parse("if a; then if b; then c; fi; fi").unwrap();
+    assert!(matches!(&nested[..], [Statement::If { branches, .. }]
+        if matches!(&branches[0].body[..], [Statement::If { .. }])));
+
+    // Quoted or not in command position, the words are not reserved.
+    assert_eq!(
+        parse("echo if 'fi'; 'if' x").unwrap(),
+        [list("echo if 'fi'"), list("'if' x")]
+    );
+    assert_eq!(parse("echo \"a; b\" $(c; d)").unwrap().len(), 1);
+    assert_eq!(
+        parse("a |\n b &&\n c\n d &\n e \\|\n f").unwrap(),
+        [
+            list("a |\n b &&\n c"),
+            list("d &"),
+            list("e \\|"),
+            list("f")
+        ]
+    );
+}
+
+#[test]
+fn test_parse_for() {
+    let list = |command: &str| Statement::List(command.to_string());
+    assert_eq!(
+        parse("for x in a  *.rs; do echo $x; done").unwrap(),
+        [Statement::For {
+            name: "x".into(),
+            words: Some("a  *.rs".into()),
+            body: vec![list("echo $x")],
+        }]
+    );
+    assert_eq!(
+        parse("for arg\ndo\n  for y in; do :; done\ndone").unwrap(),
+        [Statement::For {
+            name: "arg".into(),
+            words: None,
+            body: vec![Statement::For {
+                name: "y".into(),
+                words: Some(String::new()),
+                body: vec![list(":")],
+            }],
+        }]
+    );
+}
+
+#[test]
+fn test_parse_while() {
+    let list = |command: &str| Statement::List(command.to_string());
+    assert_eq!(
+        parse("while a; b\ndo c; done; until d; do e; done").unwrap(),
+        [
+            Statement::While {
+                until: false,
+                condition: vec![list("a"), list("b")],
+                body: vec![list("c")],
+            },
+            Statement::While {
+                until: true,
+                condition: vec![list("d")],
+                body: vec![list("e")],
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_parse_function() {
+    let list = |command: &str| Statement::List(command.to_string());
+    assert_eq!(
+        parse("greet() { echo hello $1; }\ngreet world").unwrap(),
+        [
+            Statement::Function {
+                name: "greet".into(),
+                body: vec![list("echo hello $1")],
+            },
+            list("greet world"),
+        ]
+    );
+    assert_eq!(
+        parse("f ()\n{\n  { a; b; }\n}").unwrap(),
+        [Statement::Function {
+            name: "f".into(),
+            body: vec![Statement::Group(vec![list("a"), list("b")])],
+        }]
+    );
+    assert_eq!(parse("echo f() x").unwrap(), [list("echo f() x")]);
+    assert!(matches!(parse("f() {"), Err(SyntaxError::Incomplete)));
+    assert!(matches!(parse("f() { a"), Err(SyntaxError::Incomplete)));
+    assert!(matches!(parse("f() echo"), Err(SyntaxError::Unexpected(t)) if t == "echo"));
+    assert!(matches!(parse("}"), Err(SyntaxError::Unexpected(t)) if t == "}"));
+}
+
+#[test]
+fn test_parse_errors() {
+    for incomplete in [
+        "if true",
+        "if true; then",
+        "if a; then b; else",
+        "if a; then b",
+        "for x in a",
+        "for x in a; do",
+        "for x; do b",
+        "while a; do b",
+        "until a",
+    ] {
+        assert!(matches!(parse(incomplete), Err(SyntaxError::Incomplete)));
+    }
+    for (source, token) in [
+        ("fi", "fi"),
+        ("if; then a; fi", "then"),
+        ("if a; then fi", "fi"),
+        ("if a; then b; fi; then", "then"),
+        ("for x on a; do b; done", "on"),
+        ("for x in a; echo; done", "echo"),
+        ("done", "done"),
+        ("while do b; done", "do"),
+    ] {
+        assert!(matches!(parse(source), Err(SyntaxError::Unexpected(t)) if t == token));
+    }
+}